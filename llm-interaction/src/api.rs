@@ -1,184 +1,358 @@
+use crate::model_registry::LlmModel;
 use crate::modules::{
-    parse_sse_line, ClaudeMessage, ClaudeStreamApiRequest, ClaudeUsage, StreamEvent,
+    parse_sse_line, BatchRequest, ClaudeApiError, ClaudeMessage, ClaudeStreamApiRequest,
+    ClaudeUsage, CreateBatchRequest, MessageBatch, ModelInfo, ModelListResponse,
 };
-use crate::tui::TerminalUi;
-use crossterm::Result as TermResult;
-use std::io::{self, Write};
+use crate::output::{OutputEvent, StreamNormalizer, ToolCall};
+use crate::sampling::SamplingParams;
+use crate::tui::{MessageStream, TerminalUi};
+use std::io;
+use std::time::{Duration, Instant};
+
+// Everything we keep from a completed streaming request
+pub struct MessageResult {
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+    pub usage: Option<ClaudeUsage>,
+    pub stop_reason: Option<String>,
+    pub latency: Duration,
+}
+
+impl MessageResult {
+    pub fn hit_max_tokens(&self) -> bool {
+        self.stop_reason.as_deref() == Some("max_tokens")
+    }
+}
 
 pub struct ApiClient {
     api_key: String,
 }
 
+// Turn a non-2xx response into an error carrying the API's own message
+async fn error_for_status(
+    response: reqwest::Response,
+) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    match serde_json::from_str::<ClaudeApiError>(&body) {
+        Ok(err) => Err(format!(
+            "{} ({}): {}",
+            status, err.error.error_type, err.error.message
+        )
+        .into()),
+        Err(_) => Err(format!("{}: {}", status, body).into()),
+    }
+}
+
+// The API rejects a final assistant turn that ends in whitespace, so a
+// prefill is trimmed; the continuation supplies the whitespace itself.
+pub fn trim_prefill(prefill: &str) -> &str {
+    prefill.trim_end()
+}
+
+// `history` holds the earlier turns of the conversation
+pub fn build_request(
+    history: &[ClaudeMessage],
+    content: &str,
+    prefill: Option<&str>,
+    model: &LlmModel,
+    params: &SamplingParams,
+) -> ClaudeStreamApiRequest {
+    let mut messages = history.to_vec();
+    messages.push(ClaudeMessage::text("user", content));
+    if let Some(prefill) = prefill {
+        messages.push(ClaudeMessage::text("assistant", trim_prefill(prefill)));
+    }
+    build_conversation_request(messages, model, params)
+}
+
+pub fn build_conversation_request(
+    messages: Vec<ClaudeMessage>,
+    model: &LlmModel,
+    params: &SamplingParams,
+) -> ClaudeStreamApiRequest {
+    ClaudeStreamApiRequest {
+        model: model.id.clone(),
+        max_tokens: params.max_tokens_for(model),
+        messages,
+        stream: true,
+        temperature: params.temperature,
+        top_p: params.top_p,
+        top_k: params.top_k,
+        stop_sequences: params.stop_sequences.clone(),
+        system: None,
+        tools: Vec::new(),
+        tool_choice: None,
+    }
+}
+
 impl ApiClient {
     pub fn new(api_key: String) -> Self {
         ApiClient { api_key }
     }
 
-    pub async fn send_message(
-        &self,
-        content: &str,
-        model: &str,
-    ) -> Result<(String, Option<(u32, u32)>), Box<dyn std::error::Error>> {
+    // Every model available to this API key, following pagination
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, Box<dyn std::error::Error>> {
         let client = reqwest::Client::new();
+        let mut models = Vec::new();
+        let mut after_id: Option<String> = None;
 
-        let request = ClaudeStreamApiRequest {
-            model: model.to_string(),
-            max_tokens: 1024,
-            messages: vec![ClaudeMessage {
-                role: "user".to_string(),
-                content: content.to_string(),
-            }],
-            stream: true,
-        };
+        loop {
+            let mut query = vec![("limit", "1000".to_string())];
+            if let Some(after) = &after_id {
+                query.push(("after_id", after.clone()));
+            }
 
-        let mut response = client
-            .post("https://api.anthropic.com/v1/messages")
+            let response = client
+                .get("https://api.anthropic.com/v1/models")
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", "2023-06-01")
+                .query(&query)
+                .send()
+                .await?;
+            let page: ModelListResponse = error_for_status(response).await?.json().await?;
+
+            models.extend(page.data);
+            if !page.has_more || page.last_id.is_none() {
+                break;
+            }
+            after_id = page.last_id;
+        }
+
+        Ok(models)
+    }
+
+    pub async fn create_batch(
+        &self,
+        requests: Vec<BatchRequest>,
+    ) -> Result<MessageBatch, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post("https://api.anthropic.com/v1/messages/batches")
             .header("x-api-key", &self.api_key)
             .header("anthropic-version", "2023-06-01")
             .header("content-type", "application/json")
-            .json(&request)
+            .json(&CreateBatchRequest { requests })
             .send()
             .await?;
 
-        let mut full_response = String::new();
-        let mut input_tokens = None;
-        let mut output_tokens = None;
+        Ok(error_for_status(response).await?.json().await?)
+    }
 
-        print!("🤖 "); // Claude emoji prompt
-        io::stdout().flush()?;
+    pub async fn get_batch(
+        &self,
+        batch_id: &str,
+    ) -> Result<MessageBatch, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
 
-        let mut stdout = io::stdout();
+        let response = client
+            .get(format!(
+                "https://api.anthropic.com/v1/messages/batches/{}",
+                batch_id
+            ))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .send()
+            .await?;
 
-        while let Some(chunk) = response.chunk().await? {
-            let chunk_str = String::from_utf8_lossy(&chunk);
-            for line in chunk_str.lines() {
-                if let Some(event) = parse_sse_line(line) {
-                    match event {
-                        StreamEvent::ContentBlockDelta { delta, .. } => {
-                            stdout.write_all(delta.text.as_bytes())?;
-                            stdout.flush()?;
-                            full_response.push_str(&delta.text);
-                        }
-                        StreamEvent::MessageStop => {
-                            println!(); // New line after message is complete
-                        }
-                        StreamEvent::MessageDelta { usage, .. } => {
-                            input_tokens = usage.input_tokens;
-                            output_tokens = usage.output_tokens;
-                        }
-                        _ => {} // Ignore other events
-                    }
-                }
-            }
-        }
+        Ok(error_for_status(response).await?.json().await?)
+    }
 
-        let token_info = if let (Some(input), Some(output)) = (input_tokens, output_tokens) {
-            Some((input, output))
-        } else {
-            None
-        };
+    pub async fn cancel_batch(
+        &self,
+        batch_id: &str,
+    ) -> Result<MessageBatch, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
 
-        Ok((full_response, token_info))
+        let response = client
+            .post(format!(
+                "https://api.anthropic.com/v1/messages/batches/{}/cancel",
+                batch_id
+            ))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .send()
+            .await?;
+
+        Ok(error_for_status(response).await?.json().await?)
     }
 
-    pub async fn send_message_tui(
+    // Raw JSONL results of an ended batch
+    pub async fn batch_results(
         &self,
-        content: &str,
-        model: &str,
-        ui: &TerminalUi,
-    ) -> Result<(String, Option<(u32, u32)>), Box<dyn std::error::Error>> {
+        results_url: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
         let client = reqwest::Client::new();
 
-        let request = ClaudeStreamApiRequest {
-            model: model.to_string(),
-            max_tokens: 1024,
-            messages: vec![ClaudeMessage {
-                role: "user".to_string(),
-                content: content.to_string(),
-            }],
-            stream: true,
-        };
+        let response = client
+            .get(results_url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .send()
+            .await?;
 
-        // Show thinking indicator
-        ui.draw_thinking_spinner()?;
+        Ok(error_for_status(response).await?.text().await?)
+    }
 
-        let mut response = client
-            .post("https://api.anthropic.com/v1/messages")
+    // Look up a single model; the API resolves aliases such as `-latest`.
+    // Returns None when the model does not exist for this key.
+    pub async fn get_model(
+        &self,
+        model_id: &str,
+    ) -> Result<Option<ModelInfo>, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("https://api.anthropic.com/v1/models/{}", model_id))
             .header("x-api-key", &self.api_key)
             .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&request)
             .send()
             .await?;
 
-        // Clear thinking indicator
-        ui.clear_thinking_spinner()?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(error_for_status(response).await?.json().await?))
+    }
 
-        let mut full_response = String::new();
-        let mut input_tokens = None;
-        let mut output_tokens = None;
+    async fn post_message(
+        &self,
+        request: &ClaudeStreamApiRequest,
+    ) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::new();
+        let response = client
+            .post("https://api.anthropic.com/v1/messages")
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .header("content-type", "application/json")
+            .json(request)
+            .send()
+            .await?;
+        error_for_status(response).await
+    }
 
-        let model_display = match model {
-            "claude-3-5-haiku-latest" => "Claude 3.5 Haiku",
-            "claude-3-7-sonnet-latest" => "Claude 3.7 Sonnet",
-            other => other,
+    // Read a streaming response to the end, passing each normalized event to
+    // `on_event`. A continuation streams onto the text it was prefilled with.
+    async fn read_stream(
+        mut response: reqwest::Response,
+        prefill: Option<&str>,
+        started: Instant,
+        on_event: &mut dyn FnMut(&OutputEvent) -> io::Result<()>,
+    ) -> Result<MessageResult, Box<dyn std::error::Error>> {
+        let mut result = MessageResult {
+            text: prefill.map(trim_prefill).unwrap_or_default().to_string(),
+            tool_calls: Vec::new(),
+            usage: None,
+            stop_reason: None,
+            latency: Duration::ZERO,
         };
+        let mut normalizer = StreamNormalizer::new();
 
-        // Start response
-        let mut stdout = io::stdout();
-        crossterm::queue!(
-            stdout,
-            crossterm::style::SetForegroundColor(crossterm::style::Color::Cyan),
-            crossterm::style::Print(format!("🤖 {}:\n", model_display)),
-            crossterm::style::ResetColor
-        )?;
-        stdout.flush()?;
-
-        // Draw initial message box
-        ui.draw_model_message("", None, None)?;
-
+        // Chunks don't line up with SSE lines (or UTF-8 characters), so only
+        // complete lines are parsed
+        let mut pending: Vec<u8> = Vec::new();
         while let Some(chunk) = response.chunk().await? {
-            let chunk_str = String::from_utf8_lossy(&chunk);
-            for line in chunk_str.lines() {
-                if let Some(event) = parse_sse_line(line) {
-                    match event {
-                        StreamEvent::ContentBlockDelta { delta, .. } => {
-                            full_response.push_str(&delta.text);
-                            // Redraw the message box with updated content
-                            ui.draw_model_message(&full_response, None, None)?;
-                        }
-                        StreamEvent::MessageStop => {
-                            // Final update
-                        }
-                        StreamEvent::MessageDelta { usage, .. } => {
-                            input_tokens = usage.input_tokens;
-                            output_tokens = usage.output_tokens;
-                        }
-                        _ => {} // Ignore other events
+            pending.extend_from_slice(&chunk);
+            while let Some(newline) = pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=newline).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(event) = parse_sse_line(line.trim_end()) else {
+                    continue;
+                };
+
+                for event in normalizer.normalize(event) {
+                    match &event {
+                        OutputEvent::TextDelta { text, .. } => result.text.push_str(text),
+                        OutputEvent::ToolUse { call, .. } => result.tool_calls.push(call.clone()),
+                        OutputEvent::Usage(usage) => result.usage = Some(usage.clone()),
+                        OutputEvent::Stop {
+                            stop_reason: Some(stop_reason),
+                            ..
+                        } => result.stop_reason = Some(stop_reason.clone()),
+                        OutputEvent::Error { message } => return Err(message.clone().into()),
+                        _ => {}
                     }
+                    on_event(&event)?;
                 }
             }
         }
 
-        let token_info = if let (Some(input), Some(output)) = (input_tokens, output_tokens) {
-            Some((input, output))
-        } else {
-            None
-        };
+        result.latency = started.elapsed();
+        Ok(result)
+    }
 
-        // Redraw one final time with token stats if available
-        if let Some((input, output)) = token_info {
-            let cost = match model {
-                "claude-3-5-haiku-latest" => (input as f64 * 0.000001) + (output as f64 * 0.000005),
-                "claude-3-7-sonnet-latest" => {
-                    (input as f64 * 0.000005) + (output as f64 * 0.000020)
-                }
-                _ => 0.0,
-            };
+    // Stream a reply, handing each event to `on_event`; nothing is printed here
+    pub async fn send_message(
+        &self,
+        history: &[ClaudeMessage],
+        content: &str,
+        prefill: Option<&str>,
+        model: &LlmModel,
+        params: &SamplingParams,
+        on_event: &mut dyn FnMut(&OutputEvent) -> io::Result<()>,
+    ) -> Result<MessageResult, Box<dyn std::error::Error>> {
+        let request = build_request(history, content, prefill, model, params);
+        self.send_request(&request, prefill, on_event).await
+    }
 
-            ui.draw_model_message(&full_response, Some((input, output)), Some(cost))?;
-        }
+    // Stream a request built by the caller, e.g. one with tools or several turns
+    pub async fn send_request(
+        &self,
+        request: &ClaudeStreamApiRequest,
+        prefill: Option<&str>,
+        on_event: &mut dyn FnMut(&OutputEvent) -> io::Result<()>,
+    ) -> Result<MessageResult, Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        let response = self.post_message(request).await?;
+        Self::read_stream(response, prefill, started, on_event).await
+    }
+
+    // Stream a request built with `build_request`, drawing the reply into
+    // `stream` as it comes in
+    pub async fn send_message_tui(
+        &self,
+        request: &ClaudeStreamApiRequest,
+        prefill: Option<&str>,
+        model: &LlmModel,
+        ui: &mut TerminalUi,
+        stream: &mut MessageStream,
+    ) -> Result<MessageResult, Box<dyn std::error::Error>> {
+        // Show thinking indicator
+        ui.draw_thinking_spinner()?;
+
+        let started = Instant::now();
+        let response = self.post_message(request).await;
+
+        // Clear thinking indicator
+        ui.clear_thinking_spinner()?;
+        let response = response?;
+
+        // Draw the message in place as it streams in. A continuation goes
+        // on from the text it was prefilled with, in the same stream.
+        let mut shown = prefill.map(trim_prefill).unwrap_or_default().to_string();
+        ui.stream_model_message(stream, &shown, None)?;
+
+        let result = Self::read_stream(response, prefill, started, &mut |event| {
+            if let OutputEvent::TextDelta { text, .. } = event {
+                shown.push_str(text);
+                ui.stream_model_message(stream, &shown, None)?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        // Redraw one final time with token stats if available
+        let stats = result.usage.as_ref().and_then(|usage| {
+            let tokens = usage.input_tokens.zip(usage.output_tokens)?;
+            Some((tokens, model.calculate_cost(usage)))
+        });
+        ui.end_model_message(stream, &result.text, stats)?;
 
-        Ok((full_response, token_info))
+        Ok(result)
     }
 }
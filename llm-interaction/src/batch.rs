@@ -0,0 +1,854 @@
+use crate::api::ApiClient;
+use crate::budget::{self, BudgetTracker};
+use crate::config::Config;
+use crate::model_registry::ModelRegistry;
+use crate::modules::{
+    BatchRequest, BatchRequestCounts, BatchResult, BatchResultLine, ClaudeUsage, MessageBatch,
+};
+use crate::output::OutputFormat;
+use crate::sampling::SamplingParams;
+use crate::usage::UsageLedger;
+use chrono::{DateTime, Utc};
+use crossterm::{execute, terminal};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Message Batches API limit on requests per batch
+pub const MAX_BATCH_REQUESTS: usize = 100_000;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// A batch submitted from this machine, kept in batches.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackedBatch {
+    pub id: String,
+    pub source: String,
+    pub submitted_at: DateTime<Utc>,
+    pub profile: String,
+    // Model used for lines that did not name one
+    pub model: String,
+    // Registry ids of the models the other lines named, by custom_id. Results
+    // carry the dated id the API resolved a model to, which the registry
+    // may not know.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub models: HashMap<String, String>,
+    pub request_count: usize,
+    pub processing_status: String,
+    #[serde(default)]
+    pub request_counts: BatchRequestCounts,
+    #[serde(default)]
+    pub results_path: Option<String>,
+    // Set once the batch's usage has been written to the usage ledger
+    #[serde(default)]
+    pub usage_recorded: bool,
+}
+
+impl TrackedBatch {
+    pub fn update(&mut self, batch: &MessageBatch) {
+        self.processing_status = batch.processing_status.clone();
+        self.request_counts = batch.request_counts.clone();
+    }
+}
+
+pub struct BatchStore {
+    path: PathBuf,
+    pub batches: Vec<TrackedBatch>,
+}
+
+impl BatchStore {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let batches = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid batch list {}: {}", path.display(), e),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(BatchStore { path, batches })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&self.batches)?)
+    }
+
+    // The named batch, or the most recently submitted one
+    pub fn find(&mut self, id: Option<&str>) -> Option<&mut TrackedBatch> {
+        match id {
+            Some(id) => self.batches.iter_mut().find(|b| b.id == id),
+            None => self.batches.iter_mut().max_by_key(|b| b.submitted_at),
+        }
+    }
+}
+
+fn valid_custom_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Accepts either the API's own `{"custom_id", "params"}` lines or a shorthand
+// with a prompt field (`prompt`, `content` or `body`, optionally with a `title`)
+// and an id field (`custom_id`, `id` or `request_id`). Shorthand lines get the
+// current model and sampling parameters unless they set `model` / `max_tokens`;
+// `model` may be an alias.
+fn parse_line(
+    value: Value,
+    line_no: usize,
+    registry: &ModelRegistry,
+    params: &SamplingParams,
+) -> Result<BatchRequest, String> {
+    let custom_id = ["custom_id", "id", "request_id"]
+        .iter()
+        .find_map(|key| value.get(key).and_then(|v| v.as_str()))
+        .map(String::from)
+        .unwrap_or_else(|| format!("line-{}", line_no));
+    if !valid_custom_id(&custom_id) {
+        return Err(format!(
+            "line {}: custom_id '{}' must be 1-64 letters, digits, '-' or '_'",
+            line_no, custom_id
+        ));
+    }
+
+    if let Some(params) = value.get("params") {
+        return Ok(BatchRequest {
+            custom_id,
+            params: params.clone(),
+        });
+    }
+
+    let prompt = ["prompt", "content", "body"]
+        .iter()
+        .find_map(|key| value.get(key).and_then(|v| v.as_str()))
+        .ok_or_else(|| {
+            format!(
+                "line {}: expected `params` or a `prompt`, `content` or `body` field",
+                line_no
+            )
+        })?;
+    let prompt = match value.get("title").and_then(|v| v.as_str()) {
+        Some(title) => format!("{}\n\n{}", title, prompt),
+        None => prompt.to_string(),
+    };
+
+    let (model_id, model) = match value.get("model").and_then(|v| v.as_str()) {
+        Some(name) => match registry.get(name) {
+            Some(model) => (model.id.as_str(), model),
+            None => (name, registry.get_current_model()),
+        },
+        None => {
+            let model = registry.get_current_model();
+            (model.id.as_str(), model)
+        }
+    };
+    let mut request = json!({
+        "model": model_id,
+        "max_tokens": value
+            .get("max_tokens")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(params.max_tokens_for(model) as u64),
+        "messages": [{ "role": "user", "content": prompt }],
+    });
+    if let Some(system) = value.get("system") {
+        request["system"] = system.clone();
+    }
+    if let Some(temperature) = params.temperature {
+        request["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = params.top_p {
+        request["top_p"] = json!(top_p);
+    }
+    if let Some(top_k) = params.top_k {
+        request["top_k"] = json!(top_k);
+    }
+    if !params.stop_sequences.is_empty() {
+        request["stop_sequences"] = json!(params.stop_sequences);
+    }
+
+    Ok(BatchRequest {
+        custom_id,
+        params: request,
+    })
+}
+
+pub fn parse_batch_file(
+    path: &Path,
+    registry: &ModelRegistry,
+    params: &SamplingParams,
+) -> Result<Vec<BatchRequest>, String> {
+    fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| parse_batch(&contents, registry, params))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn parse_batch(
+    contents: &str,
+    registry: &ModelRegistry,
+    params: &SamplingParams,
+) -> Result<Vec<BatchRequest>, String> {
+    let mut requests: Vec<BatchRequest> = Vec::new();
+    let mut ids: HashSet<String> = HashSet::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_no = index + 1;
+        let value: Value = serde_json::from_str(line)
+            .map_err(|e| format!("line {}: invalid JSON: {}", line_no, e))?;
+        let request = parse_line(value, line_no, registry, params)?;
+        if !ids.insert(request.custom_id.clone()) {
+            return Err(format!(
+                "line {}: duplicate custom_id '{}'",
+                line_no, request.custom_id
+            ));
+        }
+        requests.push(request);
+    }
+
+    if requests.is_empty() {
+        return Err("no requests".to_string());
+    }
+    if requests.len() > MAX_BATCH_REQUESTS {
+        return Err(format!(
+            "{} requests; a batch holds at most {}",
+            requests.len(),
+            MAX_BATCH_REQUESTS
+        ));
+    }
+
+    Ok(requests)
+}
+
+// Model and output budget of a request, for cost projections
+pub fn request_model_and_max_tokens(request: &BatchRequest) -> (Option<&str>, u32) {
+    (
+        request.params.get("model").and_then(|v| v.as_str()),
+        request
+            .params
+            .get("max_tokens")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as u32,
+    )
+}
+
+pub fn render_progress(batch: &MessageBatch) -> String {
+    const WIDTH: usize = 30;
+
+    let counts = &batch.request_counts;
+    let total = counts.total() as usize;
+    let done = total - counts.processing as usize;
+    let filled = (done * WIDTH).checked_div(total).unwrap_or(0);
+
+    format!(
+        "[{}{}] {}/{} done  ✓ {} succeeded  ✗ {} errored  {} canceled  {} expired  ({})",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        done,
+        total,
+        counts.succeeded,
+        counts.errored,
+        counts.canceled,
+        counts.expired,
+        batch.processing_status
+    )
+}
+
+// One line of the results file we write, keyed by custom_id
+#[derive(Debug, Serialize)]
+pub struct BatchOutputLine {
+    pub custom_id: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ClaudeUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Usage from a succeeded request with the model it was billed against and
+// its cost at batch pricing, 0 for models the registry has no prices for
+pub struct BatchUsage {
+    pub model: String,
+    pub usage: ClaudeUsage,
+    pub cost: f64,
+}
+
+// Convert raw API results into our output lines, pricing each succeeded
+// request at the batch discount against the model it was submitted with
+pub fn process_results(
+    raw: &str,
+    registry: &ModelRegistry,
+    tracked: &TrackedBatch,
+) -> (Vec<BatchOutputLine>, Vec<BatchUsage>) {
+    let mut lines = Vec::new();
+    let mut usages = Vec::new();
+    let mut unpriced: Vec<String> = Vec::new();
+
+    for raw_line in raw.lines().filter(|l| !l.trim().is_empty()) {
+        let parsed: BatchResultLine = match serde_json::from_str(raw_line) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("Skipping unreadable result line: {}", e);
+                continue;
+            }
+        };
+
+        let submitted = tracked
+            .models
+            .get(&parsed.custom_id)
+            .unwrap_or(&tracked.model);
+        let mut line = BatchOutputLine {
+            custom_id: parsed.custom_id,
+            status: String::new(),
+            model: None,
+            text: None,
+            stop_reason: None,
+            usage: None,
+            cost: None,
+            error: None,
+        };
+
+        match parsed.result {
+            BatchResult::Succeeded { message } => {
+                let model = registry
+                    .get(&message.model)
+                    .or_else(|| registry.get(submitted));
+
+                line.status = "succeeded".to_string();
+                line.text = Some(message.text());
+                line.stop_reason = message.stop_reason.clone();
+                line.cost = model.map(|m| m.calculate_batch_cost(&message.usage));
+                // Tokens of unknown models still count, at no cost
+                if model.is_none() && !unpriced.contains(&message.model) {
+                    unpriced.push(message.model.clone());
+                }
+                usages.push(BatchUsage {
+                    model: model.map_or(message.model.clone(), |m| m.id.clone()),
+                    usage: message.usage.clone(),
+                    cost: line.cost.unwrap_or(0.0),
+                });
+                line.model = Some(message.model);
+                line.usage = Some(message.usage);
+            }
+            BatchResult::Errored { error } => {
+                line.status = "errored".to_string();
+                line.error = Some(format!(
+                    "{}: {}",
+                    error.error.error_type, error.error.message
+                ));
+            }
+            BatchResult::Canceled => line.status = "canceled".to_string(),
+            BatchResult::Expired => line.status = "expired".to_string(),
+        }
+
+        lines.push(line);
+    }
+
+    for model in unpriced {
+        eprintln!(
+            "Warning: no pricing for {}; its tokens are recorded without a cost",
+            model
+        );
+    }
+    (lines, usages)
+}
+
+// Everything the batch subcommands need from the rest of the CLI
+pub struct BatchContext<'a> {
+    pub client: ApiClient,
+    pub config: &'a Config,
+    pub registry: &'a ModelRegistry,
+    pub params: &'a SamplingParams,
+    pub profile: &'a str,
+    // Set by --output-format: results go to stdout instead of a file
+    pub format: Option<OutputFormat>,
+}
+
+fn print_results(lines: &[BatchOutputLine], format: OutputFormat) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    match format {
+        OutputFormat::Text => {
+            for line in lines {
+                writeln!(stdout, "== {} ({})", line.custom_id, line.status)?;
+                if let Some(text) = line.text.as_deref().or(line.error.as_deref()) {
+                    writeln!(stdout, "{}", text)?;
+                }
+                writeln!(stdout)?;
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, lines)?;
+            writeln!(stdout)?;
+        }
+        OutputFormat::StreamJson => {
+            for line in lines {
+                serde_json::to_writer(&mut stdout, line)?;
+                writeln!(stdout)?;
+            }
+        }
+    }
+    stdout.flush()
+}
+
+pub async fn submit(
+    ctx: &BatchContext<'_>,
+    file: &Path,
+    wait: bool,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let model = ctx.registry.get_current_model();
+    ctx.params.validate(model)?;
+    let requests = parse_batch_file(file, ctx.registry, ctx.params)?;
+
+    // Worst case for the whole batch at batch pricing
+    let mut models = HashMap::new();
+    let projected: f64 = requests
+        .iter()
+        .map(|request| {
+            let (model_id, max_tokens) = request_model_and_max_tokens(request);
+            let request_model = model_id
+                .and_then(|id| ctx.registry.get(id))
+                .unwrap_or(model);
+            if request_model.id != model.id {
+                models.insert(request.custom_id.clone(), request_model.id.clone());
+            }
+            let prompt = request.params.get("messages").map(|m| m.to_string());
+            let full = budget::projected_cost(
+                request_model,
+                prompt.as_deref().unwrap_or_default(),
+                max_tokens,
+            );
+            full * (1.0 - request_model.pricing.batch_discount.unwrap_or(0.0))
+        })
+        .sum();
+
+    let ledger = UsageLedger::new(ctx.config.get_usage_ledger_path());
+    let tracker = BudgetTracker::new(ctx.config.budget.clone());
+    if !budget::confirm(tracker.check(projected, &ledger, ctx.profile)?)? {
+        return Ok(());
+    }
+
+    let request_count = requests.len();
+    let batch = ctx.client.create_batch(requests).await?;
+
+    let mut store = BatchStore::load(ctx.config.get_batches_path())?;
+    store.batches.push(TrackedBatch {
+        id: batch.id.clone(),
+        source: file.display().to_string(),
+        submitted_at: Utc::now(),
+        profile: ctx.profile.to_string(),
+        model: model.id.clone(),
+        models,
+        request_count,
+        processing_status: batch.processing_status.clone(),
+        request_counts: batch.request_counts.clone(),
+        results_path: None,
+        usage_recorded: false,
+    });
+    store.save()?;
+
+    // Keep stdout for the results when they are printed there
+    let submitted = format!(
+        "Submitted batch {} with {} requests (at most ${:.4} at batch pricing).",
+        batch.id, request_count, projected
+    );
+    if ctx.format.is_some() {
+        eprintln!("{}", submitted);
+    } else {
+        println!("{}", submitted);
+    }
+
+    if wait {
+        let batch = poll(ctx, &batch.id).await?;
+        if batch.processing_status == "ended" {
+            return results(ctx, Some(&batch.id), output).await;
+        }
+    } else {
+        println!("Check on it with `agent batch status {}`.", batch.id);
+    }
+
+    Ok(())
+}
+
+// Poll until the batch ends, showing its progress on stderr
+async fn poll(
+    ctx: &BatchContext<'_>,
+    batch_id: &str,
+) -> Result<MessageBatch, Box<dyn std::error::Error>> {
+    let mut stderr = io::stderr();
+    // Logs get a line each time the progress changes instead
+    let redraw = stderr.is_terminal();
+    let mut last = String::new();
+
+    loop {
+        let batch = ctx.client.get_batch(batch_id).await?;
+        let progress = render_progress(&batch);
+        if redraw {
+            execute!(stderr, terminal::Clear(terminal::ClearType::CurrentLine))?;
+            eprint!("\r{}", progress);
+        } else if progress != last {
+            eprintln!("{}", progress);
+        }
+        last = progress;
+        stderr.flush()?;
+
+        update_tracked(ctx.config, &batch)?;
+
+        if batch.processing_status == "ended" {
+            if redraw {
+                eprintln!();
+            }
+            return Ok(batch);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn update_tracked(config: &Config, batch: &MessageBatch) -> io::Result<()> {
+    let mut store = BatchStore::load(config.get_batches_path())?;
+    if let Some(tracked) = store.find(Some(&batch.id)) {
+        tracked.update(batch);
+        store.save()?;
+    }
+    Ok(())
+}
+
+pub async fn status(
+    ctx: &BatchContext<'_>,
+    batch_id: Option<&str>,
+    wait: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(batch_id) = batch_id {
+        if wait {
+            poll(ctx, batch_id).await?;
+        } else {
+            let batch = ctx.client.get_batch(batch_id).await?;
+            update_tracked(ctx.config, &batch)?;
+            println!("{}", batch.id);
+            println!("  {}", render_progress(&batch));
+            if let Some(expires_at) = &batch.expires_at {
+                println!("  expires {}", expires_at);
+            }
+        }
+        return Ok(());
+    }
+
+    let mut store = BatchStore::load(ctx.config.get_batches_path())?;
+    if store.batches.is_empty() {
+        println!("No batches submitted yet. Use `agent batch submit <file.jsonl>`.");
+        return Ok(());
+    }
+
+    for tracked in store.batches.iter_mut() {
+        if tracked.processing_status != "ended" {
+            match ctx.client.get_batch(&tracked.id).await {
+                Ok(batch) => tracked.update(&batch),
+                Err(e) => {
+                    println!("{}  could not check on it: {}", tracked.id, e);
+                    continue;
+                }
+            }
+        }
+
+        let counts = &tracked.request_counts;
+        println!(
+            "{}  {}  {:<11}  {}/{} succeeded  {}  {}",
+            tracked.id,
+            tracked.submitted_at.format("%Y-%m-%d %H:%M"),
+            tracked.processing_status,
+            counts.succeeded,
+            tracked.request_count,
+            tracked.source,
+            tracked
+                .results_path
+                .as_deref()
+                .map(|p| format!("-> {}", p))
+                .unwrap_or_default()
+        );
+    }
+    store.save()?;
+
+    Ok(())
+}
+
+pub async fn results(
+    ctx: &BatchContext<'_>,
+    batch_id: Option<&str>,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = BatchStore::load(ctx.config.get_batches_path())?;
+
+    let batch_id = match (batch_id, store.find(None)) {
+        (Some(id), _) => id.to_string(),
+        (None, Some(latest)) => latest.id.clone(),
+        (None, None) => return Err("No batches submitted yet".into()),
+    };
+
+    let batch = ctx.client.get_batch(&batch_id).await?;
+    let Some(results_url) = batch.results_url.as_deref() else {
+        return Err(format!(
+            "Batch {} has not ended yet: {}",
+            batch.id,
+            render_progress(&batch)
+        )
+        .into());
+    };
+
+    // Batches submitted elsewhere are tracked from here on under the current profile
+    if store.find(Some(&batch.id)).is_none() {
+        let current = ctx.registry.get_current_model();
+        store.batches.push(TrackedBatch {
+            id: batch.id.clone(),
+            source: String::new(),
+            submitted_at: Utc::now(),
+            profile: ctx.profile.to_string(),
+            model: current.id.clone(),
+            models: HashMap::new(),
+            request_count: batch.request_counts.total() as usize,
+            processing_status: batch.processing_status.clone(),
+            request_counts: batch.request_counts.clone(),
+            results_path: None,
+            usage_recorded: false,
+        });
+    }
+    let tracked = store.find(Some(&batch.id)).unwrap();
+    tracked.update(&batch);
+
+    let raw = ctx.client.batch_results(results_url).await?;
+    let (lines, usages) = process_results(&raw, ctx.registry, tracked);
+
+    let output = match (output, ctx.format) {
+        (Some(path), _) => Some(path.to_path_buf()),
+        (None, Some(_)) => None,
+        (None, None) => Some(PathBuf::from(format!("{}.results.jsonl", batch.id))),
+    };
+    if let Some(output) = &output {
+        let mut contents = String::new();
+        for line in &lines {
+            contents.push_str(&serde_json::to_string(line)?);
+            contents.push('\n');
+        }
+        fs::write(output, contents)?;
+        tracked.results_path = Some(output.display().to_string());
+    }
+
+    if !tracked.usage_recorded && ctx.config.records_usage() {
+        let ledger = UsageLedger::new(ctx.config.get_usage_ledger_path());
+        for usage in &usages {
+            ledger.record_batch(&tracked.profile, &usage.model, &usage.usage, usage.cost)?;
+        }
+        tracked.usage_recorded = true;
+    }
+    store.save()?;
+
+    let succeeded = lines.iter().filter(|l| l.status == "succeeded").count();
+    let cost: f64 = lines.iter().filter_map(|l| l.cost).sum();
+    match output {
+        Some(output) => println!(
+            "Wrote {} results ({} succeeded, {} not) to {}. Cost at batch pricing: ${:.6}",
+            lines.len(),
+            succeeded,
+            lines.len() - succeeded,
+            output.display(),
+            cost
+        ),
+        None => {
+            print_results(&lines, ctx.format.unwrap_or(OutputFormat::Text))?;
+            eprintln!(
+                "{} results ({} succeeded, {} not). Cost at batch pricing: ${:.6}",
+                lines.len(),
+                succeeded,
+                lines.len() - succeeded,
+                cost
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn cancel(
+    ctx: &BatchContext<'_>,
+    batch_id: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = BatchStore::load(ctx.config.get_batches_path())?;
+    let batch_id = match (batch_id, store.find(None)) {
+        (Some(id), _) => id.to_string(),
+        (None, Some(latest)) => latest.id.clone(),
+        (None, None) => return Err("No batches submitted yet".into()),
+    };
+
+    let batch = ctx.client.cancel_batch(&batch_id).await?;
+    if let Some(tracked) = store.find(Some(&batch.id)) {
+        tracked.update(&batch);
+        store.save()?;
+    }
+
+    println!(
+        "Cancellation requested for {}; requests already processed still return results.",
+        batch.id
+    );
+    println!("  {}", render_progress(&batch));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<Vec<BatchRequest>, String> {
+        parse_batch(contents, &ModelRegistry::new(), &SamplingParams::default())
+    }
+
+    #[test]
+    fn native_lines_are_sent_as_written() {
+        let requests = parse(
+            r#"{"custom_id": "a", "params": {"model": "claude-x", "max_tokens": 10, "messages": []}}"#,
+        )
+        .unwrap();
+        assert_eq!(requests[0].custom_id, "a");
+        assert_eq!(requests[0].params["model"], "claude-x");
+        assert_eq!(requests[0].params["max_tokens"], 10);
+    }
+
+    #[test]
+    fn shorthand_lines_get_the_current_model_and_resolve_aliases() {
+        let registry = ModelRegistry::new();
+        let requests = parse(concat!(
+            "{\"id\": \"one\", \"prompt\": \"Hi\", \"title\": \"Greeting\"}\n",
+            "\n",
+            "{\"request_id\": \"two\", \"body\": \"Hello\", \"model\": \"sonnet\"}\n",
+            "{\"content\": \"Hey\", \"model\": \"claude-unlisted\", \"max_tokens\": 5}\n",
+        ))
+        .unwrap();
+
+        assert_eq!(requests[0].custom_id, "one");
+        assert_eq!(
+            requests[0].params["model"],
+            registry.get_current_model().id.as_str()
+        );
+        assert_eq!(
+            requests[0].params["messages"][0]["content"],
+            "Greeting\n\nHi"
+        );
+        assert_eq!(
+            requests[1].params["model"],
+            registry.get("sonnet").unwrap().id.as_str()
+        );
+        assert_eq!(requests[2].custom_id, "line-4");
+        assert_eq!(requests[2].params["model"], "claude-unlisted");
+        assert_eq!(requests[2].params["max_tokens"], 5);
+    }
+
+    #[test]
+    fn rejects_duplicate_and_invalid_ids() {
+        let duplicate =
+            parse("{\"id\": \"a\", \"prompt\": \"x\"}\n{\"id\": \"a\", \"prompt\": \"y\"}");
+        assert_eq!(duplicate.unwrap_err(), "line 2: duplicate custom_id 'a'");
+
+        for id in ["", "has space", "ü", &"x".repeat(65)] {
+            let line = json!({ "id": id, "prompt": "x" }).to_string();
+            assert!(parse(&line).unwrap_err().starts_with("line 1: custom_id"));
+        }
+        assert!(parse(&json!({ "id": "x".repeat(64), "prompt": "x" }).to_string()).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse("{").unwrap_err().starts_with("line 1: invalid JSON"));
+        assert!(parse("{\"id\": \"a\"}")
+            .unwrap_err()
+            .starts_with("line 1: expected `params`"));
+    }
+
+    #[test]
+    fn results_are_priced_against_the_submitted_model() {
+        let registry = ModelRegistry::new();
+        let haiku = registry.get("haiku").unwrap();
+        let sonnet = registry.get("sonnet").unwrap();
+        let tracked = TrackedBatch {
+            id: "batch".to_string(),
+            source: String::new(),
+            submitted_at: Utc::now(),
+            profile: "default".to_string(),
+            model: haiku.id.clone(),
+            models: HashMap::from([("b".to_string(), sonnet.id.clone())]),
+            request_count: 2,
+            processing_status: "ended".to_string(),
+            request_counts: BatchRequestCounts::default(),
+            results_path: None,
+            usage_recorded: false,
+        };
+        // The API answers with dated ids the registry doesn't list
+        let result = |id: &str, model: &str| {
+            json!({
+                "custom_id": id,
+                "result": {"type": "succeeded", "message": {
+                    "id": "msg", "type": "message", "role": "assistant", "model": model,
+                    "content": [{"type": "text", "text": "ok"}],
+                    "stop_reason": "end_turn", "stop_sequence": null,
+                    "usage": {"input_tokens": 1000, "output_tokens": 1000}
+                }}
+            })
+            .to_string()
+        };
+        let raw = format!(
+            "{}\n{}\n",
+            result("a", "claude-haiku-dated"),
+            result("b", "claude-sonnet-dated")
+        );
+
+        let (lines, usages) = process_results(&raw, &registry, &tracked);
+        assert_eq!(usages[0].model, haiku.id);
+        assert_eq!(usages[1].model, sonnet.id);
+        assert!(lines[1].cost.unwrap() > lines[0].cost.unwrap());
+        assert_eq!(lines[1].model.as_deref(), Some("claude-sonnet-dated"));
+
+        // Neither model known: the tokens are kept, without a cost
+        let tracked = TrackedBatch {
+            model: "claude-gone".to_string(),
+            models: HashMap::new(),
+            ..tracked
+        };
+        let (lines, usages) = process_results(&result("a", "claude-new"), &registry, &tracked);
+        assert_eq!(lines[0].cost, None);
+        assert_eq!(usages.len(), 1);
+        assert_eq!(usages[0].model, "claude-new");
+        assert_eq!(usages[0].usage.output_tokens, Some(1000));
+        assert_eq!(usages[0].cost, 0.0);
+    }
+
+    #[test]
+    fn enforces_the_request_count_limits() {
+        assert_eq!(parse("\n  \n").unwrap_err(), "no requests");
+
+        let line = |n| format!("{{\"prompt\": \"p\", \"id\": \"r{}\"}}\n", n);
+        let mut contents: String = (0..MAX_BATCH_REQUESTS).map(line).collect();
+        assert_eq!(parse(&contents).unwrap().len(), MAX_BATCH_REQUESTS);
+        contents.push_str(&line(MAX_BATCH_REQUESTS));
+        assert!(parse(&contents)
+            .unwrap_err()
+            .ends_with("a batch holds at most 100000"));
+    }
+}
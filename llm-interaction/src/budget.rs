@@ -0,0 +1,410 @@
+use crate::model_registry::LlmModel;
+use crate::modules::ClaudeUsage;
+use crate::usage::UsageLedger;
+use chrono::Utc;
+use dialoguer::Confirm;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+fn default_warn_at() -> f64 {
+    0.8
+}
+
+// Spending limits in USD, stored under "budget" in config.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BudgetLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily: Option<f64>,
+    // Daily limit for each named profile
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub profiles: HashMap<String, f64>,
+    // Fraction of a limit at which we start warning
+    #[serde(default = "default_warn_at")]
+    pub warn_at: f64,
+}
+
+impl Default for BudgetLimits {
+    fn default() -> Self {
+        BudgetLimits {
+            session: None,
+            daily: None,
+            profiles: HashMap::new(),
+            warn_at: default_warn_at(),
+        }
+    }
+}
+
+impl BudgetLimits {
+    pub fn is_empty(&self) -> bool {
+        self.session.is_none() && self.daily.is_none() && self.profiles.is_empty()
+    }
+
+    // Daily and profile limits are checked against the usage ledger
+    pub fn uses_ledger(&self) -> bool {
+        self.daily.is_some() || !self.profiles.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetScope {
+    Session,
+    Day,
+    Profile(String),
+}
+
+impl fmt::Display for BudgetScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetScope::Session => write!(f, "session"),
+            BudgetScope::Day => write!(f, "today"),
+            BudgetScope::Profile(name) => write!(f, "profile '{}' today", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Allowance {
+    pub scope: BudgetScope,
+    pub limit: f64,
+    pub spent: f64,
+}
+
+impl Allowance {
+    pub fn remaining(&self) -> f64 {
+        (self.limit - self.spent).max(0.0)
+    }
+}
+
+impl fmt::Display for Allowance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: ${:.4} of ${:.2} spent, ${:.4} remaining",
+            self.scope,
+            self.spent,
+            self.limit,
+            self.remaining()
+        )
+    }
+}
+
+pub enum BudgetCheck {
+    Within,
+    // Limits the request would push past the warning threshold
+    Warning(Vec<Allowance>),
+    // Limits the request would exceed
+    Exceeded(Vec<Allowance>),
+}
+
+// Worst-case cost of a request: roughly four characters per input token and
+// the full `max_tokens` of output
+pub fn projected_cost(model: &LlmModel, prompt: &str, max_tokens: u32) -> f64 {
+    let usage = ClaudeUsage {
+        input_tokens: Some((prompt.chars().count() as u32).div_ceil(4)),
+        output_tokens: Some(max_tokens),
+        ..Default::default()
+    };
+    model.calculate_cost(&usage)
+}
+
+// Tracks spend for the running session; day and profile spend come from the ledger
+pub struct BudgetTracker {
+    pub limits: BudgetLimits,
+    session_spent: f64,
+}
+
+impl BudgetTracker {
+    pub fn new(limits: BudgetLimits) -> Self {
+        BudgetTracker {
+            limits,
+            session_spent: 0.0,
+        }
+    }
+
+    pub fn add_session_spend(&mut self, cost: f64) {
+        self.session_spent += cost;
+    }
+
+    pub fn allowances(&self, ledger: &UsageLedger, profile: &str) -> io::Result<Vec<Allowance>> {
+        let mut allowances = Vec::new();
+
+        if let Some(limit) = self.limits.session {
+            allowances.push(Allowance {
+                scope: BudgetScope::Session,
+                limit,
+                spent: self.session_spent,
+            });
+        }
+
+        let profile_limit = self.limits.profiles.get(profile).copied();
+        if self.limits.daily.is_none() && profile_limit.is_none() {
+            return Ok(allowances);
+        }
+
+        let start_of_day = Utc::now()
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc();
+        let today = ledger.read(Some(start_of_day))?;
+
+        if let Some(limit) = self.limits.daily {
+            allowances.push(Allowance {
+                scope: BudgetScope::Day,
+                limit,
+                spent: today.iter().map(|r| r.cost).sum(),
+            });
+        }
+
+        if let Some(limit) = profile_limit {
+            allowances.push(Allowance {
+                scope: BudgetScope::Profile(profile.to_string()),
+                limit,
+                spent: today
+                    .iter()
+                    .filter(|r| r.profile == profile)
+                    .map(|r| r.cost)
+                    .sum(),
+            });
+        }
+
+        Ok(allowances)
+    }
+
+    pub fn check(
+        &self,
+        projected: f64,
+        ledger: &UsageLedger,
+        profile: &str,
+    ) -> io::Result<BudgetCheck> {
+        let allowances = self.allowances(ledger, profile)?;
+
+        let exceeded: Vec<Allowance> = allowances
+            .iter()
+            .filter(|a| a.spent + projected > a.limit)
+            .cloned()
+            .collect();
+        if !exceeded.is_empty() {
+            return Ok(BudgetCheck::Exceeded(exceeded));
+        }
+
+        let warnings: Vec<Allowance> = allowances
+            .into_iter()
+            .filter(|a| a.spent + projected >= a.limit * self.limits.warn_at)
+            .collect();
+        if !warnings.is_empty() {
+            return Ok(BudgetCheck::Warning(warnings));
+        }
+
+        Ok(BudgetCheck::Within)
+    }
+}
+
+// Spent and remaining for every limit that applies to `profile`
+pub fn print_budget(
+    tracker: &BudgetTracker,
+    ledger: &UsageLedger,
+    profile: &str,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let allowances = tracker.allowances(ledger, profile)?;
+    if allowances.is_empty() {
+        writeln!(
+            out,
+            "No spending limits set. Use `agent budget set` to add one."
+        )?;
+        return Ok(());
+    }
+
+    writeln!(out, "Remaining allowance:")?;
+    for allowance in &allowances {
+        writeln!(out, "  {}", allowance)?;
+    }
+    Ok(())
+}
+
+// Print any warnings and, past a limit, ask whether to go ahead anyway.
+// Returns false when the request should not be sent.
+pub fn confirm(check: BudgetCheck) -> io::Result<bool> {
+    match check {
+        BudgetCheck::Within => Ok(true),
+        BudgetCheck::Warning(allowances) => {
+            for allowance in &allowances {
+                println!("⚠️  Approaching budget limit for {}", allowance);
+            }
+            Ok(true)
+        }
+        BudgetCheck::Exceeded(allowances) => {
+            for allowance in &allowances {
+                println!("🛑 Budget limit reached for {}", allowance);
+            }
+            Confirm::new()
+                .with_prompt("Send this request anyway?")
+                .default(false)
+                .interact()
+                .map_err(io::Error::other)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_registry::ModelRegistry;
+    use crate::pricing::ModelPricing;
+    use crate::usage::UsageRecord;
+    use chrono::DateTime;
+
+    fn ledger(name: &str) -> UsageLedger {
+        let path = std::env::temp_dir()
+            .join(format!("agent-budget-{}-{}", name, std::process::id()))
+            .join("usage.jsonl");
+        let _ = std::fs::remove_file(&path);
+        UsageLedger::new(path)
+    }
+
+    fn spend(ledger: &UsageLedger, profile: &str, cost: f64) {
+        spend_at(ledger, Utc::now(), profile, cost);
+    }
+
+    fn spend_at(ledger: &UsageLedger, timestamp: DateTime<Utc>, profile: &str, cost: f64) {
+        ledger
+            .append(&UsageRecord {
+                timestamp,
+                profile: profile.to_string(),
+                model: "claude-test".to_string(),
+                input_tokens: 0,
+                output_tokens: 0,
+                cache_creation_input_tokens: 0,
+                cache_read_input_tokens: 0,
+                cost,
+                latency_ms: 0,
+                batch: false,
+            })
+            .unwrap();
+    }
+
+    fn scopes(allowances: &[Allowance]) -> Vec<BudgetScope> {
+        allowances.iter().map(|a| a.scope.clone()).collect()
+    }
+
+    #[test]
+    fn projected_cost_counts_four_characters_per_token() {
+        let mut model = ModelRegistry::new().get_current_model().clone();
+        model.pricing = ModelPricing {
+            input: 1_000_000.0,
+            output: 0.0,
+            cache_write: 0.0,
+            cache_read: 0.0,
+            batch_discount: None,
+        };
+        assert_eq!(projected_cost(&model, "", 0), 0.0);
+        assert_eq!(projected_cost(&model, "abcd", 0), 1.0);
+        assert_eq!(projected_cost(&model, "abcde", 0), 2.0);
+        // Characters, not bytes
+        assert_eq!(projected_cost(&model, "éééé", 0), 1.0);
+
+        model.pricing.output = 2_000_000.0;
+        assert_eq!(projected_cost(&model, "abcd", 10), 21.0);
+    }
+
+    #[test]
+    fn session_spend_warns_then_exceeds() {
+        let ledger = ledger("session");
+        let mut tracker = BudgetTracker::new(BudgetLimits {
+            session: Some(1.0),
+            ..Default::default()
+        });
+        assert!(!tracker.limits.uses_ledger());
+        assert!(matches!(
+            tracker.check(0.5, &ledger, "default").unwrap(),
+            BudgetCheck::Within
+        ));
+
+        tracker.add_session_spend(0.5);
+        match tracker.check(0.3, &ledger, "default").unwrap() {
+            BudgetCheck::Warning(allowances) => {
+                assert_eq!(scopes(&allowances), [BudgetScope::Session]);
+                assert_eq!(allowances[0].spent, 0.5);
+            }
+            _ => panic!("expected a warning"),
+        }
+        assert!(matches!(
+            tracker.check(0.6, &ledger, "default").unwrap(),
+            BudgetCheck::Exceeded(_)
+        ));
+    }
+
+    #[test]
+    fn daily_and_profile_limits_use_todays_ledger() {
+        let ledger = ledger("daily");
+        let mut limits = BudgetLimits {
+            daily: Some(10.0),
+            warn_at: 0.5,
+            ..Default::default()
+        };
+        limits.profiles.insert("work".to_string(), 2.0);
+        let tracker = BudgetTracker::new(limits);
+
+        spend(&ledger, "work", 1.0);
+        spend(&ledger, "home", 3.0);
+        let allowances = tracker.allowances(&ledger, "work").unwrap();
+        assert_eq!(
+            scopes(&allowances),
+            [BudgetScope::Day, BudgetScope::Profile("work".to_string())]
+        );
+        assert_eq!(allowances[0].spent, 4.0);
+        assert_eq!(allowances[1].spent, 1.0);
+        assert_eq!(allowances[1].remaining(), 1.0);
+
+        // Only the profile is past its warning threshold
+        match tracker.check(0.5, &ledger, "work").unwrap() {
+            BudgetCheck::Warning(allowances) => assert_eq!(
+                scopes(&allowances),
+                [BudgetScope::Profile("work".to_string())]
+            ),
+            _ => panic!("expected a warning"),
+        }
+        // Exceeding one limit reports only that one
+        match tracker.check(1.5, &ledger, "work").unwrap() {
+            BudgetCheck::Exceeded(allowances) => assert_eq!(
+                scopes(&allowances),
+                [BudgetScope::Profile("work".to_string())]
+            ),
+            _ => panic!("expected the profile limit to be exceeded"),
+        }
+        // Other profiles only have the daily limit
+        assert_eq!(
+            scopes(&tracker.allowances(&ledger, "home").unwrap()),
+            [BudgetScope::Day]
+        );
+        assert!(matches!(
+            tracker.check(6.5, &ledger, "home").unwrap(),
+            BudgetCheck::Exceeded(_)
+        ));
+    }
+
+    #[test]
+    fn spend_before_today_does_not_count() {
+        let ledger = ledger("yesterday");
+        spend_at(
+            &ledger,
+            Utc::now() - chrono::Duration::days(1),
+            "default",
+            100.0,
+        );
+        let tracker = BudgetTracker::new(BudgetLimits {
+            daily: Some(1.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            tracker.allowances(&ledger, "default").unwrap()[0].spent,
+            0.0
+        );
+    }
+}
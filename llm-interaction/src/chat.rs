@@ -0,0 +1,750 @@
+use crate::api::{trim_prefill, MessageResult};
+use crate::budget::BudgetCheck;
+use crate::commands::{CommandRegistry, Outcome};
+use crate::conversation::Role as ConversationRole;
+use crate::editor::{char_width, Buffer, History};
+use crate::markdown;
+use crate::output::OutputEvent;
+use crate::repl::{attach_files, write_file, Frontend, Session};
+use crate::tui::{self, BorderStyle, UiStyle};
+use crossterm::event::{
+    DisableBracketedPaste, EnableBracketedPaste, Event, EventStream, KeyCode, KeyEvent,
+    KeyEventKind, KeyModifiers,
+};
+use crossterm::execute;
+use futures_util::StreamExt;
+use ratatui::{
+    layout::{Constraint, Layout, Position, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, BorderType, Paragraph},
+    DefaultTerminal, Frame,
+};
+use std::io::{self, stdout};
+use tokio::sync::mpsc;
+
+// Rows the input pane grows to before it scrolls
+const MAX_INPUT_ROWS: usize = 8;
+
+enum Role {
+    User,
+    Assistant,
+    // Command output, errors and status messages
+    Notice,
+}
+
+struct Entry {
+    role: Role,
+    // Shown above the text: "You" or the model's name
+    label: String,
+    text: String,
+    // Lines from the last draw, with the width and text length they were
+    // rendered for
+    rendered: Option<(usize, usize, Vec<Line<'static>>)>,
+}
+
+impl Entry {
+    fn new(role: Role, label: &str, text: &str) -> Self {
+        Entry {
+            role,
+            label: label.to_string(),
+            text: text.to_string(),
+            rendered: None,
+        }
+    }
+
+    // Replies are Markdown; everything else is shown as typed
+    fn lines(&mut self, width: usize) -> &[Line<'static>] {
+        let fresh =
+            matches!(&self.rendered, Some((w, len, _)) if *w == width && *len == self.text.len());
+        if !fresh {
+            let lines = match self.role {
+                Role::Assistant => markdown::render(&self.text, width),
+                Role::User => plain_lines(&self.text, width, Style::new()),
+                Role::Notice => plain_lines(&self.text, width, Style::new().fg(Color::DarkGray)),
+            };
+            self.rendered = Some((width, self.text.len(), lines));
+        }
+        &self.rendered.as_ref().unwrap().2
+    }
+}
+
+// What the main loop should do after an event
+enum Action {
+    None,
+    Submit,
+    Quit,
+}
+
+// The full-screen chat: transcript, input pane and status bar
+struct Chat {
+    transcript: Vec<Entry>,
+    input: Buffer,
+    history: History,
+    // Position while browsing history, and the unsent text to come back to
+    history_index: Option<usize>,
+    draft: String,
+    // Rows scrolled back from the bottom of the transcript; 0 follows output
+    scroll: usize,
+    // Transcript rows visible at the last draw, for paging
+    page: usize,
+    streaming: bool,
+    input_tokens: u32,
+    output_tokens: u32,
+    cost: f64,
+    // Tokens in the last exchange, shown against the context window
+    context_tokens: u32,
+}
+
+pub async fn run(
+    mut session: Session,
+    commands: CommandRegistry,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut chat = Chat::new(History::load(Some(session.config.get_history_path())));
+    chat.show_conversation(&session);
+
+    // Restores the terminal on panic as well
+    let mut terminal = ratatui::init();
+    execute!(stdout(), EnableBracketedPaste)?;
+    let result = chat.run(&mut terminal, &mut session, &commands).await;
+    let _ = execute!(stdout(), DisableBracketedPaste);
+    ratatui::restore();
+    result
+}
+
+impl Chat {
+    fn new(history: History) -> Self {
+        let mut chat = Chat {
+            transcript: Vec::new(),
+            input: Buffer::default(),
+            history,
+            history_index: None,
+            draft: String::new(),
+            scroll: 0,
+            page: 0,
+            streaming: false,
+            input_tokens: 0,
+            output_tokens: 0,
+            cost: 0.0,
+            context_tokens: 0,
+        };
+        chat.notice("Type a message and press Enter, or /help for commands.");
+        chat
+    }
+
+    // Replace the transcript with the branch of the conversation in use
+    fn show_conversation(&mut self, session: &Session) {
+        // Only the welcome notice stays
+        self.transcript.truncate(1);
+        for turn in session.conversation.active() {
+            self.transcript.push(match turn.role {
+                ConversationRole::User => Entry::new(Role::User, "You", &turn.text),
+                ConversationRole::Assistant => {
+                    let label = session
+                        .registry
+                        .get(&turn.model)
+                        .map_or(turn.model.as_str(), |model| &model.display_name);
+                    Entry::new(Role::Assistant, label, &turn.text)
+                }
+            });
+        }
+        self.scroll = 0;
+    }
+
+    fn notice(&mut self, text: &str) {
+        self.transcript.push(Entry::new(Role::Notice, "", text));
+        self.scroll = 0;
+    }
+
+    async fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        session: &mut Session,
+        commands: &CommandRegistry,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut events = EventStream::new();
+
+        loop {
+            terminal.draw(|frame| self.draw(frame, session))?;
+            let Some(event) = events.next().await else {
+                return Ok(());
+            };
+
+            match self.handle_event(event?) {
+                Action::None => {}
+                Action::Quit => return Ok(()),
+                Action::Submit => {
+                    let input = self.input.text();
+                    self.input.set("");
+                    self.history_index = None;
+                    if !self
+                        .submit(input.trim(), terminal, session, commands, &mut events)
+                        .await?
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    // Run a command or send a prompt; returns false to quit
+    async fn submit(
+        &mut self,
+        input: &str,
+        terminal: &mut DefaultTerminal,
+        session: &mut Session,
+        commands: &CommandRegistry,
+        events: &mut EventStream,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if input.is_empty() {
+            return Ok(true);
+        }
+        self.history.add(input)?;
+
+        let position = session.conversation.current;
+        let outcome = if input.starts_with('/') {
+            let mut out = Vec::new();
+            let outcome = commands.dispatch(session, input, &mut out)?;
+            // A command may have run an editor over the screen
+            terminal.clear()?;
+            if session.conversation.current != position {
+                self.show_conversation(session);
+            }
+            let out = String::from_utf8_lossy(&out);
+            if !out.trim().is_empty() {
+                self.notice(out.trim_end());
+            }
+            outcome
+        } else {
+            Outcome::Send {
+                prompt: attach_files(input),
+                partial: String::new(),
+            }
+        };
+
+        match outcome {
+            Outcome::Handled => {}
+            Outcome::Quit => return Ok(false),
+            Outcome::Send { prompt, partial } => {
+                // /retry and /edit ask a prompt of their own
+                let asked = if session.conversation.current != position {
+                    prompt.as_str()
+                } else {
+                    input
+                };
+                // /continue goes on with the last reply instead
+                if partial.is_empty() {
+                    self.transcript.push(Entry::new(Role::User, "You", asked));
+                }
+                let mut screen = Screen {
+                    chat: self,
+                    terminal,
+                    events,
+                };
+                session.send(&mut screen, prompt, partial).await?;
+            }
+            Outcome::Write { path, contents } => {
+                let question = format!("{} exists. Overwrite it? (y/n)", path.display());
+                if !path.exists() || self.ask(&question, terminal, session, events).await? {
+                    self.notice(&write_file(&path, &contents));
+                } else {
+                    self.notice("Not saved.");
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // Show budget warnings and, past a limit, ask before sending
+    async fn confirm(
+        &mut self,
+        check: BudgetCheck,
+        terminal: &mut DefaultTerminal,
+        session: &Session,
+        events: &mut EventStream,
+    ) -> io::Result<bool> {
+        let allowances = match check {
+            BudgetCheck::Within => return Ok(true),
+            BudgetCheck::Warning(allowances) => {
+                for allowance in &allowances {
+                    self.notice(&format!("⚠️  Approaching budget limit for {}", allowance));
+                }
+                return Ok(true);
+            }
+            BudgetCheck::Exceeded(allowances) => allowances,
+        };
+
+        for allowance in &allowances {
+            self.notice(&format!("🛑 Budget limit reached for {}", allowance));
+        }
+        let send = self
+            .ask("Send this request anyway? (y/n)", terminal, session, events)
+            .await?;
+        if !send {
+            self.notice("Not sent.");
+        }
+        Ok(send)
+    }
+
+    // Show `question` and wait for y or n
+    async fn ask(
+        &mut self,
+        question: &str,
+        terminal: &mut DefaultTerminal,
+        session: &Session,
+        events: &mut EventStream,
+    ) -> io::Result<bool> {
+        self.notice(question);
+
+        loop {
+            terminal.draw(|frame| self.draw(frame, session))?;
+            let Some(event) = events.next().await else {
+                return Ok(false);
+            };
+            if let Event::Key(key) = event? {
+                match key.code {
+                    KeyCode::Char('y') | KeyCode::Char('Y') => return Ok(true),
+                    KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc | KeyCode::Enter => {
+                        return Ok(false)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Stream a reply into the transcript while still handling keys and
+    // resizes. Returns None when it failed or was cancelled with Ctrl-C.
+    async fn stream(
+        &mut self,
+        prompt: &str,
+        prefill: Option<&str>,
+        terminal: &mut DefaultTerminal,
+        session: &Session,
+        events: &mut EventStream,
+    ) -> io::Result<Option<MessageResult>> {
+        let model = session.registry.get_current_model();
+
+        // A continuation keeps writing into the reply it continues, above
+        // the notices that came after it
+        let continued = prefill.and_then(|_| {
+            self.transcript
+                .iter()
+                .rposition(|entry| matches!(entry.role, Role::Assistant))
+        });
+        let reply = continued.unwrap_or_else(|| {
+            self.transcript
+                .push(Entry::new(Role::Assistant, &model.display_name, ""));
+            self.transcript.len() - 1
+        });
+        self.transcript[reply].text = prefill.map(trim_prefill).unwrap_or_default().to_string();
+        self.scroll = 0;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut on_event = move |event: &OutputEvent| {
+            if let OutputEvent::TextDelta { text, .. } = event {
+                let _ = tx.send(text.clone());
+            }
+            Ok(())
+        };
+        let history = session.conversation.history(prompt, prefill.is_some());
+        let request = session.client.send_message(
+            &history,
+            prompt,
+            prefill,
+            model,
+            &session.params,
+            &mut on_event,
+        );
+        tokio::pin!(request);
+
+        self.streaming = true;
+        let result = loop {
+            terminal.draw(|frame| self.draw(frame, session))?;
+            tokio::select! {
+                result = &mut request => break result,
+                Some(text) = rx.recv() => self.transcript[reply].text.push_str(&text),
+                event = events.next() => {
+                    let Some(event) = event else {
+                        self.streaming = false;
+                        return Ok(None);
+                    };
+                    let event = event?;
+                    if is_ctrl_c(&event) {
+                        self.streaming = false;
+                        self.notice("Cancelled.");
+                        return Ok(None);
+                    }
+                    // Typing goes on, but nothing is sent until the reply is done
+                    if let Action::Quit = self.handle_event(event) {
+                        self.streaming = false;
+                        return Ok(None);
+                    }
+                }
+            }
+        };
+        self.streaming = false;
+
+        match result {
+            Ok(result) => {
+                self.transcript[reply].text = result.text.clone();
+                if let Some(usage) = &result.usage {
+                    let input = usage.input_tokens.unwrap_or(0);
+                    let output = usage.output_tokens.unwrap_or(0);
+                    self.input_tokens += input;
+                    self.output_tokens += output;
+                    self.context_tokens = input + output;
+                    self.cost += model.calculate_cost(usage);
+                }
+                Ok(Some(result))
+            }
+            Err(e) => {
+                if self.transcript[reply].text.is_empty() {
+                    self.transcript.remove(reply);
+                }
+                self.notice(&format!("Error: {}", e));
+                Ok(None)
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) -> Action {
+        let key = match event {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            Event::Paste(text) => {
+                self.input
+                    .insert(&text.replace("\r\n", "\n").replace('\r', "\n"));
+                return Action::None;
+            }
+            // Resizes only need a redraw
+            _ => return Action::None,
+        };
+        self.handle_key(key)
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        let input = &mut self.input;
+
+        match key.code {
+            KeyCode::Enter if shift || alt => input.insert("\n"),
+            KeyCode::Char('j') if ctrl => input.insert("\n"),
+            KeyCode::Enter => {
+                // A trailing backslash continues on the next line
+                if input.cursor == input.chars.len() && input.chars.last() == Some(&'\\') {
+                    input.chars.pop();
+                    input.cursor -= 1;
+                    input.insert("\n");
+                } else if !self.streaming {
+                    return Action::Submit;
+                }
+            }
+            KeyCode::Char('c') if ctrl => {
+                if input.chars.is_empty() {
+                    return Action::Quit;
+                }
+                input.set("");
+            }
+            KeyCode::Char('d') if ctrl => {
+                if input.chars.is_empty() {
+                    return Action::Quit;
+                }
+                if input.cursor < input.chars.len() {
+                    input.chars.remove(input.cursor);
+                }
+            }
+
+            // Transcript scrolling
+            KeyCode::PageUp => self.scroll += (self.page / 2).max(1),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub((self.page / 2).max(1)),
+            KeyCode::Up if shift => self.scroll += 1,
+            KeyCode::Down if shift => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::End if ctrl => self.scroll = 0,
+
+            // Movement
+            KeyCode::Char('a') if ctrl => input.cursor = input.line_start(),
+            KeyCode::Home => input.cursor = input.line_start(),
+            KeyCode::Char('e') if ctrl => input.cursor = input.line_end(),
+            KeyCode::End => input.cursor = input.line_end(),
+            KeyCode::Char('b') if alt => input.cursor = input.word_left(),
+            KeyCode::Char('f') if alt => input.cursor = input.word_right(),
+            KeyCode::Left if ctrl || alt => input.cursor = input.word_left(),
+            KeyCode::Right if ctrl || alt => input.cursor = input.word_right(),
+            KeyCode::Left => input.cursor = input.cursor.saturating_sub(1),
+            KeyCode::Right => input.cursor = (input.cursor + 1).min(input.chars.len()),
+
+            // Up and down move between lines, then through history
+            KeyCode::Up if !input.on_first_line() => input.move_vertical(true),
+            KeyCode::Down if !input.on_last_line() => input.move_vertical(false),
+            KeyCode::Up => {
+                let entries = self.history.entries();
+                let next = match self.history_index {
+                    None if !entries.is_empty() => {
+                        self.draft = input.text();
+                        Some(entries.len() - 1)
+                    }
+                    Some(i) if i > 0 => Some(i - 1),
+                    other => other,
+                };
+                if let Some(i) = next {
+                    self.history_index = Some(i);
+                    input.set(&entries[i]);
+                }
+            }
+            KeyCode::Down => {
+                if let Some(i) = self.history_index {
+                    if i + 1 < self.history.entries().len() {
+                        self.history_index = Some(i + 1);
+                        input.set(&self.history.entries()[i + 1]);
+                    } else {
+                        self.history_index = None;
+                        input.set(&self.draft);
+                    }
+                }
+            }
+
+            // Deletion
+            KeyCode::Backspace if alt => {
+                let start = input.word_left();
+                input.kill(start, input.cursor);
+            }
+            KeyCode::Backspace if input.cursor > 0 => {
+                input.cursor -= 1;
+                input.chars.remove(input.cursor);
+            }
+            KeyCode::Delete if input.cursor < input.chars.len() => {
+                input.chars.remove(input.cursor);
+            }
+            KeyCode::Char('k') if ctrl => {
+                let end = input.line_end();
+                input.kill(input.cursor, end);
+            }
+            KeyCode::Char('u') if ctrl => {
+                let start = input.line_start();
+                input.kill(start, input.cursor);
+            }
+            KeyCode::Char('w') if ctrl => {
+                let start = input.word_left();
+                input.kill(start, input.cursor);
+            }
+
+            KeyCode::Tab => input.insert("    "),
+            KeyCode::Char(c) if !ctrl && !alt => input.insert(&c.to_string()),
+            _ => {}
+        }
+        Action::None
+    }
+
+    fn draw(&mut self, frame: &mut Frame, session: &Session) {
+        let area = frame.area();
+        let input_width = area.width.saturating_sub(2).max(1) as usize;
+        let (input_rows, (cursor_row, cursor_col)) = layout_input(&self.input, input_width);
+        let input_height = input_rows.len().min(MAX_INPUT_ROWS);
+
+        let [transcript_area, input_area, status_area] = Layout::vertical([
+            Constraint::Min(1),
+            Constraint::Length(input_height as u16 + 2),
+            Constraint::Length(1),
+        ])
+        .areas(area);
+
+        let style = &session.ui.style;
+        self.draw_transcript(frame, transcript_area, style);
+
+        // Keep the cursor row in view once the input outgrows its pane
+        let top = (cursor_row + 1).saturating_sub(input_height);
+        let lines: Vec<Line> = input_rows[top..top + input_height]
+            .iter()
+            .map(|row| Line::raw(row.as_str()))
+            .collect();
+        let hint = if self.streaming {
+            " Ctrl-C stop "
+        } else if area.width >= 72 {
+            " Enter send · Alt-Enter newline · PgUp/PgDn scroll · Ctrl-C quit "
+        } else {
+            " Enter send · Ctrl-C quit "
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(
+                Block::bordered()
+                    .border_type(border_type(&style.border_style))
+                    .border_style(Style::new().fg(style.prompt_fg.into()))
+                    .title(" Message ")
+                    .title_bottom(Line::from(hint).right_aligned()),
+            ),
+            input_area,
+        );
+        frame.set_cursor_position(Position::new(
+            input_area.x + 1 + cursor_col as u16,
+            input_area.y + 1 + (cursor_row - top) as u16,
+        ));
+
+        frame.render_widget(self.status_line(session), status_area);
+    }
+
+    fn draw_transcript(&mut self, frame: &mut Frame, area: Rect, style: &UiStyle) {
+        let width = area.width.saturating_sub(2).max(1) as usize;
+        let height = area.height.saturating_sub(2) as usize;
+        self.page = height;
+
+        let mut rows: Vec<Line> = Vec::new();
+        for entry in &mut self.transcript {
+            if !entry.label.is_empty() {
+                rows.push(Line::styled(
+                    entry.label.clone(),
+                    Style::new()
+                        .fg(style.prompt_fg.into())
+                        .add_modifier(Modifier::BOLD),
+                ));
+            }
+            rows.extend(entry.lines(width).iter().cloned());
+            rows.push(Line::raw(""));
+        }
+
+        let max_scroll = rows.len().saturating_sub(height);
+        self.scroll = self.scroll.min(max_scroll);
+        let start = max_scroll - self.scroll;
+        let visible: Vec<Line> = rows.drain(start..).take(height).collect();
+
+        let mut block = Block::bordered()
+            .border_type(border_type(&style.border_style))
+            .border_style(Style::new().fg(style.status_fg.into()));
+        if self.scroll > 0 {
+            block = block.title_bottom(
+                Line::from(format!(" ↓ {} more (PgDn) ", self.scroll)).right_aligned(),
+            );
+        }
+        frame.render_widget(Paragraph::new(visible).block(block), area);
+    }
+
+    fn status_line(&self, session: &Session) -> Line<'static> {
+        let model = session.registry.get_current_model();
+        let context = if model.context_window > 0 {
+            self.context_tokens as f64 / model.context_window as f64 * 100.0
+        } else {
+            0.0
+        };
+        let style = &session.ui.style;
+        let dim = Style::new().fg(style.status_fg.into());
+
+        let mut spans = vec![
+            Span::styled(
+                format!(" {} ", model.display_name),
+                Style::new()
+                    .fg(style.prompt_fg.into())
+                    .add_modifier(Modifier::REVERSED),
+            ),
+            Span::raw(format!(
+                " {} in · {} out ",
+                self.input_tokens, self.output_tokens
+            )),
+            Span::styled("│", dim),
+            Span::raw(format!(" ${:.4} ", self.cost)),
+            Span::styled("│", dim),
+            Span::raw(format!(" context {:.0}% ", context)),
+        ];
+        if self.streaming {
+            spans.push(Span::styled("│", dim));
+            spans.push(Span::styled(" ● streaming ", Style::new().fg(Color::Green)));
+        }
+        Line::from(spans)
+    }
+}
+
+// The chat as `Session::send` sees it, with the terminal it draws on and
+// the events it reads while a reply streams in
+struct Screen<'a> {
+    chat: &'a mut Chat,
+    terminal: &'a mut DefaultTerminal,
+    events: &'a mut EventStream,
+}
+
+impl Frontend for Screen<'_> {
+    async fn confirm(&mut self, session: &Session, check: BudgetCheck) -> io::Result<bool> {
+        self.chat
+            .confirm(check, self.terminal, session, self.events)
+            .await
+    }
+
+    async fn stream(
+        &mut self,
+        session: &mut Session,
+        prompt: &str,
+        prefill: Option<&str>,
+    ) -> io::Result<Option<MessageResult>> {
+        self.chat
+            .stream(prompt, prefill, self.terminal, session, self.events)
+            .await
+    }
+
+    fn notice(&mut self, _session: &Session, text: &str) -> io::Result<()> {
+        self.chat.notice(text);
+        Ok(())
+    }
+}
+
+fn border_type(style: &BorderStyle) -> BorderType {
+    match style {
+        BorderStyle::Single => BorderType::Plain,
+        BorderStyle::Double => BorderType::Double,
+        BorderStyle::Rounded => BorderType::Rounded,
+        BorderStyle::Bold => BorderType::Thick,
+    }
+}
+
+fn is_ctrl_c(event: &Event) -> bool {
+    matches!(event, Event::Key(key)
+        if key.kind != KeyEventKind::Release
+            && key.code == KeyCode::Char('c')
+            && key.modifiers.contains(KeyModifiers::CONTROL))
+}
+
+fn plain_lines(text: &str, width: usize, style: Style) -> Vec<Line<'static>> {
+    tui::wrap(text, width)
+        .into_iter()
+        .map(|row| Line::styled(row, style))
+        .collect()
+}
+
+// Input text as rows of `width` columns, plus the cursor's row and column
+fn layout_input(input: &Buffer, width: usize) -> (Vec<String>, (usize, usize)) {
+    let mut rows = vec![String::new()];
+    let mut row_width = 0;
+    let mut cursor = (0, 0);
+
+    for (i, &c) in input.chars.iter().enumerate() {
+        if i == input.cursor {
+            cursor = (rows.len() - 1, row_width);
+        }
+        if c == '\n' {
+            rows.push(String::new());
+            row_width = 0;
+            continue;
+        }
+        let w = char_width(c);
+        if row_width + w > width {
+            rows.push(String::new());
+            row_width = 0;
+            if i == input.cursor {
+                cursor = (rows.len() - 1, 0);
+            }
+        }
+        rows.last_mut().unwrap().push(c);
+        row_width += w;
+    }
+    if input.cursor == input.chars.len() {
+        cursor = (rows.len() - 1, row_width);
+    }
+
+    // A cursor just past a full row sits at the start of the next one
+    if cursor.1 >= width {
+        if cursor.0 + 1 == rows.len() {
+            rows.push(String::new());
+        }
+        cursor = (cursor.0 + 1, 0);
+    }
+    (rows, cursor)
+}
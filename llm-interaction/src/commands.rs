@@ -0,0 +1,755 @@
+use crate::budget;
+use crate::conversation::{self, Role};
+use crate::editor;
+use crate::markdown::{self, CodeBlock};
+use crate::repl::Session;
+use crate::sampling;
+use crate::search::{self, SearchQuery};
+use crate::templates::{self, PromptTemplate};
+use crate::theme;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+
+type CommandResult = Result<Outcome, Box<dyn std::error::Error>>;
+
+// What the REPL does after a command has run
+pub enum Outcome {
+    Handled,
+    Quit,
+    // Send a prompt, continuing from `partial` when it isn't empty
+    Send { prompt: String, partial: String },
+    // Write `contents` to `path`, asking first if the file exists
+    Write { path: PathBuf, contents: String },
+}
+
+// Commands write their output to `out` so the full-screen chat can show it
+type Handler = fn(&CommandRegistry, &mut Session, &str, &mut dyn Write) -> CommandResult;
+
+enum Action {
+    Builtin(Handler),
+    // A user command from a Markdown file that expands into a prompt
+    Prompt(Box<PromptTemplate>),
+}
+
+// What Tab offers for a command's first argument
+#[derive(Clone, Copy)]
+pub enum ArgValues {
+    None,
+    // Model ids and aliases
+    Models,
+    // Built-in and user theme names
+    Themes,
+    Words(&'static [&'static str]),
+}
+
+pub struct SlashCommand {
+    pub name: String,
+    // Usage hint for the arguments, e.g. `<key> <value>`
+    pub args: String,
+    pub help: String,
+    pub values: ArgValues,
+    action: Action,
+}
+
+impl SlashCommand {
+    fn builtin(name: &str, args: &str, help: &str, handler: Handler) -> Self {
+        SlashCommand {
+            name: name.to_string(),
+            args: args.to_string(),
+            help: help.to_string(),
+            values: ArgValues::None,
+            action: Action::Builtin(handler),
+        }
+    }
+
+    fn completes(mut self, values: ArgValues) -> Self {
+        self.values = values;
+        self
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        matches!(self.action, Action::Builtin(_))
+    }
+
+    pub fn usage(&self) -> String {
+        if self.args.is_empty() {
+            format!("/{}", self.name)
+        } else {
+            format!("/{} {}", self.name, self.args)
+        }
+    }
+}
+
+pub struct CommandRegistry {
+    commands: Vec<SlashCommand>,
+}
+
+impl CommandRegistry {
+    pub fn builtin() -> Self {
+        CommandRegistry {
+            commands: vec![
+                SlashCommand::builtin("help", "", "Show this help message", help),
+                SlashCommand::builtin("quit", "", "Exit the program", quit),
+                SlashCommand::builtin(
+                    "models",
+                    "[model]",
+                    "List models, or switch model for this session",
+                    models,
+                )
+                .completes(ArgValues::Models),
+                SlashCommand::builtin("budget", "", "Show remaining budget", show_budget),
+                SlashCommand::builtin(
+                    "set",
+                    "<key> <value>",
+                    "Change a sampling parameter for this session",
+                    set,
+                )
+                .completes(ArgValues::Words(&sampling::PARAM_KEYS)),
+                SlashCommand::builtin(
+                    "params",
+                    "[save|reset]",
+                    "Show, save or reset sampling parameters",
+                    params,
+                )
+                .completes(ArgValues::Words(&["save", "reset"])),
+                SlashCommand::builtin("theme", "[name]", "List themes, or switch theme", theme)
+                    .completes(ArgValues::Themes),
+                SlashCommand::builtin(
+                    "retry",
+                    "[model]",
+                    "Ask for another answer to the last prompt, optionally once from another model",
+                    retry,
+                )
+                .completes(ArgValues::Models),
+                SlashCommand::builtin(
+                    "edit",
+                    "",
+                    "Change the last prompt in $EDITOR and ask again",
+                    edit,
+                ),
+                SlashCommand::builtin("undo", "", "Go back to before the last prompt", undo),
+                SlashCommand::builtin(
+                    "branch",
+                    "[n]",
+                    "List the conversation's branches, or switch to one",
+                    branch,
+                ),
+                SlashCommand::builtin(
+                    "search",
+                    "<words> [--model m] [--role r] [--since d] [--until d]",
+                    "Search saved conversations",
+                    search,
+                ),
+                SlashCommand::builtin(
+                    "copy",
+                    "<n>",
+                    "Copy code block n of the last answer to the clipboard",
+                    copy,
+                ),
+                SlashCommand::builtin(
+                    "save",
+                    "<n> <path>",
+                    "Save code block n of the last answer to a file",
+                    save,
+                ),
+                SlashCommand::builtin(
+                    "save-response",
+                    "<path>",
+                    "Save the last answer to a file",
+                    save_response,
+                ),
+                SlashCommand::builtin(
+                    "continue",
+                    "",
+                    "Continue a response cut off at max_tokens",
+                    continue_response,
+                ),
+            ],
+        }
+    }
+
+    // Built-in commands plus Markdown commands from `dirs`; earlier
+    // directories win, and built-ins can't be replaced
+    pub fn load(dirs: &[PathBuf]) -> Self {
+        let mut registry = Self::builtin();
+
+        for template in templates::list(dirs) {
+            if registry.find(&template.name).is_some() {
+                eprintln!(
+                    "Skipping {}: /{} is a built-in command",
+                    template.path.display(),
+                    template.name
+                );
+                continue;
+            }
+            registry.commands.push(SlashCommand {
+                name: template.name.clone(),
+                args: template.meta.args.clone().unwrap_or_default(),
+                help: template
+                    .meta
+                    .description
+                    .clone()
+                    .unwrap_or_else(|| format!("Prompt from {}", template.path.display())),
+                values: ArgValues::None,
+                action: Action::Prompt(Box::new(template)),
+            });
+        }
+
+        registry
+    }
+
+    pub fn commands(&self) -> &[SlashCommand] {
+        &self.commands
+    }
+
+    pub fn find(&self, name: &str) -> Option<&SlashCommand> {
+        self.commands.iter().find(|c| c.name == name)
+    }
+
+    pub fn help(&self) -> String {
+        let width = self
+            .commands
+            .iter()
+            .map(|c| c.usage().len())
+            .max()
+            .unwrap_or(0);
+        let line = |c: &SlashCommand| format!("  {:<width$}  {}\n", c.usage(), c.help);
+
+        let mut out = String::from("Available commands:\n");
+        for command in self.commands.iter().filter(|c| c.is_builtin()) {
+            out.push_str(&line(command));
+        }
+        if self.commands.iter().any(|c| !c.is_builtin()) {
+            out.push_str("\nYour commands:\n");
+            for command in self.commands.iter().filter(|c| !c.is_builtin()) {
+                out.push_str(&line(command));
+            }
+        }
+        out
+    }
+
+    // Run `/name args...`
+    pub fn dispatch(
+        &self,
+        session: &mut Session,
+        input: &str,
+        out: &mut dyn Write,
+    ) -> CommandResult {
+        let input = input.trim().trim_start_matches('/');
+        let (name, args) = input
+            .split_once(char::is_whitespace)
+            .map(|(name, args)| (name, args.trim()))
+            .unwrap_or((input, ""));
+
+        let Some(command) = self.find(name) else {
+            writeln!(out, "Unknown command /{}. Type /help for the list.", name)?;
+            return Ok(Outcome::Handled);
+        };
+
+        match &command.action {
+            Action::Builtin(handler) => handler(self, session, args, out),
+            Action::Prompt(template) => expand(command, template, args, out),
+        }
+    }
+}
+
+// Arguments are appended when the file doesn't use them.
+fn expand(
+    command: &SlashCommand,
+    template: &PromptTemplate,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    let vars = templates::arg_vars(args);
+
+    if template.check_vars(&vars).is_err() {
+        writeln!(out, "Usage: {}", command.usage())?;
+        return Ok(Outcome::Handled);
+    }
+    let mut prompt = match template.render(&vars, None) {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            writeln!(out, "Error: {}", e)?;
+            return Ok(Outcome::Handled);
+        }
+    };
+    if !args.is_empty() && !template.body.contains("{{") {
+        prompt = format!("{}\n\n{}", prompt, args);
+    }
+
+    Ok(Outcome::Send {
+        prompt,
+        partial: String::new(),
+    })
+}
+
+fn help(
+    registry: &CommandRegistry,
+    _session: &mut Session,
+    _args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    write!(out, "{}", registry.help())?;
+    Ok(Outcome::Handled)
+}
+
+fn quit(
+    _registry: &CommandRegistry,
+    _session: &mut Session,
+    _args: &str,
+    _out: &mut dyn Write,
+) -> CommandResult {
+    Ok(Outcome::Quit)
+}
+
+fn models(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    if args.is_empty() {
+        let current = session.registry.get_current_model().id.clone();
+        for model in session.registry.list_models() {
+            let marker = if model.id == current { "*" } else { " " };
+            writeln!(
+                out,
+                "{} {:<28} {} ({})",
+                marker,
+                model.id,
+                model.display_name,
+                model.aliases.join(", ")
+            )?;
+        }
+        writeln!(
+            out,
+            "Switch with /models <model>; `agent models use` changes the default."
+        )?;
+        return Ok(Outcome::Handled);
+    }
+
+    switch_model(session, args, "for this session", out)?;
+    Ok(Outcome::Handled)
+}
+
+// Use another model for the rest of the session; false if there's no such model
+// `scope` says for how long, e.g. "for this session"
+fn switch_model(
+    session: &mut Session,
+    name: &str,
+    scope: &str,
+    out: &mut dyn Write,
+) -> io::Result<bool> {
+    match session.registry.select_model(name) {
+        Ok(model) => {
+            let model = model.clone();
+            if let Err(e) = session.params.validate(&model) {
+                writeln!(out, "Warning: {}", e)?;
+            }
+            session.ui.set_model(&model);
+            writeln!(out, "Switched to {} {}.", model.display_name, scope)?;
+            Ok(true)
+        }
+        Err(e) => {
+            writeln!(out, "Error: {}", e)?;
+            Ok(false)
+        }
+    }
+}
+
+// Saving can fail without ending the session
+fn save_conversation(session: &Session, out: &mut dyn Write) -> io::Result<()> {
+    if let Err(e) = session.store.save(&session.conversation) {
+        writeln!(out, "Warning: could not save conversation: {}", e)?;
+    }
+    Ok(())
+}
+
+// The earlier answer stays as a branch. Another model answers this once;
+// the session goes back to its own model afterwards.
+fn retry(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    if session.conversation.last_answer().is_none() {
+        writeln!(out, "There is no answer to retry.")?;
+        return Ok(Outcome::Handled);
+    }
+    if !args.is_empty() {
+        let current = session.registry.get_current_model().id.clone();
+        if !switch_model(session, args, "for this answer", out)? {
+            return Ok(Outcome::Handled);
+        }
+        session.restore_model = Some(current);
+    }
+
+    let prompt = session.conversation.rewind().unwrap_or_default();
+    session.truncated = None;
+    Ok(Outcome::Send {
+        prompt,
+        partial: String::new(),
+    })
+}
+
+fn edit(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    _args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    let Some(prompt) = session
+        .conversation
+        .active()
+        .into_iter()
+        .rfind(|turn| turn.role == Role::User)
+        .map(|turn| turn.text.clone())
+    else {
+        writeln!(out, "There is no prompt to edit.")?;
+        return Ok(Outcome::Handled);
+    };
+
+    let edited = match editor::edit_in_editor(&prompt, session.full_screen) {
+        Ok(edited) => edited,
+        Err(e) => {
+            writeln!(out, "Error: {}", e)?;
+            return Ok(Outcome::Handled);
+        }
+    };
+    let edited = edited.trim_end();
+    if edited.trim().is_empty() || edited == prompt.trim_end() {
+        writeln!(out, "The prompt is unchanged; nothing was sent.")?;
+        return Ok(Outcome::Handled);
+    }
+
+    session.conversation.rewind();
+    session.truncated = None;
+    Ok(Outcome::Send {
+        prompt: edited.to_string(),
+        partial: String::new(),
+    })
+}
+
+fn undo(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    _args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    if session.conversation.rewind().is_none() {
+        writeln!(out, "There is nothing to undo.")?;
+        return Ok(Outcome::Handled);
+    }
+    session.truncated = None;
+    save_conversation(session, out)?;
+    writeln!(
+        out,
+        "Went back to before your last prompt. It's kept as a branch; see /branch."
+    )?;
+    Ok(Outcome::Handled)
+}
+
+fn branch(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    let branches = session.conversation.branches();
+    if branches.is_empty() {
+        writeln!(out, "The conversation has no messages yet.")?;
+        return Ok(Outcome::Handled);
+    }
+
+    if args.is_empty() {
+        let current = session.conversation.current;
+        for (n, &last) in branches.iter().enumerate() {
+            let turns = session.conversation.branch(last);
+            let last_text = |role: Role| {
+                turns
+                    .iter()
+                    .rfind(|turn| turn.role == role)
+                    .map(|turn| conversation::summary(&turn.text))
+                    .unwrap_or_default()
+            };
+            let marker = if current == Some(last) { "*" } else { " " };
+            writeln!(
+                out,
+                "{} {}. {} messages, {} ({})",
+                marker,
+                n + 1,
+                turns.len(),
+                last_text(Role::User),
+                turns.last().map_or("", |turn| turn.model.as_str())
+            )?;
+            writeln!(out, "     {}", last_text(Role::Assistant))?;
+        }
+        if current.is_none_or(|current| !branches.contains(&current)) {
+            writeln!(
+                out,
+                "You're at an earlier point; your next prompt starts a new branch."
+            )?;
+        }
+        writeln!(out, "Switch with /branch <n>.")?;
+        return Ok(Outcome::Handled);
+    }
+
+    match args.parse::<usize>() {
+        Ok(n) if (1..=branches.len()).contains(&n) => {
+            session.conversation.switch_branch(branches[n - 1]);
+            session.truncated = None;
+            save_conversation(session, out)?;
+            writeln!(out, "Switched to branch {}.", n)?;
+        }
+        _ => writeln!(
+            out,
+            "There is no branch {}; the conversation has {}.",
+            args,
+            branches.len()
+        )?,
+    }
+    Ok(Outcome::Handled)
+}
+
+fn theme(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    let dir = session.config.get_themes_dir();
+    if args.is_empty() {
+        let current = session
+            .config
+            .theme
+            .clone()
+            .unwrap_or_else(|| theme::DEFAULT_THEME.to_string());
+        for name in theme::names(&dir) {
+            let marker = if name == current { "*" } else { " " };
+            writeln!(out, "{} {}", marker, name)?;
+        }
+        writeln!(
+            out,
+            "Switch with /theme <name>; add your own as TOML files in {}.",
+            dir.display()
+        )?;
+        return Ok(Outcome::Handled);
+    }
+
+    match theme::load(args, &dir) {
+        Ok(style) => {
+            session.ui.style = style;
+            session.config.set_theme(args.to_string())?;
+            writeln!(out, "Switched to the {} theme.", args)?;
+        }
+        Err(e) => writeln!(out, "Error: {}", e)?,
+    }
+    Ok(Outcome::Handled)
+}
+
+fn search(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    match SearchQuery::parse(args) {
+        Ok(query) if !query.terms.is_empty() => {
+            let highlight = !session.ui.plain && !session.full_screen;
+            search::run(&session.store, &query, highlight, out)?;
+        }
+        Ok(_) => writeln!(out, "Usage: /search <words> [--model m] [--role user|assistant] [--since date] [--until date]")?,
+        Err(e) => writeln!(out, "Error: {}", e)?,
+    }
+    Ok(Outcome::Handled)
+}
+
+// Code block `number` (from 1) of the last answer
+fn code_block(session: &Session, number: &str) -> Result<CodeBlock, String> {
+    let Some(response) = session.conversation.last_answer() else {
+        return Err("There is no answer yet.".to_string());
+    };
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("'{}' is not a code block number.", number))?;
+    let mut blocks = markdown::code_blocks(response);
+    match blocks.len() {
+        0 => Err("The last answer has no code blocks.".to_string()),
+        count if number == 0 || number > count => Err(format!(
+            "There is no code block {}; the last answer has {}.",
+            number, count
+        )),
+        _ => Ok(blocks.swap_remove(number - 1)),
+    }
+}
+
+// Uses OSC 52, which asks the terminal itself to set the clipboard, so it
+// also works over SSH
+fn copy(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    if args.is_empty() {
+        writeln!(out, "Usage: /copy <n>")?;
+        return Ok(Outcome::Handled);
+    }
+    let block = match code_block(session, args) {
+        Ok(block) => block,
+        Err(e) => {
+            writeln!(out, "Error: {}", e)?;
+            return Ok(Outcome::Handled);
+        }
+    };
+    if !io::stdout().is_terminal() {
+        writeln!(out, "Error: copying to the clipboard needs a terminal.")?;
+        return Ok(Outcome::Handled);
+    }
+
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", BASE64.encode(&block.text))?;
+    stdout.flush()?;
+    writeln!(
+        out,
+        "Copied code block {} ({} lines) to the clipboard.",
+        args,
+        block.text.lines().count()
+    )?;
+    Ok(Outcome::Handled)
+}
+
+fn save(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    let Some((number, path)) = args.split_once(char::is_whitespace) else {
+        writeln!(out, "Usage: /save <n> <path>")?;
+        return Ok(Outcome::Handled);
+    };
+    match code_block(session, number) {
+        Ok(block) => Ok(Outcome::Write {
+            path: PathBuf::from(path.trim()),
+            contents: block.text,
+        }),
+        Err(e) => {
+            writeln!(out, "Error: {}", e)?;
+            Ok(Outcome::Handled)
+        }
+    }
+}
+
+fn save_response(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    if args.is_empty() {
+        writeln!(out, "Usage: /save-response <path>")?;
+        return Ok(Outcome::Handled);
+    }
+    match session.conversation.last_answer() {
+        Some(response) => Ok(Outcome::Write {
+            path: PathBuf::from(args),
+            contents: format!("{}\n", response.trim_end()),
+        }),
+        None => {
+            writeln!(out, "Error: There is no answer yet.")?;
+            Ok(Outcome::Handled)
+        }
+    }
+}
+
+fn show_budget(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    _args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    budget::print_budget(&session.budget, &session.ledger, &session.profile, out)?;
+    Ok(Outcome::Handled)
+}
+
+fn set(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    let mut parts = args.splitn(2, char::is_whitespace);
+    let key = parts.next().unwrap_or_default();
+    let value = parts.next().unwrap_or_default();
+    if key.is_empty() {
+        writeln!(
+            out,
+            "Usage: /set <key> <value>  (keys: {}; use `default` to clear)",
+            sampling::PARAM_KEYS.join(", ")
+        )?;
+        return Ok(Outcome::Handled);
+    }
+
+    let mut updated = session.params.clone();
+    match updated
+        .set(key, value)
+        .and_then(|_| updated.validate(session.registry.get_current_model()))
+    {
+        Ok(()) => {
+            session.params = updated;
+            writeln!(out, "Set {} for this session.", key)?;
+        }
+        Err(e) => writeln!(out, "Error: {}", e)?,
+    }
+    Ok(Outcome::Handled)
+}
+
+fn params(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    match args {
+        "" => {
+            writeln!(out, "Sampling parameters:")?;
+            write!(out, "{}", session.params)?;
+        }
+        "save" => {
+            session.config.set_sampling(session.params.clone())?;
+            writeln!(out, "Saved sampling parameters as defaults.")?;
+        }
+        "reset" => {
+            session.params = session.config.sampling.clone();
+            writeln!(out, "Sampling parameters reset to saved defaults.")?;
+        }
+        other => writeln!(
+            out,
+            "Unknown option '{}'. Usage: /params [save|reset]",
+            other
+        )?,
+    }
+    Ok(Outcome::Handled)
+}
+
+fn continue_response(
+    _registry: &CommandRegistry,
+    session: &mut Session,
+    _args: &str,
+    out: &mut dyn Write,
+) -> CommandResult {
+    match session.truncated.take() {
+        Some((prompt, partial)) => Ok(Outcome::Send { prompt, partial }),
+        None => {
+            writeln!(
+                out,
+                "Nothing to continue: the last response was not cut off."
+            )?;
+            Ok(Outcome::Handled)
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use crate::commands::{ArgValues, CommandRegistry};
+use crate::model_registry::ModelRegistry;
+use ignore::WalkBuilder;
+use std::path::Path;
+
+// Paths listed for one `@` completion
+const MAX_PATHS: usize = 200;
+
+pub struct Candidate {
+    // Text that replaces the word being completed
+    pub replacement: String,
+    // Shown in the menu
+    pub display: String,
+    pub hint: String,
+}
+
+impl Candidate {
+    // Directories keep going, anything else ends the word
+    pub fn is_final(&self) -> bool {
+        !self.replacement.ends_with('/')
+    }
+}
+
+pub struct Completion {
+    // Char offset in the line where the replaced word starts
+    pub start: usize,
+    pub candidates: Vec<Candidate>,
+}
+
+impl Completion {
+    // Longest prefix shared by every candidate
+    pub fn common_prefix(&self) -> String {
+        let mut candidates = self.candidates.iter().map(|c| c.replacement.as_str());
+        let Some(first) = candidates.next() else {
+            return String::new();
+        };
+        let mut len = first.len();
+        for other in candidates {
+            len = first
+                .char_indices()
+                .zip(other.chars())
+                .take_while(|((i, a), b)| *i < len && a == b)
+                .map(|((i, a), _)| i + a.len_utf8())
+                .last()
+                .unwrap_or(0);
+        }
+        first[..len].to_string()
+    }
+}
+
+struct CommandEntry {
+    name: String,
+    usage: String,
+    help: String,
+    values: ArgValues,
+}
+
+// Tab completion for the REPL: slash commands and their arguments, model
+// ids and aliases, and `@` paths that aren't ignored by .gitignore
+pub struct Completer {
+    commands: Vec<CommandEntry>,
+    // (id or alias, display name)
+    models: Vec<(String, String)>,
+    themes: Vec<String>,
+}
+
+impl Completer {
+    pub fn new(commands: &CommandRegistry, models: &ModelRegistry, themes: Vec<String>) -> Self {
+        Completer {
+            commands: commands
+                .commands()
+                .iter()
+                .map(|c| CommandEntry {
+                    name: c.name.clone(),
+                    usage: c.usage(),
+                    help: c.help.clone(),
+                    values: c.values,
+                })
+                .collect(),
+            models: models
+                .list_models()
+                .into_iter()
+                .flat_map(|m| {
+                    std::iter::once(m.id.clone())
+                        .chain(m.aliases.iter().cloned())
+                        .map(|name| (name, m.display_name.clone()))
+                })
+                .collect(),
+            themes,
+        }
+    }
+
+    // Complete the word ending at the cursor; `line` is the text before it
+    pub fn complete(&self, line: &str) -> Option<Completion> {
+        let word_start = line
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let word = &line[word_start..];
+        let start = line[..word_start].chars().count();
+
+        if let Some(partial) = word.strip_prefix('@') {
+            return Some(Completion {
+                start: start + 1,
+                candidates: complete_path(partial),
+            });
+        }
+
+        let command_line = line.strip_prefix('/')?;
+        if command_line.contains('\n') {
+            return None;
+        }
+        if word_start == 0 {
+            let candidates = self
+                .commands
+                .iter()
+                .filter(|c| c.name.starts_with(command_line))
+                .map(|c| Candidate {
+                    replacement: format!("/{}", c.name),
+                    display: c.usage.clone(),
+                    hint: c.help.clone(),
+                })
+                .collect();
+            return Some(Completion { start, candidates });
+        }
+
+        // Only the first argument has known values
+        let mut words = command_line[..word_start - 1].split_whitespace();
+        let name = words.next()?;
+        if words.next().is_some() {
+            return None;
+        }
+        let command = self.commands.iter().find(|c| c.name == name)?;
+        let candidates = match command.values {
+            ArgValues::None => return None,
+            ArgValues::Models => self
+                .models
+                .iter()
+                .filter(|(name, _)| name.starts_with(word))
+                .map(|(name, display_name)| Candidate {
+                    replacement: name.clone(),
+                    display: name.clone(),
+                    hint: display_name.clone(),
+                })
+                .collect(),
+            ArgValues::Themes => self
+                .themes
+                .iter()
+                .filter(|name| name.starts_with(word))
+                .map(|name| Candidate {
+                    replacement: name.clone(),
+                    display: name.clone(),
+                    hint: String::new(),
+                })
+                .collect(),
+            ArgValues::Words(values) => values
+                .iter()
+                .filter(|v| v.starts_with(word))
+                .map(|v| Candidate {
+                    replacement: v.to_string(),
+                    display: v.to_string(),
+                    hint: String::new(),
+                })
+                .collect(),
+        };
+        Some(Completion { start, candidates })
+    }
+}
+
+// Entries of the directory named so far whose names start with the rest.
+// Hidden files only show up once the name starts with a dot.
+fn complete_path(partial: &str) -> Vec<Candidate> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(i) => partial.split_at(i + 1),
+        None => ("", partial),
+    };
+    let base = if dir.is_empty() { "." } else { dir };
+    if !Path::new(base).is_dir() {
+        return Vec::new();
+    }
+
+    let mut candidates: Vec<Candidate> = WalkBuilder::new(base)
+        .max_depth(Some(1))
+        .hidden(!prefix.starts_with('.'))
+        .require_git(false)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.depth() == 1)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            if !name.starts_with(prefix) {
+                return None;
+            }
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            let name = if is_dir { format!("{}/", name) } else { name };
+            Some(Candidate {
+                replacement: format!("{}{}", dir, name),
+                display: name,
+                hint: String::new(),
+            })
+        })
+        .collect();
+    // The first ones in order, not the first ones the walk came across
+    candidates.sort_by(|a, b| a.replacement.cmp(&b.replacement));
+    candidates.truncate(MAX_PATHS);
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn paths_are_the_first_ones_in_order() {
+        let dir = std::env::temp_dir().join(format!("agent-complete-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for i in (0..MAX_PATHS + 50).rev() {
+            fs::write(dir.join(format!("f{:03}", i)), "").unwrap();
+        }
+        fs::write(dir.join("other"), "").unwrap();
+
+        let candidates = complete_path(&format!("{}/f", dir.display()));
+        let names: Vec<&str> = candidates.iter().map(|c| c.display.as_str()).collect();
+        let expected: Vec<String> = (0..MAX_PATHS).map(|i| format!("f{:03}", i)).collect();
+        assert_eq!(names, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,14 +1,26 @@
+use crate::budget::BudgetLimits;
+use crate::sampling::SamplingParams;
+use serde::de::DeserializeOwned;
 use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 
+pub const DEFAULT_PROFILE: &str = "default";
+
 pub struct Config {
     pub api_key: Option<String>,
     pub config_path: PathBuf,
     pub tui_enabled: bool,
     pub current_model: Option<String>,
+    // Name of the theme picked with /theme
+    pub theme: Option<String>,
     pub token_tracking: bool,
+    pub profile: String,
+    pub budget: BudgetLimits,
+    pub sampling: SamplingParams,
+    // How many times to continue a response cut off at max_tokens (0 = never)
+    pub max_continuations: u32,
 }
 
 impl Config {
@@ -32,6 +44,11 @@ impl Config {
                 .and_then(|v| v.as_str())
                 .map(String::from);
 
+            let theme = config
+                .get("theme")
+                .and_then(|v| v.as_str())
+                .map(String::from);
+
             let tui_enabled = config
                 .get("tui_enabled")
                 .and_then(|v| v.as_bool())
@@ -42,12 +59,31 @@ impl Config {
                 .and_then(|v| v.as_bool())
                 .unwrap_or(true);
 
+            let profile = config
+                .get("profile")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+
+            let budget = section(&config, "budget", &config_path)?;
+            let sampling = section(&config, "sampling", &config_path)?;
+
+            let max_continuations = config
+                .get("max_continuations")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0) as u32;
+
             Ok(Config {
                 api_key,
                 config_path,
                 tui_enabled,
                 current_model,
+                theme,
                 token_tracking,
+                profile,
+                budget,
+                sampling,
+                max_continuations,
             })
         } else {
             Ok(Config {
@@ -55,7 +91,12 @@ impl Config {
                 config_path,
                 tui_enabled: false,
                 current_model: None,
+                theme: None,
                 token_tracking: true,
+                profile: DEFAULT_PROFILE.to_string(),
+                budget: BudgetLimits::default(),
+                sampling: SamplingParams::default(),
+                max_continuations: 0,
             })
         }
     }
@@ -77,6 +118,13 @@ impl Config {
             );
         }
 
+        if let Some(theme) = &self.theme {
+            config.insert(
+                "theme".to_string(),
+                serde_json::Value::String(theme.clone()),
+            );
+        }
+
         config.insert(
             "tui_enabled".to_string(),
             serde_json::Value::Bool(self.tui_enabled),
@@ -85,6 +133,23 @@ impl Config {
             "token_tracking".to_string(),
             serde_json::Value::Bool(self.token_tracking),
         );
+        config.insert(
+            "profile".to_string(),
+            serde_json::Value::String(self.profile.clone()),
+        );
+        if !self.budget.is_empty() {
+            config.insert("budget".to_string(), serde_json::to_value(&self.budget)?);
+        }
+        config.insert(
+            "max_continuations".to_string(),
+            serde_json::Value::from(self.max_continuations),
+        );
+        if !self.sampling.is_empty() {
+            config.insert(
+                "sampling".to_string(),
+                serde_json::to_value(&self.sampling)?,
+            );
+        }
 
         // Create directory if it doesn't exist
         if let Some(parent) = self.config_path.parent() {
@@ -105,16 +170,77 @@ impl Config {
         self.save()
     }
 
+    pub fn set_theme(&mut self, theme: String) -> io::Result<()> {
+        self.theme = Some(theme);
+        self.save()
+    }
+
     pub fn set_tui_enabled(&mut self, enabled: bool) -> io::Result<()> {
         self.tui_enabled = enabled;
         self.save()
     }
 
+    // The ledger is kept while tracking is on, and also while a limit
+    // depends on it, so turning tracking off can't lift a spending limit
+    pub fn records_usage(&self) -> bool {
+        self.token_tracking || self.budget.uses_ledger()
+    }
+
     pub fn set_token_tracking(&mut self, enabled: bool) -> io::Result<()> {
         self.token_tracking = enabled;
         self.save()
     }
 
+    pub fn set_budget(&mut self, budget: BudgetLimits) -> io::Result<()> {
+        self.budget = budget;
+        self.save()
+    }
+
+    pub fn set_sampling(&mut self, sampling: SamplingParams) -> io::Result<()> {
+        self.sampling = sampling;
+        self.save()
+    }
+
+    pub fn get_usage_ledger_path(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .join("usage.jsonl")
+    }
+
+    // User overrides for the built-in pricing table
+    pub fn get_pricing_path(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .join("pricing.json")
+    }
+
+    // User model registries, merged over the built-in models in this order
+    pub fn get_model_registry_files(&self) -> Vec<PathBuf> {
+        let dir = self
+            .config_path
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .to_path_buf();
+        vec![dir.join("models.json"), dir.join("models.toml")]
+    }
+
+    pub fn get_models_cache_path(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .join("models_cache.json")
+    }
+
+    // Message batches submitted from this machine
+    pub fn get_batches_path(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .join("batches.json")
+    }
+
     pub fn get_conversations_dir(&self) -> PathBuf {
         self.config_path
             .parent()
@@ -129,4 +255,79 @@ impl Config {
         }
         Ok(())
     }
+
+    // Prompt templates for `agent run`, shared by every project
+    pub fn get_prompts_dir(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .join("prompts")
+    }
+
+    // Where to look for a template: the project's `.agent/prompts` first
+    pub fn get_prompt_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        if let Some(project) = find_project_dir() {
+            dirs.push(project.join("prompts"));
+        }
+        dirs.push(self.get_prompts_dir());
+        dirs
+    }
+
+    // User themes, one TOML file each
+    pub fn get_themes_dir(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .join("themes")
+    }
+
+    // Lines entered at the interactive prompt
+    pub fn get_history_path(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .join("history")
+    }
+
+    // Markdown slash commands: the project's `.agent/commands`, then ours
+    pub fn get_command_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        if let Some(project) = find_project_dir() {
+            dirs.push(project.join("commands"));
+        }
+        dirs.push(
+            self.config_path
+                .parent()
+                .unwrap_or(&PathBuf::from("."))
+                .join("commands"),
+        );
+        dirs
+    }
+}
+
+// A section of config.json that is there but doesn't parse is an error, so
+// that a typo can't quietly turn a spending limit off
+fn section<T: DeserializeOwned + Default>(
+    config: &HashMap<String, serde_json::Value>,
+    key: &str,
+    path: &Path,
+) -> io::Result<T> {
+    let Some(value) = config.get(key) else {
+        return Ok(T::default());
+    };
+    serde_json::from_value(value.clone()).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid \"{}\" in {}: {}", key, path.display(), e),
+        )
+    })
+}
+
+// The nearest `.agent` directory from the working directory upwards
+pub fn find_project_dir() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(".agent"))
+        .find(|dir| dir.is_dir())
 }
@@ -0,0 +1,514 @@
+use crate::api::trim_prefill;
+use crate::modules::ClaudeMessage;
+use crate::search::SearchIndex;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Characters of the first prompt kept as a conversation's title
+const TITLE_CHARS: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Turn {
+    pub role: Role,
+    pub text: String,
+    pub timestamp: DateTime<Utc>,
+    // Model the prompt went to, or that wrote the answer
+    pub model: String,
+    // Index of the turn this one follows; None for the first prompt
+    pub parent: Option<usize>,
+    // What an answer cost; missing for prompts and older answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TurnUsage>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TurnUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub cost: f64,
+}
+
+impl TurnUsage {
+    pub fn add(&mut self, other: &TurnUsage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cost += other.cost;
+    }
+}
+
+// One chat session, saved as `<id>.json` in the conversations directory.
+// Retried answers and edited prompts branch off where they were asked, so
+// the turns form a tree; `current` is the last turn of the branch in use.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: String,
+    pub title: String,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    // Every turn of every branch, in the order they were added
+    pub turns: Vec<Turn>,
+    pub current: Option<usize>,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        let now = Utc::now();
+        Conversation {
+            id: uuid::Uuid::new_v4().simple().to_string()[..8].to_string(),
+            title: String::new(),
+            created: now,
+            updated: now,
+            turns: Vec::new(),
+            current: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    // Indexes of the turns on the branch in use, first to last
+    fn path_to(&self, last: Option<usize>) -> Vec<usize> {
+        let mut path: Vec<usize> = std::iter::successors(last, |&i| self.turns[i].parent).collect();
+        path.reverse();
+        path
+    }
+
+    fn path(&self) -> Vec<usize> {
+        self.path_to(self.current)
+    }
+
+    // The turns of the branch in use
+    pub fn active(&self) -> Vec<&Turn> {
+        self.path().into_iter().map(|i| &self.turns[i]).collect()
+    }
+
+    pub fn last_answer(&self) -> Option<&str> {
+        self.active()
+            .into_iter()
+            .rfind(|turn| turn.role == Role::Assistant)
+            .map(|turn| turn.text.as_str())
+    }
+
+    // Turns to send ahead of `prompt`. When continuing a cut-off answer,
+    // that exchange is left out: its answer goes in as the prefill.
+    pub fn history(&self, prompt: &str, continuing: bool) -> Vec<ClaudeMessage> {
+        let mut turns = self.active();
+        if continuing && self.ends_with_prompt(prompt) {
+            turns.truncate(turns.len() - 2);
+        }
+        turns
+            .iter()
+            .map(|turn| {
+                let text = match turn.role {
+                    Role::User => &turn.text,
+                    Role::Assistant => trim_prefill(&turn.text),
+                };
+                ClaudeMessage::text(turn.role.as_str(), text)
+            })
+            .collect()
+    }
+
+    // Add a prompt and its answer to the branch in use. A continued answer
+    // replaces the part of it recorded before, and asking a prompt that was
+    // asked at this point already adds another answer to it.
+    pub fn add_exchange(
+        &mut self,
+        prompt: &str,
+        answer: &str,
+        model: &str,
+        usage: Option<TurnUsage>,
+        continued: bool,
+    ) {
+        if continued && self.ends_with_prompt(prompt) {
+            if let Some(current) = self.current {
+                let turn = &mut self.turns[current];
+                turn.text = answer.to_string();
+                if let Some(usage) = usage {
+                    turn.usage.get_or_insert_default().add(&usage);
+                }
+                self.updated = Utc::now();
+                return;
+            }
+        }
+
+        let asked = self.turns.iter().position(|turn| {
+            turn.parent == self.current && turn.role == Role::User && turn.text == prompt
+        });
+        let user = match asked {
+            Some(user) => user,
+            None => self.push(Role::User, prompt, model, self.current),
+        };
+        let answer = self.push(Role::Assistant, answer, model, Some(user));
+        self.turns[answer].usage = usage;
+        self.current = Some(answer);
+    }
+
+    fn push(&mut self, role: Role, text: &str, model: &str, parent: Option<usize>) -> usize {
+        let now = Utc::now();
+        if self.title.is_empty() && role == Role::User {
+            self.title = summary(text);
+        }
+        self.turns.push(Turn {
+            role,
+            text: text.to_string(),
+            timestamp: now,
+            model: model.to_string(),
+            parent,
+            usage: None,
+        });
+        self.updated = now;
+        self.turns.len() - 1
+    }
+
+    fn ends_with_prompt(&self, prompt: &str) -> bool {
+        match self.active().as_slice() {
+            [.., user, answer] => {
+                user.role == Role::User && user.text == prompt && answer.role == Role::Assistant
+            }
+            _ => false,
+        }
+    }
+
+    // Go back to just before the last prompt and return it, so it can be
+    // asked again; the exchange stays behind as a branch
+    pub fn rewind(&mut self) -> Option<String> {
+        let user = self
+            .path()
+            .into_iter()
+            .rfind(|&i| self.turns[i].role == Role::User)?;
+        self.current = self.turns[user].parent;
+        self.updated = Utc::now();
+        Some(self.turns[user].text.clone())
+    }
+
+    // Last turns of every branch, oldest first
+    pub fn branches(&self) -> Vec<usize> {
+        (0..self.turns.len())
+            .filter(|&i| !self.turns.iter().any(|turn| turn.parent == Some(i)))
+            .collect()
+    }
+
+    // The turns of the branch ending at `last`
+    pub fn branch(&self, last: usize) -> Vec<&Turn> {
+        self.path_to(Some(last))
+            .into_iter()
+            .map(|i| &self.turns[i])
+            .collect()
+    }
+
+    pub fn switch_branch(&mut self, last: usize) {
+        self.current = Some(last);
+        self.updated = Utc::now();
+    }
+
+    // Every turn follows one added before it, so branches can't loop or
+    // point past the end. Files may have been edited by hand.
+    fn check_links(&self) -> Result<(), String> {
+        for (i, turn) in self.turns.iter().enumerate() {
+            if let Some(parent) = turn.parent.filter(|&parent| parent >= i) {
+                return Err(format!(
+                    "turn {} follows turn {}, which isn't before it",
+                    i, parent
+                ));
+            }
+        }
+        match self.current {
+            Some(current) if current >= self.turns.len() => {
+                Err(format!("the current turn {} is past the last one", current))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// First line of a prompt, shortened, e.g. for the title
+pub fn summary(text: &str) -> String {
+    let line = text.trim().lines().next().unwrap_or_default();
+    if line.chars().count() <= TITLE_CHARS {
+        return line.to_string();
+    }
+    let short: String = line.chars().take(TITLE_CHARS - 1).collect();
+    format!("{}…", short.trim_end())
+}
+
+// Saved conversations and the search index kept next to them
+pub struct ConversationStore {
+    dir: PathBuf,
+}
+
+impl ConversationStore {
+    pub fn new(dir: PathBuf) -> Self {
+        ConversationStore { dir }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    // Ids are lowercase hex, so one typed by the user can't name a file
+    // outside the store
+    fn valid_id(id: &str) -> bool {
+        !id.is_empty() && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    }
+
+    // Where the search index keeps a segment per conversation
+    pub fn index_dir(&self) -> PathBuf {
+        self.dir.join("index")
+    }
+
+    // Ids of every saved conversation
+    pub fn ids(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut ids: Vec<String> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+            .filter(|id| Self::valid_id(id))
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    // Load by id, or by a prefix that only one id starts with
+    pub fn load(&self, id: &str) -> io::Result<Conversation> {
+        if !Self::valid_id(id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid conversation id {}", id),
+            ));
+        }
+        let path = self.path(id);
+        let path = if path.is_file() {
+            path
+        } else {
+            let matches: Vec<String> = self
+                .ids()?
+                .into_iter()
+                .filter(|other| other.starts_with(id))
+                .collect();
+            match matches.as_slice() {
+                [only] => self.path(only),
+                [] => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No conversation with id {}", id),
+                    ))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Several conversations start with {}", id),
+                    ))
+                }
+            }
+        };
+
+        let contents = fs::read_to_string(&path)?;
+        let invalid = |e: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid conversation {}: {}", path.display(), e),
+            )
+        };
+        let conversation: Conversation =
+            serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+        conversation.check_links().map_err(invalid)?;
+        Ok(conversation)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        Self::valid_id(id) && self.path(id).is_file()
+    }
+
+    // Write the conversation and add its new turns to the search index
+    pub fn save(&self, conversation: &Conversation) -> io::Result<()> {
+        self.save_all(std::slice::from_ref(conversation))
+    }
+
+    pub fn save_all(&self, conversations: &[Conversation]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        for conversation in conversations {
+            fs::write(
+                self.path(&conversation.id),
+                serde_json::to_string_pretty(conversation)?,
+            )?;
+            SearchIndex::save_conversation(self, conversation)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const MODEL: &str = "claude-test";
+
+    fn texts(turns: &[&Turn]) -> Vec<String> {
+        turns.iter().map(|turn| turn.text.clone()).collect()
+    }
+
+    fn usage(input_tokens: u32, output_tokens: u32) -> Option<TurnUsage> {
+        Some(TurnUsage {
+            input_tokens,
+            output_tokens,
+            cost: 0.5,
+        })
+    }
+
+    #[test]
+    fn a_retried_prompt_gets_sibling_answers() {
+        let mut conversation = Conversation::new();
+        conversation.add_exchange("question", "first answer", MODEL, None, false);
+        assert_eq!(conversation.rewind().as_deref(), Some("question"));
+        assert_eq!(conversation.current, None);
+        conversation.add_exchange("question", "second answer", MODEL, None, false);
+
+        // The prompt is shared, the answers are both kept
+        assert_eq!(conversation.turns.len(), 3);
+        assert_eq!(conversation.turns[1].parent, Some(0));
+        assert_eq!(conversation.turns[2].parent, Some(0));
+        assert_eq!(conversation.branches(), vec![1, 2]);
+        assert_eq!(texts(&conversation.active()), ["question", "second answer"]);
+
+        conversation.switch_branch(1);
+        assert_eq!(conversation.last_answer(), Some("first answer"));
+        assert_eq!(
+            texts(&conversation.branch(2)),
+            ["question", "second answer"]
+        );
+    }
+
+    #[test]
+    fn an_edited_prompt_branches_off_where_it_was_asked() {
+        let mut conversation = Conversation::new();
+        conversation.add_exchange("one", "1", MODEL, None, false);
+        conversation.add_exchange("two", "2", MODEL, None, false);
+        assert_eq!(conversation.rewind().as_deref(), Some("two"));
+        assert_eq!(texts(&conversation.active()), ["one", "1"]);
+        conversation.add_exchange("two, edited", "2b", MODEL, None, false);
+
+        assert_eq!(conversation.branches(), vec![3, 5]);
+        assert_eq!(
+            texts(&conversation.active()),
+            ["one", "1", "two, edited", "2b"]
+        );
+        assert_eq!(texts(&conversation.branch(3)), ["one", "1", "two", "2"]);
+        assert_eq!(conversation.title, "one");
+    }
+
+    #[test]
+    fn undo_goes_back_one_exchange_at_a_time() {
+        let mut conversation = Conversation::new();
+        assert_eq!(conversation.rewind(), None);
+        conversation.add_exchange("one", "1", MODEL, None, false);
+        conversation.add_exchange("two", "2", MODEL, None, false);
+
+        assert_eq!(conversation.rewind().as_deref(), Some("two"));
+        assert_eq!(conversation.history("three", false).len(), 2);
+        assert_eq!(conversation.rewind().as_deref(), Some("one"));
+        assert!(conversation.active().is_empty());
+        assert_eq!(conversation.rewind(), None);
+        // Nothing is lost; the old exchanges are a branch
+        assert_eq!(conversation.turns.len(), 4);
+        assert_eq!(conversation.branches(), vec![3]);
+    }
+
+    #[test]
+    fn a_continuation_replaces_the_answer_it_continues() {
+        let mut conversation = Conversation::new();
+        conversation.add_exchange("question", "cut off ", MODEL, usage(10, 20), false);
+
+        // Continuing sends the cut-off answer as the prefill, not as history
+        assert!(conversation.history("question", true).is_empty());
+        assert_eq!(conversation.history("next", false).len(), 2);
+
+        conversation.add_exchange(
+            "question",
+            "cut off and finished",
+            MODEL,
+            usage(30, 5),
+            true,
+        );
+        assert_eq!(conversation.turns.len(), 2);
+        assert_eq!(conversation.last_answer(), Some("cut off and finished"));
+        let total = conversation.turns[1].usage.unwrap();
+        assert_eq!((total.input_tokens, total.output_tokens), (40, 25));
+        assert_eq!(total.cost, 1.0);
+
+        // A continuation of another prompt is a new exchange
+        conversation.add_exchange("other", "answer", MODEL, None, true);
+        assert_eq!(conversation.turns.len(), 4);
+    }
+
+    #[test]
+    fn the_store_only_opens_its_own_files() {
+        let dir = std::env::temp_dir().join(format!("agent-store-{}", std::process::id()));
+        let store = ConversationStore::new(dir.join("conversations"));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("x.json"), "{}").unwrap();
+
+        for id in ["../x", "..", "", "ABC", "a/b"] {
+            let e = store.load(id).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", id);
+            assert!(!store.contains(id));
+        }
+
+        let mut conversation = Conversation::new();
+        conversation.add_exchange("one", "1", MODEL, None, false);
+        store.save(&conversation).unwrap();
+        assert_eq!(store.load(&conversation.id[..4]).unwrap().turns.len(), 2);
+
+        // Hand-edited links that loop or point past the end
+        for (turns, current) in [
+            (json!([1, null]), 1),
+            (json!([null, 1]), 1),
+            (json!([null, 0]), 2),
+        ] {
+            let mut value = serde_json::to_value(&conversation).unwrap();
+            for (turn, parent) in value["turns"]
+                .as_array_mut()
+                .unwrap()
+                .iter_mut()
+                .zip(turns.as_array().unwrap())
+            {
+                turn["parent"] = parent.clone();
+            }
+            value["current"] = json!(current);
+            fs::write(
+                store.path(&conversation.id),
+                serde_json::to_string(&value).unwrap(),
+            )
+            .unwrap();
+            let e = store.load(&conversation.id).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", value);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
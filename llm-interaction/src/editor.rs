@@ -0,0 +1,926 @@
+use crate::completion::{Completer, Completion};
+use crossterm::{
+    cursor,
+    event::{
+        self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyEventKind,
+        KeyModifiers, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+        PushKeyboardEnhancementFlags,
+    },
+    execute, queue,
+    style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::fs::{self, OpenOptions};
+use std::io::{self, stdout, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::Command;
+use unicode_width::UnicodeWidthChar;
+
+// Entries kept in the history file
+const MAX_HISTORY: usize = 1000;
+
+// Completions shown at once below the input
+const MENU_ROWS: usize = 8;
+
+pub enum ReadLine {
+    Line(String),
+    // Ctrl-C
+    Interrupted,
+    // Ctrl-D on an empty line
+    Eof,
+}
+
+// Submitted lines, persisted one per line with newlines escaped
+pub struct History {
+    path: Option<PathBuf>,
+    entries: Vec<String>,
+    // Lines in the file, which may hold more than the entries kept
+    lines: usize,
+}
+
+impl History {
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let mut entries: Vec<String> = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter(|l| !l.is_empty())
+                    .map(unescape)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let lines = entries.len();
+        if entries.len() > MAX_HISTORY {
+            entries.drain(..entries.len() - MAX_HISTORY);
+        }
+        History {
+            path,
+            entries,
+            lines,
+        }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    pub fn add(&mut self, line: &str) -> io::Result<()> {
+        if line.trim().is_empty() || self.entries.last().map(String::as_str) == Some(line) {
+            return Ok(());
+        }
+        self.entries.push(line.to_string());
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.drain(..self.entries.len() - MAX_HISTORY);
+        }
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Lines are appended until the file holds twice the entries kept,
+        // then it's rewritten with just those
+        if self.lines >= 2 * MAX_HISTORY {
+            let contents: String = self
+                .entries
+                .iter()
+                .map(|e| format!("{}\n", escape(e)))
+                .collect();
+            self.lines = self.entries.len();
+            return fs::write(path, contents);
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.lines += 1;
+        writeln!(file, "{}", escape(line))
+    }
+}
+
+// Let the user change `text` in $VISUAL or $EDITOR (vi when neither is
+// set). The full-screen chat steps aside while the editor runs.
+pub fn edit_in_editor(text: &str, full_screen: bool) -> io::Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+
+    // A new file only this user can read, under a name that can't be guessed
+    // ahead of time and taken over by someone else sharing the temp dir
+    let path =
+        std::env::temp_dir().join(format!("agent-edit-{}.md", uuid::Uuid::new_v4().simple()));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(&path)?.write_all(text.as_bytes())?;
+
+    if full_screen {
+        execute!(stdout(), LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+    }
+    let status = Command::new(program).args(words).arg(&path).status();
+    if full_screen {
+        terminal::enable_raw_mode()?;
+        execute!(stdout(), EnterAlternateScreen)?;
+    }
+
+    let edited = match status {
+        Ok(status) if status.success() => fs::read_to_string(&path),
+        Ok(status) => Err(io::Error::other(format!(
+            "{} exited with {}",
+            program, status
+        ))),
+        Err(e) => Err(io::Error::other(format!(
+            "could not run {}: {}",
+            program, e
+        ))),
+    };
+    let _ = fs::remove_file(&path);
+    edited
+}
+
+fn escape(line: &str) -> String {
+    line.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Columns a character takes up; control characters count as none
+pub fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+fn str_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+// Cut `s` down to at most `max` columns
+fn truncate_to_width(s: &str, max: usize) -> String {
+    let mut width = 0;
+    s.chars()
+        .take_while(|&c| {
+            width += char_width(c);
+            width <= max
+        })
+        .collect()
+}
+
+// Ctrl-R state: the query and the history entry it currently matches
+struct Search {
+    query: String,
+    index: Option<usize>,
+}
+
+// An open completion menu; the buffer shows the selected candidate
+struct Menu {
+    completion: Completion,
+    selected: usize,
+    // The buffer before the menu opened, restored by Esc
+    original: Vec<char>,
+    original_cursor: usize,
+}
+
+impl Menu {
+    fn select(&mut self, selected: usize, buffer: &mut Buffer) {
+        self.selected = selected;
+        buffer.chars = self.original.clone();
+        buffer.cursor = self.original_cursor;
+        buffer.replace(
+            self.completion.start,
+            &self.completion.candidates[selected].replacement,
+        );
+    }
+}
+
+// The text being edited, as chars so the cursor is always on a boundary
+#[derive(Default)]
+pub struct Buffer {
+    pub chars: Vec<char>,
+    pub cursor: usize,
+}
+
+impl Buffer {
+    pub fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        for c in text.chars() {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    pub fn line_start(&self) -> usize {
+        self.chars[..self.cursor]
+            .iter()
+            .rposition(|&c| c == '\n')
+            .map_or(0, |i| i + 1)
+    }
+
+    pub fn line_end(&self) -> usize {
+        self.chars[self.cursor..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(self.chars.len(), |i| self.cursor + i)
+    }
+
+    pub fn word_left(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && !self.chars[i - 1].is_alphanumeric() {
+            i -= 1;
+        }
+        while i > 0 && self.chars[i - 1].is_alphanumeric() {
+            i -= 1;
+        }
+        i
+    }
+
+    pub fn word_right(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.chars.len() && !self.chars[i].is_alphanumeric() {
+            i += 1;
+        }
+        while i < self.chars.len() && self.chars[i].is_alphanumeric() {
+            i += 1;
+        }
+        i
+    }
+
+    // Replace `start..cursor`, leaving the cursor after the new text
+    pub fn replace(&mut self, start: usize, text: &str) {
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+        self.insert(text);
+    }
+
+    // Remove `start..end` and return it
+    pub fn kill(&mut self, start: usize, end: usize) -> String {
+        let killed: String = self.chars.drain(start..end).collect();
+        self.cursor = start;
+        killed
+    }
+
+    pub fn on_first_line(&self) -> bool {
+        !self.chars[..self.cursor].contains(&'\n')
+    }
+
+    pub fn on_last_line(&self) -> bool {
+        !self.chars[self.cursor..].contains(&'\n')
+    }
+
+    // Move to the same column on the previous or next line
+    pub fn move_vertical(&mut self, up: bool) {
+        let column = self.cursor - self.line_start();
+        let target_start = if up {
+            let prev_end = self.line_start() - 1;
+            self.chars[..prev_end]
+                .iter()
+                .rposition(|&c| c == '\n')
+                .map_or(0, |i| i + 1)
+        } else {
+            self.line_end() + 1
+        };
+        let target_end = self.chars[target_start..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(self.chars.len(), |i| target_start + i);
+        self.cursor = (target_start + column).min(target_end);
+    }
+}
+
+// An emacs-style line editor: history, Ctrl-R search, multi-line input with
+// Shift/Alt-Enter (or a trailing `\`), and bracketed paste
+pub struct LineEditor {
+    history: History,
+    kill_ring: String,
+    // Whether the terminal reports Shift-Enter, asked once
+    enhanced_keys: Option<bool>,
+    completer: Option<Completer>,
+}
+
+impl LineEditor {
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        LineEditor {
+            history: History::load(history_path),
+            kill_ring: String::new(),
+            enhanced_keys: None,
+            completer: None,
+        }
+    }
+
+    pub fn set_completer(&mut self, completer: Completer) {
+        self.completer = Some(completer);
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn read_line(&mut self, prompt: &str, prompt_color: Color) -> io::Result<ReadLine> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = stdout();
+        execute!(stdout, EnableBracketedPaste)?;
+        // Lets terminals that support it report Shift-Enter distinctly
+        let enhanced = *self
+            .enhanced_keys
+            .get_or_insert_with(|| terminal::supports_keyboard_enhancement().unwrap_or(false));
+        if enhanced {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
+            )?;
+        }
+
+        let result = self.edit(prompt, prompt_color);
+
+        if enhanced {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, DisableBracketedPaste);
+        terminal::disable_raw_mode()?;
+
+        if let Ok(ReadLine::Line(line)) = &result {
+            self.history.add(line)?;
+        }
+        result
+    }
+
+    fn edit(&mut self, prompt: &str, prompt_color: Color) -> io::Result<ReadLine> {
+        let mut buffer = Buffer::default();
+        let mut view = View::default();
+        // Position while browsing history, and the unsent text to come back to
+        let mut history_index: Option<usize> = None;
+        let mut draft = String::new();
+        let mut search: Option<Search> = None;
+        let mut menu: Option<Menu> = None;
+
+        loop {
+            let search_prompt = search.as_ref().map(|s| {
+                let failed = if s.index.is_none() && !s.query.is_empty() {
+                    "failing "
+                } else {
+                    ""
+                };
+                format!("({}reverse-i-search)`{}': ", failed, s.query)
+            });
+            view.render(
+                search_prompt.as_deref().unwrap_or(prompt),
+                prompt_color,
+                &buffer,
+                menu.as_ref(),
+            )?;
+
+            let key = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                Event::Paste(text) => {
+                    search = None;
+                    menu = None;
+                    buffer.insert(&text.replace("\r\n", "\n").replace('\r', "\n"));
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if let Some(s) = search.as_mut() {
+                if self.handle_search(s, &mut buffer, key) {
+                    continue;
+                }
+                search = None;
+                if matches!(key.code, KeyCode::Esc)
+                    || (key.code == KeyCode::Char('g')
+                        && key.modifiers.contains(KeyModifiers::CONTROL))
+                {
+                    buffer.set(&draft);
+                    continue;
+                }
+            }
+
+            // Any other key closes the menu and is handled with the selection kept
+            if let Some(m) = menu.as_mut() {
+                let count = m.completion.candidates.len();
+                match key.code {
+                    KeyCode::Tab | KeyCode::Down => {
+                        m.select((m.selected + 1) % count, &mut buffer);
+                        continue;
+                    }
+                    KeyCode::BackTab | KeyCode::Up => {
+                        m.select((m.selected + count - 1) % count, &mut buffer);
+                        continue;
+                    }
+                    // Accept without sending
+                    KeyCode::Enter => {
+                        menu = None;
+                        continue;
+                    }
+                    KeyCode::Esc => {
+                        buffer.chars = m.original.clone();
+                        buffer.cursor = m.original_cursor;
+                        menu = None;
+                        continue;
+                    }
+                    _ => menu = None,
+                }
+            }
+
+            let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+            let alt = key.modifiers.contains(KeyModifiers::ALT);
+            let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+
+            match key.code {
+                KeyCode::Enter if shift || alt => buffer.insert("\n"),
+                KeyCode::Char('j') if ctrl => buffer.insert("\n"),
+                KeyCode::Enter => {
+                    // A trailing backslash continues on the next line
+                    if buffer.cursor == buffer.chars.len() && buffer.chars.last() == Some(&'\\') {
+                        buffer.chars.pop();
+                        buffer.cursor -= 1;
+                        buffer.insert("\n");
+                        continue;
+                    }
+                    view.finish(prompt, prompt_color, &buffer)?;
+                    return Ok(ReadLine::Line(buffer.text()));
+                }
+                KeyCode::Char('c') if ctrl => {
+                    view.finish(prompt, prompt_color, &buffer)?;
+                    return Ok(ReadLine::Interrupted);
+                }
+                KeyCode::Char('d') if ctrl => {
+                    if buffer.chars.is_empty() {
+                        view.finish(prompt, prompt_color, &buffer)?;
+                        return Ok(ReadLine::Eof);
+                    }
+                    if buffer.cursor < buffer.chars.len() {
+                        buffer.chars.remove(buffer.cursor);
+                    }
+                }
+                KeyCode::Char('r') if ctrl => {
+                    draft = buffer.text();
+                    search = Some(Search {
+                        query: String::new(),
+                        index: None,
+                    });
+                }
+                KeyCode::Char('l') if ctrl => {
+                    execute!(stdout(), Clear(ClearType::All), cursor::MoveTo(0, 0))?;
+                    view = View::default();
+                }
+
+                // Movement
+                KeyCode::Char('a') if ctrl => buffer.cursor = buffer.line_start(),
+                KeyCode::Home => buffer.cursor = buffer.line_start(),
+                KeyCode::Char('e') if ctrl => buffer.cursor = buffer.line_end(),
+                KeyCode::End => buffer.cursor = buffer.line_end(),
+                KeyCode::Char('b') if ctrl => buffer.cursor = buffer.cursor.saturating_sub(1),
+                KeyCode::Char('f') if ctrl => {
+                    buffer.cursor = (buffer.cursor + 1).min(buffer.chars.len())
+                }
+                KeyCode::Char('b') if alt => buffer.cursor = buffer.word_left(),
+                KeyCode::Char('f') if alt => buffer.cursor = buffer.word_right(),
+                KeyCode::Left if ctrl || alt => buffer.cursor = buffer.word_left(),
+                KeyCode::Right if ctrl || alt => buffer.cursor = buffer.word_right(),
+                KeyCode::Left => buffer.cursor = buffer.cursor.saturating_sub(1),
+                KeyCode::Right => buffer.cursor = (buffer.cursor + 1).min(buffer.chars.len()),
+
+                // Up and down move between lines, then through history
+                KeyCode::Up | KeyCode::Char('p')
+                    if (key.code == KeyCode::Up || ctrl) && !buffer.on_first_line() =>
+                {
+                    buffer.move_vertical(true)
+                }
+                KeyCode::Down | KeyCode::Char('n')
+                    if (key.code == KeyCode::Down || ctrl) && !buffer.on_last_line() =>
+                {
+                    buffer.move_vertical(false)
+                }
+                KeyCode::Up | KeyCode::Char('p') if key.code == KeyCode::Up || ctrl => {
+                    let entries = self.history.entries();
+                    let next = match history_index {
+                        None if !entries.is_empty() => {
+                            draft = buffer.text();
+                            Some(entries.len() - 1)
+                        }
+                        Some(i) if i > 0 => Some(i - 1),
+                        other => other,
+                    };
+                    if let Some(i) = next {
+                        history_index = Some(i);
+                        buffer.set(&entries[i]);
+                    }
+                }
+                KeyCode::Down | KeyCode::Char('n') if key.code == KeyCode::Down || ctrl => {
+                    if let Some(i) = history_index {
+                        if i + 1 < self.history.entries().len() {
+                            history_index = Some(i + 1);
+                            buffer.set(&self.history.entries()[i + 1]);
+                        } else {
+                            history_index = None;
+                            buffer.set(&draft);
+                        }
+                    }
+                }
+
+                // Deletion and the kill ring
+                KeyCode::Backspace if alt => {
+                    let start = buffer.word_left();
+                    self.kill_ring = buffer.kill(start, buffer.cursor);
+                }
+                KeyCode::Backspace | KeyCode::Char('h')
+                    if (key.code == KeyCode::Backspace || ctrl) && buffer.cursor > 0 =>
+                {
+                    buffer.cursor -= 1;
+                    buffer.chars.remove(buffer.cursor);
+                }
+                KeyCode::Delete if buffer.cursor < buffer.chars.len() => {
+                    buffer.chars.remove(buffer.cursor);
+                }
+                KeyCode::Char('k') if ctrl => {
+                    let end = buffer.line_end();
+                    // At the end of a line, kill the newline instead
+                    let end = if end == buffer.cursor && end < buffer.chars.len() {
+                        end + 1
+                    } else {
+                        end
+                    };
+                    self.kill_ring = buffer.kill(buffer.cursor, end);
+                }
+                KeyCode::Char('u') if ctrl => {
+                    let start = buffer.line_start();
+                    self.kill_ring = buffer.kill(start, buffer.cursor);
+                }
+                KeyCode::Char('w') if ctrl => {
+                    let start = buffer.word_left();
+                    self.kill_ring = buffer.kill(start, buffer.cursor);
+                }
+                KeyCode::Char('d') if alt => {
+                    let end = buffer.word_right();
+                    self.kill_ring = buffer.kill(buffer.cursor, end);
+                }
+                KeyCode::Char('y') if ctrl => {
+                    let text = self.kill_ring.clone();
+                    buffer.insert(&text);
+                }
+                KeyCode::Char('t') if ctrl && buffer.cursor > 0 && buffer.chars.len() > 1 => {
+                    if buffer.cursor == buffer.chars.len() {
+                        buffer.cursor -= 1;
+                    }
+                    buffer.chars.swap(buffer.cursor - 1, buffer.cursor);
+                    buffer.cursor += 1;
+                }
+
+                KeyCode::Tab => {
+                    let before: String = buffer.chars[..buffer.cursor].iter().collect();
+                    let Some(completion) =
+                        self.completer.as_ref().and_then(|c| c.complete(&before))
+                    else {
+                        buffer.insert("    ");
+                        continue;
+                    };
+                    let start = completion.start;
+                    match completion.candidates.as_slice() {
+                        [] => {}
+                        [only] => {
+                            let end = if only.is_final() { " " } else { "" };
+                            buffer.replace(start, &format!("{}{}", only.replacement, end));
+                        }
+                        _ => {
+                            // Extend to the shared prefix first, then offer the choices
+                            let prefix = completion.common_prefix();
+                            if prefix.chars().count() > buffer.cursor - start {
+                                buffer.replace(start, &prefix);
+                            } else {
+                                let mut m = Menu {
+                                    completion,
+                                    selected: 0,
+                                    original: buffer.chars.clone(),
+                                    original_cursor: buffer.cursor,
+                                };
+                                m.select(0, &mut buffer);
+                                menu = Some(m);
+                            }
+                        }
+                    }
+                }
+                KeyCode::Char(c) if !ctrl && !alt => buffer.insert(&c.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    // Keys handled while searching; returns false to leave search mode and
+    // handle the key normally with the match kept in the buffer
+    fn handle_search(&self, search: &mut Search, buffer: &mut Buffer, key: KeyEvent) -> bool {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let entries = self.history.entries();
+
+        let find = |query: &str, before: usize| -> Option<usize> {
+            entries[..before]
+                .iter()
+                .rposition(|entry| entry.contains(query))
+        };
+
+        match key.code {
+            KeyCode::Char('r') if ctrl => {
+                // Next older match
+                let before = search.index.unwrap_or(entries.len());
+                if let Some(i) = find(&search.query, before) {
+                    search.index = Some(i);
+                }
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                search.index = find(&search.query, entries.len());
+            }
+            KeyCode::Char(c) if !ctrl && !key.modifiers.contains(KeyModifiers::ALT) => {
+                search.query.push(c);
+                search.index = find(&search.query, search.index.map_or(entries.len(), |i| i + 1));
+            }
+            _ => return false,
+        }
+
+        match search.index {
+            Some(i) => {
+                buffer.set(&entries[i]);
+                if let Some(at) = entries[i].find(&search.query) {
+                    buffer.cursor = entries[i][..at].chars().count();
+                }
+            }
+            None if search.query.is_empty() => buffer.set(""),
+            None => {}
+        }
+        true
+    }
+}
+
+// Tracks where the input was drawn so it can be redrawn in place
+#[derive(Default)]
+struct View {
+    // Rows between the first input row and the cursor
+    cursor_row: u16,
+}
+
+impl View {
+    fn render(
+        &mut self,
+        prompt: &str,
+        prompt_color: Color,
+        buffer: &Buffer,
+        menu: Option<&Menu>,
+    ) -> io::Result<()> {
+        let mut stdout = stdout();
+        // Some ptys report a size of zero
+        let cols = terminal::size()
+            .map(|(w, _)| w as usize)
+            .ok()
+            .filter(|&w| w > 0)
+            .unwrap_or(80);
+        let prompt_width = str_width(prompt);
+        // Continuation lines line up under the first, up to half the screen
+        let indent = prompt_width.min(cols / 2);
+
+        if self.cursor_row > 0 {
+            queue!(stdout, cursor::MoveUp(self.cursor_row))?;
+        }
+        queue!(
+            stdout,
+            cursor::MoveToColumn(0),
+            Clear(ClearType::FromCursorDown),
+            SetForegroundColor(prompt_color),
+            Print(prompt),
+            ResetColor
+        )?;
+
+        // Rows used by finished lines, and the width of the one being drawn
+        let mut rows = 0;
+        let mut width = prompt_width;
+        let mut cursor_pos = (0, width);
+        for (i, &c) in buffer.chars.iter().enumerate() {
+            if i == buffer.cursor {
+                cursor_pos = (rows, width);
+            }
+            if c == '\n' {
+                rows += width.div_ceil(cols).max(1);
+                width = indent;
+                queue!(stdout, Print("\r\n"), Print(" ".repeat(indent)))?;
+            } else {
+                width += char_width(c);
+                queue!(stdout, Print(c))?;
+            }
+        }
+        if buffer.cursor == buffer.chars.len() {
+            cursor_pos = (rows, width);
+        }
+
+        // Leave the terminal's pending-wrap state so positions stay predictable
+        if width > 0 && width.is_multiple_of(cols) {
+            queue!(stdout, Print("\r\n"))?;
+        }
+        let end_row = rows + width / cols;
+        let menu_rows = match menu {
+            Some(menu) => draw_menu(menu, cols)?,
+            None => 0,
+        };
+
+        let (line_rows, column) = cursor_pos;
+        let target_row = line_rows + column / cols;
+        let up = (end_row + menu_rows).saturating_sub(target_row);
+        if up > 0 {
+            queue!(stdout, cursor::MoveUp(up as u16))?;
+        }
+        queue!(stdout, cursor::MoveToColumn((column % cols) as u16))?;
+
+        self.cursor_row = target_row as u16;
+        stdout.flush()
+    }
+
+    // Redraw with the cursor after the input and start a fresh line below it
+    fn finish(&mut self, prompt: &str, prompt_color: Color, buffer: &Buffer) -> io::Result<()> {
+        let end = Buffer {
+            chars: buffer.chars.clone(),
+            cursor: buffer.chars.len(),
+        };
+        self.render(prompt, prompt_color, &end, None)?;
+        self.cursor_row = 0;
+        execute!(stdout(), Print("\r\n"))
+    }
+}
+
+// Draw the completions below the input, scrolled so the selection is
+// visible, and return the rows used
+fn draw_menu(menu: &Menu, cols: usize) -> io::Result<usize> {
+    let mut stdout = stdout();
+    let candidates = &menu.completion.candidates;
+    let first = menu.selected.saturating_sub(MENU_ROWS - 1);
+    let shown = &candidates[first..candidates.len().min(first + MENU_ROWS)];
+    let name_width = shown
+        .iter()
+        .map(|c| str_width(&c.display))
+        .max()
+        .unwrap_or(0);
+
+    for (i, candidate) in shown.iter().enumerate() {
+        let padding = " ".repeat(name_width - str_width(&candidate.display));
+        let line = format!(" {}{}  {} ", candidate.display, padding, candidate.hint);
+        // Never wrap, so the row count stays exact
+        let line = truncate_to_width(&line, cols - 1);
+        queue!(stdout, Print("\r\n"))?;
+        if first + i == menu.selected {
+            queue!(
+                stdout,
+                SetAttribute(Attribute::Reverse),
+                Print(line),
+                SetAttribute(Attribute::Reset)
+            )?;
+        } else {
+            queue!(stdout, Print(line))?;
+        }
+    }
+
+    if candidates.len() > shown.len() {
+        queue!(
+            stdout,
+            Print("\r\n"),
+            SetForegroundColor(if crate::tui::no_color() {
+                Color::Reset
+            } else {
+                Color::DarkGrey
+            }),
+            Print(truncate_to_width(
+                &format!(" {}/{}", menu.selected + 1, candidates.len()),
+                cols - 1
+            )),
+            ResetColor
+        )?;
+        return Ok(shown.len() + 1);
+    }
+    Ok(shown.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str, cursor: usize) -> Buffer {
+        Buffer {
+            chars: text.chars().collect(),
+            cursor,
+        }
+    }
+
+    fn history_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("agent-history-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn insert_replace_and_kill_move_the_cursor() {
+        let mut buffer = buffer("héllo", 1);
+        buffer.insert("ab");
+        assert_eq!((buffer.text().as_str(), buffer.cursor), ("habéllo", 3));
+
+        buffer.cursor = 4;
+        buffer.replace(1, "é");
+        assert_eq!((buffer.text().as_str(), buffer.cursor), ("héllo", 2));
+
+        assert_eq!(buffer.kill(0, 2), "hé");
+        assert_eq!((buffer.text().as_str(), buffer.cursor), ("llo", 0));
+
+        buffer.set("new text");
+        assert_eq!(buffer.cursor, 8);
+    }
+
+    #[test]
+    fn words_skip_punctuation() {
+        let mut buffer = buffer("one, two;  three", 9);
+        assert_eq!(buffer.word_left(), 5);
+        assert_eq!(buffer.word_right(), 16);
+        buffer.cursor = 0;
+        assert_eq!(buffer.word_left(), 0);
+        assert_eq!(buffer.word_right(), 3);
+    }
+
+    #[test]
+    fn lines_and_vertical_moves_keep_the_column() {
+        let mut buffer = buffer("first line\nab\nthird", 8);
+        assert!(buffer.on_first_line() && !buffer.on_last_line());
+        assert_eq!((buffer.line_start(), buffer.line_end()), (0, 10));
+
+        // The short middle line clamps the column
+        buffer.move_vertical(false);
+        assert_eq!(buffer.cursor, 13);
+        assert_eq!((buffer.line_start(), buffer.line_end()), (11, 13));
+        buffer.move_vertical(false);
+        assert_eq!(buffer.cursor, 16);
+        assert!(buffer.on_last_line());
+        buffer.move_vertical(true);
+        buffer.move_vertical(true);
+        assert_eq!(buffer.cursor, 2);
+    }
+
+    #[test]
+    fn history_skips_blanks_and_repeats_and_keeps_newlines() {
+        let path = history_path("entries");
+        let mut history = History::load(Some(path.clone()));
+        for line in ["one", "one", "  ", "two\nlines \\n", "one"] {
+            history.add(line).unwrap();
+        }
+        assert_eq!(history.entries(), ["one", "two\nlines \\n", "one"]);
+
+        let loaded = History::load(Some(path.clone()));
+        assert_eq!(loaded.entries(), history.entries());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn history_file_is_compacted_to_the_entries_kept() {
+        let path = history_path("compact");
+        let mut history = History::load(Some(path.clone()));
+        for i in 0..2 * MAX_HISTORY {
+            history.add(&i.to_string()).unwrap();
+        }
+        let lines = |path: &PathBuf| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 2 * MAX_HISTORY);
+        assert_eq!(history.entries().len(), MAX_HISTORY);
+
+        // Loading keeps only the newest entries but knows the file's length
+        let mut loaded = History::load(Some(path.clone()));
+        assert_eq!(loaded.entries(), history.entries());
+        loaded.add("last").unwrap();
+        assert_eq!(lines(&path), MAX_HISTORY);
+        assert_eq!(
+            loaded.entries().first().unwrap(),
+            &(MAX_HISTORY + 1).to_string()
+        );
+        assert_eq!(loaded.entries().last().unwrap(), "last");
+        fs::remove_file(path).unwrap();
+    }
+}
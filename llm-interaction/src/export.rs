@@ -0,0 +1,320 @@
+use crate::conversation::{Conversation, Role, Turn, TurnUsage};
+use crate::markdown;
+use crate::model_registry::ModelRegistry;
+use crate::repl::split_attachments;
+use chrono::{DateTime, Utc};
+use pulldown_cmark::{html, Event, Parser, Tag, TagEnd};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Md,
+    Html,
+    Json,
+}
+
+#[derive(Serialize)]
+struct Transcript<'a> {
+    id: &'a str,
+    title: &'a str,
+    created: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    messages: Vec<Message<'a>>,
+    // None when no answer has its usage recorded, e.g. imported ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<TurnUsage>,
+}
+
+// A turn as it reads in an export: prompts without the contents of the
+// files attached to them
+#[derive(Serialize)]
+struct Message<'a> {
+    role: Role,
+    #[serde(skip)]
+    speaker: String,
+    model: &'a str,
+    timestamp: DateTime<Utc>,
+    text: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<TurnUsage>,
+}
+
+// The branch of the conversation in use, as Markdown, a standalone HTML
+// page or JSON
+pub fn export(conversation: &Conversation, registry: &ModelRegistry, format: Format) -> String {
+    let transcript = transcript(conversation, registry);
+    match format {
+        Format::Md => to_markdown(&transcript),
+        Format::Html => to_html(&transcript),
+        Format::Json => serde_json::to_string_pretty(&transcript).unwrap_or_default() + "\n",
+    }
+}
+
+fn transcript<'a>(conversation: &'a Conversation, registry: &ModelRegistry) -> Transcript<'a> {
+    let messages: Vec<Message> = conversation
+        .active()
+        .into_iter()
+        .map(|turn| message(turn, registry))
+        .collect();
+    let mut usage: Option<TurnUsage> = None;
+    for turn_usage in messages.iter().filter_map(|message| message.usage.as_ref()) {
+        usage.get_or_insert_default().add(turn_usage);
+    }
+
+    Transcript {
+        id: &conversation.id,
+        title: if conversation.title.is_empty() {
+            "Untitled conversation"
+        } else {
+            &conversation.title
+        },
+        created: conversation.created,
+        updated: conversation.updated,
+        messages,
+        usage,
+    }
+}
+
+fn message<'a>(turn: &'a Turn, registry: &ModelRegistry) -> Message<'a> {
+    let (text, attachments, speaker) = match turn.role {
+        Role::User => {
+            let (text, attachments) = split_attachments(&turn.text);
+            (text, attachments, "You".to_string())
+        }
+        Role::Assistant => {
+            let speaker = registry
+                .get(&turn.model)
+                .map_or(turn.model.clone(), |model| model.display_name.clone());
+            (turn.text.as_str(), Vec::new(), speaker)
+        }
+    };
+    Message {
+        role: turn.role,
+        speaker,
+        model: &turn.model,
+        timestamp: turn.timestamp,
+        text,
+        attachments,
+        usage: turn.usage,
+    }
+}
+
+fn time(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn usage_line(model: &str, usage: &TurnUsage) -> String {
+    format!(
+        "{} · {} in, {} out tokens · ${:.6}",
+        model, usage.input_tokens, usage.output_tokens, usage.cost
+    )
+}
+
+fn total_line(usage: &TurnUsage) -> String {
+    format!(
+        "Total: {} in, {} out tokens · ${:.6}",
+        usage.input_tokens, usage.output_tokens, usage.cost
+    )
+}
+
+fn to_markdown(transcript: &Transcript) -> String {
+    let mut md = format!(
+        "# {}\n\nConversation `{}` · started {} · last message {}\n",
+        transcript.title,
+        transcript.id,
+        time(transcript.created),
+        time(transcript.updated)
+    );
+
+    for message in &transcript.messages {
+        md.push_str(&format!(
+            "\n## {} · {}\n\n{}\n",
+            message.speaker,
+            time(message.timestamp),
+            message.text.trim_end()
+        ));
+        if !message.attachments.is_empty() {
+            let paths: Vec<String> = message
+                .attachments
+                .iter()
+                .map(|path| format!("`{}`", path))
+                .collect();
+            md.push_str(&format!("\nAttached: {}\n", paths.join(", ")));
+        }
+        if let Some(usage) = &message.usage {
+            md.push_str(&format!("\n*{}*\n", usage_line(message.model, usage)));
+        }
+    }
+
+    if let Some(usage) = &transcript.usage {
+        md.push_str(&format!("\n---\n\n{}\n", total_line(usage)));
+    }
+    md
+}
+
+fn to_html(transcript: &Transcript) -> String {
+    let mut body = String::new();
+    for message in &transcript.messages {
+        let class = match message.role {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        };
+        body.push_str(&format!(
+            "<section class=\"{}\">\n<h2>{} <span class=\"meta\">{}</span></h2>\n{}",
+            class,
+            escape(&message.speaker),
+            time(message.timestamp),
+            markdown_html(message.text)
+        ));
+        if !message.attachments.is_empty() {
+            let paths: Vec<String> = message
+                .attachments
+                .iter()
+                .map(|path| format!("<code>{}</code>", escape(path)))
+                .collect();
+            body.push_str(&format!(
+                "<p class=\"meta\">Attached: {}</p>\n",
+                paths.join(", ")
+            ));
+        }
+        if let Some(usage) = &message.usage {
+            body.push_str(&format!(
+                "<p class=\"meta\">{}</p>\n",
+                escape(&usage_line(message.model, usage))
+            ));
+        }
+        body.push_str("</section>\n");
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ max-width: 50rem; margin: 2rem auto; padding: 0 1rem; font-family: system-ui, sans-serif; line-height: 1.5; }}
+section {{ border-radius: 6px; padding: 0.5rem 1rem; margin: 1rem 0; }}
+section.user {{ background: #f2f2f2; }}
+section.assistant {{ background: #eef5fc; }}
+h2 {{ font-size: 1rem; }}
+.meta {{ color: #666; font-size: 0.85rem; font-weight: normal; }}
+pre {{ background: #272822; color: #f8f8f2; padding: 0.75rem; overflow-x: auto; border-radius: 4px; }}
+code {{ font-family: ui-monospace, monospace; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<p class="meta">Conversation <code>{id}</code> · started {created} · last message {updated}</p>
+{body}{total}</body>
+</html>
+"#,
+        title = escape(transcript.title),
+        id = escape(transcript.id),
+        created = time(transcript.created),
+        updated = time(transcript.updated),
+        body = body,
+        total = transcript
+            .usage
+            .as_ref()
+            .map_or(String::new(), |usage| format!(
+                "<p class=\"meta\">{}</p>\n",
+                escape(&total_line(usage))
+            )),
+    )
+}
+
+// Messages are Markdown; any HTML in them is shown as text, not run, and so
+// are links and images to anything but web pages and mail addresses
+fn markdown_html(text: &str) -> String {
+    // Whether each open link or image is kept
+    let mut kept: Vec<bool> = Vec::new();
+    let events = Parser::new_ext(text, markdown::options()).filter_map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Some(Event::Text(html)),
+        Event::Start(Tag::Link { ref dest_url, .. } | Tag::Image { ref dest_url, .. }) => {
+            let safe = safe_url(dest_url);
+            kept.push(safe);
+            safe.then_some(event)
+        }
+        Event::End(TagEnd::Link | TagEnd::Image) => kept.pop().unwrap_or(true).then_some(event),
+        event => Some(event),
+    });
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+// Relative URLs, or http, https and mailto ones. Browsers ignore control
+// characters and whitespace in a scheme, so they're left out before looking.
+fn safe_url(url: &str) -> bool {
+    let url: String = url
+        .chars()
+        .filter(|c| !c.is_ascii_control() && !c.is_ascii_whitespace())
+        .collect();
+    match url.split_once(':') {
+        Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => ["http", "https", "mailto"]
+            .iter()
+            .any(|allowed| scheme.eq_ignore_ascii_case(allowed)),
+        _ => true,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::markdown_html;
+
+    #[test]
+    fn web_and_mail_links_are_kept() {
+        let html = markdown_html(
+            "[site](https://example.com) [mail](mailto:a@example.com) [page](docs/a.md#top) ![logo](http://example.com/a.png)",
+        );
+        assert!(html.contains(r#"<a href="https://example.com">site</a>"#));
+        assert!(html.contains(r#"<a href="mailto:a@example.com">mail</a>"#));
+        assert!(html.contains(r#"<a href="docs/a.md#top">page</a>"#));
+        assert!(html.contains(r#"<img src="http://example.com/a.png" alt="logo" />"#));
+    }
+
+    #[test]
+    fn other_schemes_become_text() {
+        for markdown in [
+            "[click](javascript:alert(1))",
+            "[click](JavaScript:alert(1))",
+            "[click](<java\tscript:alert(1)>)",
+            "[click](java&#x09;script:alert(1))",
+            "[click](data:text/html;base64,PHNjcmlwdD4=)",
+            "<javascript:alert(1)>",
+            "![click](vbscript:msgbox)",
+            "[![click](javascript:a)](javascript:b)",
+        ] {
+            let html = markdown_html(markdown);
+            assert!(
+                !html.contains("<a") && !html.contains("<img"),
+                "{}: {}",
+                markdown,
+                html
+            );
+            assert!(
+                html.contains("click") || html.contains("alert"),
+                "{}: {}",
+                markdown,
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn raw_html_is_escaped() {
+        let html = markdown_html("<script>alert(1)</script>\n\nhi <b onclick=x>there</b>");
+        assert!(!html.contains("<script") && !html.contains("<b "));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+}
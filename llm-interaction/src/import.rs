@@ -0,0 +1,677 @@
+use crate::conversation::{self, Conversation, ConversationStore, Role, Turn};
+use crate::repl::file_block;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+// Claude.ai exports don't say which model answered
+const CLAUDE_AI_MODEL: &str = "claude.ai";
+
+// A conversation in the `conversations.json` of a Claude.ai data export
+#[derive(Deserialize)]
+struct ExportedConversation {
+    uuid: String,
+    #[serde(default)]
+    name: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(default)]
+    chat_messages: Vec<ExportedMessage>,
+    // The message the conversation was left at, in exports with branches
+    #[serde(default)]
+    current_leaf_message_uuid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExportedMessage {
+    uuid: String,
+    // "human" or "assistant"
+    sender: String,
+    created_at: DateTime<Utc>,
+    // Older exports only have the plain text
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    content: Vec<ContentBlock>,
+    // Pasted text and documents, with the text extracted from them
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    // Uploads such as images, which the export leaves out
+    #[serde(default)]
+    files: Vec<ExportedFile>,
+    // Missing in older exports, where the messages are one branch in order
+    #[serde(default)]
+    parent_message_uuid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Attachment {
+    #[serde(default)]
+    file_name: String,
+    #[serde(default)]
+    extracted_content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExportedFile {
+    #[serde(default)]
+    file_name: String,
+}
+
+// Add the conversations of a Claude.ai data export (the zip, or the
+// conversations.json in it) to the store. A conversation imported before
+// is brought up to date when the export has a newer copy, keeping whatever
+// was added to it here.
+pub fn claude_export(
+    path: &Path,
+    store: &ConversationStore,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let contents = read_export(path)?;
+    let values = match serde_json::from_str(&contents).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid export {}: {}", path.display(), e),
+        )
+    })? {
+        Value::Array(values) => values,
+        value => vec![value],
+    };
+
+    // The newest copy of each conversation in the export
+    let mut exported: Vec<Conversation> = Vec::new();
+    let mut empty = 0;
+    for value in values {
+        let conversation = match serde_json::from_value::<ExportedConversation>(value)
+            .map_err(|e| e.to_string())
+            .and_then(convert)
+        {
+            Ok(Some(conversation)) => conversation,
+            Ok(None) => {
+                empty += 1;
+                continue;
+            }
+            Err(e) => {
+                writeln!(out, "Warning: skipping a conversation: {}", e)?;
+                continue;
+            }
+        };
+        match exported
+            .iter_mut()
+            .find(|other| other.id == conversation.id)
+        {
+            Some(other) if other.updated < conversation.updated => *other = conversation,
+            Some(_) => {}
+            None => exported.push(conversation),
+        }
+    }
+
+    let mut conversations = Vec::new();
+    let (mut added, mut updated, mut existing, mut conflicts) = (0, 0, 0, 0);
+    for conversation in exported {
+        if !store.contains(&conversation.id) {
+            added += 1;
+            conversations.push(conversation);
+            continue;
+        }
+        let stored = store.load(&conversation.id)?;
+        let (turns, saved) = (stored.turns.len(), stored.updated);
+        match merge(stored, conversation) {
+            Some(merged) if merged.turns.len() > turns || merged.updated > saved => {
+                updated += 1;
+                conversations.push(merged);
+            }
+            Some(_) => existing += 1,
+            None => conflicts += 1,
+        }
+    }
+    store.save_all(&conversations)?;
+
+    for conversation in &conversations {
+        writeln!(
+            out,
+            "{}  {}  {}",
+            conversation.id,
+            conversation.updated.format("%Y-%m-%d %H:%M"),
+            conversation.title
+        )?;
+    }
+    let mut summary = format!("Imported {} conversations from {}", added, path.display());
+    if updated > 0 {
+        summary.push_str(&format!("; updated {} imported before", updated));
+    }
+    if existing > 0 {
+        summary.push_str(&format!("; {} already up to date", existing));
+    }
+    if conflicts > 0 {
+        summary.push_str(&format!(
+            "; left {} alone that changed both here and in the export",
+            conflicts
+        ));
+    }
+    if empty > 0 {
+        summary.push_str(&format!("; skipped {} with no messages", empty));
+    }
+    writeln!(out, "{}.", summary)?;
+    if !conversations.is_empty() {
+        writeln!(
+            out,
+            "Find them with `agent search <words>` and continue one with `agent --resume <id>`."
+        )?;
+    }
+    Ok(())
+}
+
+// conversations.json, read from the zip when given one
+fn read_export(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let mut magic = [0; 4];
+    let is_zip = file.read(&mut magic)? == magic.len() && &magic == b"PK\x03\x04";
+    if !is_zip {
+        return fs::read_to_string(path);
+    }
+
+    let mut archive = zip::ZipArchive::new(file).map_err(io::Error::other)?;
+    let name = archive
+        .file_names()
+        .find(|name| name.rsplit('/').next() == Some("conversations.json"))
+        .map(str::to_string)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no conversations.json", path.display()),
+            )
+        })?;
+    let mut contents = String::new();
+    archive
+        .by_name(&name)
+        .map_err(io::Error::other)?
+        .read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+// None when there's nothing to import
+fn convert(exported: ExportedConversation) -> Result<Option<Conversation>, String> {
+    let mut conversation = Conversation::new();
+    // The whole uuid, so the same conversation always gets the same id and
+    // no two get the same one. Checked, as it becomes a file name.
+    conversation.id = Uuid::parse_str(&exported.uuid)
+        .map_err(|_| format!("'{}' is not a valid uuid", exported.uuid))?
+        .simple()
+        .to_string();
+
+    let mut indexes: HashMap<String, usize> = HashMap::new();
+    for message in exported.chat_messages {
+        let role = match message.sender.as_str() {
+            "human" => Role::User,
+            "assistant" => Role::Assistant,
+            _ => continue,
+        };
+        let parent = match &message.parent_message_uuid {
+            Some(uuid) => indexes.get(uuid).copied(),
+            None => conversation.turns.len().checked_sub(1),
+        };
+        let text = message_text(&message);
+        // Empty messages can't be sent back; what follows them goes on
+        // from their parent instead
+        if text.trim().is_empty() {
+            if let Some(parent) = parent {
+                indexes.insert(message.uuid, parent);
+            }
+            continue;
+        }
+
+        indexes.insert(message.uuid, conversation.turns.len());
+        conversation.turns.push(Turn {
+            role,
+            text,
+            timestamp: message.created_at,
+            model: CLAUDE_AI_MODEL.to_string(),
+            parent,
+            usage: None,
+        });
+    }
+    if conversation.turns.is_empty() {
+        return Ok(None);
+    }
+
+    conversation.current = exported
+        .current_leaf_message_uuid
+        .and_then(|uuid| indexes.get(&uuid).copied())
+        .or(Some(conversation.turns.len() - 1));
+    conversation.title = if exported.name.trim().is_empty() {
+        conversation
+            .turns
+            .iter()
+            .find(|turn| turn.role == Role::User)
+            .map(|turn| conversation::summary(&turn.text))
+            .unwrap_or_default()
+    } else {
+        exported.name.trim().to_string()
+    };
+    conversation.created = exported.created_at;
+    conversation.updated = exported.updated_at;
+    Ok(Some(conversation))
+}
+
+// A conversation imported before, brought up to date with its copy in the
+// export. Turns added here since are kept on top of what the export adds.
+// None when the turns that came from the export before don't start the new
+// copy any more, so the two can't be put together.
+fn merge(stored: Conversation, mut exported: Conversation) -> Option<Conversation> {
+    let imported = stored
+        .turns
+        .iter()
+        .take_while(|turn| turn.model == CLAUDE_AI_MODEL)
+        .count();
+    if imported == stored.turns.len() {
+        return Some(if exported.updated > stored.updated {
+            exported
+        } else {
+            stored
+        });
+    }
+    let same =
+        |(a, b): (&Turn, &Turn)| a.role == b.role && a.text == b.text && a.parent == b.parent;
+    if exported.turns.len() < imported
+        || !stored.turns[..imported]
+            .iter()
+            .zip(&exported.turns)
+            .all(same)
+    {
+        return None;
+    }
+
+    // Local turns move up past the exported ones that are new
+    let offset = exported.turns.len() - imported;
+    let moved = |i: usize| if i < imported { i } else { i + offset };
+    for mut turn in stored.turns.into_iter().skip(imported) {
+        turn.parent = turn.parent.map(moved);
+        exported.turns.push(turn);
+    }
+    if let Some(current) = stored.current.filter(|&current| current >= imported) {
+        exported.current = Some(moved(current));
+    }
+    exported.created = stored.created;
+    exported.updated = exported.updated.max(stored.updated);
+    Some(exported)
+}
+
+// The message's text blocks, followed by its attachments the way
+// `@path` attaches files
+fn message_text(message: &ExportedMessage) -> String {
+    let blocks: Vec<&str> = message
+        .content
+        .iter()
+        .filter(|block| block.kind == "text")
+        .filter_map(|block| block.text.as_deref())
+        .filter(|text| !text.trim().is_empty())
+        .collect();
+    let mut text = if blocks.is_empty() {
+        message.text.clone()
+    } else {
+        blocks.join("\n\n")
+    };
+
+    for attachment in &message.attachments {
+        let name = match attachment.file_name.as_str() {
+            "" => "attachment",
+            name => name,
+        };
+        let contents = attachment
+            .extracted_content
+            .as_deref()
+            .unwrap_or("(not included in the export)");
+        text.push_str(&file_block(name, contents));
+    }
+    for file in &message.files {
+        if !message
+            .attachments
+            .iter()
+            .any(|attachment| attachment.file_name == file.file_name)
+        {
+            text.push_str(&file_block(&file.file_name, "(not included in the export)"));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const UUID: &str = "3fa85f64-5717-4562-b3fc-2c963f66afa6";
+
+    fn message(uuid: &str, sender: &str, text: &str, parent: Option<&str>) -> Value {
+        json!({
+            "uuid": uuid,
+            "sender": sender,
+            "created_at": "2025-06-01T10:00:00Z",
+            "content": [{"type": "text", "text": text}],
+            "parent_message_uuid": parent,
+        })
+    }
+
+    fn exported(messages: Vec<Value>, extra: Value) -> ExportedConversation {
+        let mut value = json!({
+            "uuid": UUID,
+            "name": "",
+            "created_at": "2025-06-01T10:00:00Z",
+            "updated_at": "2025-06-02T10:00:00Z",
+            "chat_messages": messages,
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn convert_ok(messages: Vec<Value>, extra: Value) -> Conversation {
+        convert(exported(messages, extra)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn converts_branches_and_follows_the_current_leaf() {
+        let conversation = convert_ok(
+            vec![
+                message("q", "human", "Question", None),
+                message("a1", "assistant", "First answer", Some("q")),
+                message("a2", "assistant", "Retried answer", Some("q")),
+                message("f", "human", "Follow-up", Some("a1")),
+            ],
+            json!({"current_leaf_message_uuid": "a2"}),
+        );
+
+        assert_eq!(conversation.id, "3fa85f6457174562b3fc2c963f66afa6");
+        assert_eq!(conversation.title, "Question");
+        let parents: Vec<Option<usize>> = conversation.turns.iter().map(|t| t.parent).collect();
+        assert_eq!(parents, [None, Some(0), Some(0), Some(1)]);
+        assert_eq!(conversation.current, Some(2));
+        assert_eq!(conversation.last_answer(), Some("Retried answer"));
+
+        // Without a leaf, the last message
+        let conversation = convert_ok(
+            vec![
+                message("q", "human", "Question", None),
+                message("a", "assistant", "Answer", Some("q")),
+            ],
+            json!({"name": "  Named  "}),
+        );
+        assert_eq!(conversation.current, Some(1));
+        assert_eq!(conversation.title, "Named");
+    }
+
+    #[test]
+    fn empty_messages_are_skipped() {
+        let conversation = convert_ok(
+            vec![
+                message("q", "human", "Question", None),
+                message("e", "assistant", "  ", Some("q")),
+                message("f", "human", "Anyone?", Some("e")),
+            ],
+            json!({}),
+        );
+        assert_eq!(conversation.turns.len(), 2);
+        assert_eq!(conversation.turns[1].parent, Some(0));
+
+        // Older exports: plain text and no parents
+        let conversation = convert_ok(
+            vec![
+                json!({"uuid": "1", "sender": "human", "created_at": "2025-06-01T10:00:00Z", "text": "Hi"}),
+                json!({"uuid": "2", "sender": "assistant", "created_at": "2025-06-01T10:00:00Z", "text": ""}),
+                json!({"uuid": "3", "sender": "assistant", "created_at": "2025-06-01T10:00:00Z", "text": "Hello"}),
+            ],
+            json!({}),
+        );
+        assert_eq!(conversation.turns[1].parent, Some(0));
+        assert_eq!(conversation.turns[1].text, "Hello");
+
+        let nothing = exported(vec![message("e", "human", "", None)], json!({}));
+        assert!(convert(nothing).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_uuids_are_rejected() {
+        for uuid in [
+            "../../etc/passwd",
+            "",
+            "3fa85f64-5717-4562-b3fc-2c963f66afaz",
+        ] {
+            let exported = exported(
+                vec![message("q", "human", "Question", None)],
+                json!({ "uuid": uuid }),
+            );
+            assert!(convert(exported).is_err(), "{}", uuid);
+        }
+    }
+
+    #[test]
+    fn attachments_are_added_as_files() {
+        let message: ExportedMessage = serde_json::from_value(json!({
+            "uuid": "q",
+            "sender": "human",
+            "created_at": "2025-06-01T10:00:00Z",
+            "text": "ignored when there are blocks",
+            "content": [
+                {"type": "text", "text": "Read these"},
+                {"type": "tool_use"},
+                {"type": "text", "text": " "},
+                {"type": "text", "text": "please"}
+            ],
+            "attachments": [
+                {"file_name": "notes.txt", "extracted_content": "Some notes\n"},
+                {"file_name": "", "extracted_content": null}
+            ],
+            "files": [{"file_name": "notes.txt"}, {"file_name": "photo.png"}]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            message_text(&message),
+            format!(
+                "Read these\n\nplease{}{}{}",
+                file_block("notes.txt", "Some notes"),
+                file_block("attachment", "(not included in the export)"),
+                file_block("photo.png", "(not included in the export)")
+            )
+        );
+    }
+
+    // The export before and after another exchange on Claude.ai
+    fn old_and_newer() -> (Conversation, Conversation) {
+        let old = convert_ok(
+            vec![
+                message("q", "human", "Question", None),
+                message("a", "assistant", "Answer", Some("q")),
+            ],
+            json!({}),
+        );
+        let mut newer = convert_ok(
+            vec![
+                message("q", "human", "Question", None),
+                message("a", "assistant", "Answer", Some("q")),
+                message("f", "human", "Web follow-up", Some("a")),
+                message("b", "assistant", "Web answer", Some("f")),
+            ],
+            json!({}),
+        );
+        newer.updated = Utc::now() + chrono::Duration::days(1);
+        (old, newer)
+    }
+
+    fn texts(conversation: &Conversation) -> Vec<&str> {
+        conversation
+            .turns
+            .iter()
+            .map(|turn| turn.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn a_newer_export_goes_under_the_turns_added_here() {
+        let (old, newer) = old_and_newer();
+        let mut stored = old.clone();
+        stored.add_exchange(
+            "Local follow-up",
+            "Local answer",
+            "claude-test",
+            None,
+            false,
+        );
+        // A retried answer, on a branch of its own
+        stored.rewind();
+        stored.add_exchange("Local follow-up", "Local retry", "claude-test", None, false);
+        assert_eq!(stored.current, Some(4));
+
+        let merged = merge(stored.clone(), newer.clone()).unwrap();
+        assert_eq!(
+            texts(&merged),
+            [
+                "Question",
+                "Answer",
+                "Web follow-up",
+                "Web answer",
+                "Local follow-up",
+                "Local answer",
+                "Local retry"
+            ]
+        );
+        let parents: Vec<Option<usize>> = merged.turns.iter().map(|t| t.parent).collect();
+        assert_eq!(
+            parents,
+            [None, Some(0), Some(1), Some(2), Some(1), Some(4), Some(4)]
+        );
+        assert_eq!(merged.current, Some(6));
+        assert_eq!(merged.created, stored.created);
+        assert_eq!(merged.updated, newer.updated);
+
+        // On a turn that came from the export, the export's leaf is current
+        stored.switch_branch(1);
+        assert_eq!(merge(stored, newer).unwrap().current, Some(3));
+    }
+
+    #[test]
+    fn without_turns_added_here_the_newer_copy_wins() {
+        let (old, newer) = old_and_newer();
+        assert_eq!(texts(&merge(old.clone(), newer.clone()).unwrap()).len(), 4);
+        assert_eq!(texts(&merge(newer, old).unwrap()).len(), 4);
+    }
+
+    #[test]
+    fn an_unchanged_export_changes_nothing() {
+        let (old, _) = old_and_newer();
+        let mut stored = old.clone();
+        stored.add_exchange(
+            "Local follow-up",
+            "Local answer",
+            "claude-test",
+            None,
+            false,
+        );
+
+        let merged = merge(stored.clone(), old).unwrap();
+        assert_eq!(texts(&merged), texts(&stored));
+        assert_eq!(merged.current, stored.current);
+        assert_eq!(merged.updated, stored.updated);
+    }
+
+    #[test]
+    fn turns_changed_in_the_export_under_local_ones_conflict() {
+        let (old, newer) = old_and_newer();
+        let mut stored = old;
+        stored.add_exchange(
+            "Local follow-up",
+            "Local answer",
+            "claude-test",
+            None,
+            false,
+        );
+
+        let mut edited = newer.clone();
+        edited.turns[1].text = "Edited answer".to_string();
+        assert!(merge(stored.clone(), edited).is_none());
+
+        let mut shorter = newer;
+        shorter.turns.truncate(1);
+        assert!(merge(stored, shorter).is_none());
+    }
+
+    #[test]
+    fn importing_again_reports_what_changed() {
+        let dir = std::env::temp_dir().join(format!("agent-import-{}", std::process::id()));
+        let store = ConversationStore::new(dir.join("conversations"));
+        fs::create_dir_all(&dir).unwrap();
+        let export = dir.join("conversations.json");
+        let conversation = |messages: Value, updated: &str| {
+            json!([{
+                "uuid": UUID,
+                "name": "Imported",
+                "created_at": "2025-06-01T10:00:00Z",
+                "updated_at": updated,
+                "chat_messages": messages,
+            }])
+            .to_string()
+        };
+        let import = || {
+            let mut out = Vec::new();
+            claude_export(&export, &store, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let first = json!([
+            message("q", "human", "Question", None),
+            message("a", "assistant", "Answer", Some("q"))
+        ]);
+        fs::write(&export, conversation(first.clone(), "2025-06-02T10:00:00Z")).unwrap();
+        assert!(import().contains("Imported 1 conversations"));
+        assert!(
+            import().contains("Imported 0 conversations from")
+                && import().contains("; 1 already up to date.")
+        );
+
+        let mut stored = store
+            .load(&uuid::Uuid::parse_str(UUID).unwrap().simple().to_string())
+            .unwrap();
+        stored.add_exchange(
+            "Local follow-up",
+            "Local answer",
+            "claude-test",
+            None,
+            false,
+        );
+        store.save(&stored).unwrap();
+        let mut second = first.as_array().unwrap().clone();
+        second.push(message("f", "human", "Web follow-up", Some("a")));
+        fs::write(&export, conversation(json!(second), "2099-01-01T00:00:00Z")).unwrap();
+        assert!(import().contains("; updated 1 imported before."));
+        assert_eq!(store.load(&stored.id).unwrap().turns.len(), 5);
+        assert!(import().contains("; 1 already up to date."));
+
+        fs::write(
+            &export,
+            conversation(
+                json!([message("q", "human", "Changed", None)]),
+                "2099-01-02T00:00:00Z",
+            ),
+        )
+        .unwrap();
+        assert!(import().contains("; left 1 alone that changed both here and in the export."));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
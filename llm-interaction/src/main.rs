@@ -1,23 +1,139 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
+mod api;
+mod batch;
+mod budget;
+mod chat;
+mod commands;
+mod completion;
+mod config;
+mod conversation;
+mod editor;
+mod export;
+mod import;
+mod markdown;
+mod model_registry;
 mod modules;
-use modules::{parse_sse_line, ClaudeMessage, ClaudeStreamApiRequest, StreamEvent};
+mod oneshot;
+mod output;
+mod pricing;
+mod repl;
+mod sampling;
+mod search;
+mod structured;
+mod templates;
+mod theme;
+mod tui;
+mod usage;
 
-use clap::{Parser, Subcommand};
-use dirs;
-use reqwest;
+use api::ApiClient;
+use budget::BudgetTracker;
+use commands::CommandRegistry;
+use config::Config;
+use conversation::{Conversation, ConversationStore, Role};
+use model_registry::{Capability, ModelRegistry, ModelStatus, RemoteModelCache};
+use output::OutputFormat;
+use pricing::PricingTable;
+use repl::Session;
+use sampling::SamplingParams;
+use search::SearchQuery;
+use structured::OutputSchema;
+use tui::TerminalUi;
+use usage::{GroupBy, UsageLedger};
+
+use chrono::Utc;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
 use std::collections::HashMap;
-use std::fs;
-use std::io::{self, Write};
-use tokio;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Profile to attribute usage to (overrides the configured profile)
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    #[command(flatten)]
+    request: RequestArgs,
+
+    /// Answer a single prompt non-interactively and exit (stdin is appended when piped)
+    #[arg(short = 'p', long = "print", value_name = "PROMPT")]
+    print: Option<String>,
+
+    /// Use the full-screen chat interface (same as tui_enabled in config)
+    #[arg(long)]
+    tui: bool,
+
+    /// Pick up a saved conversation by id (see `agent search`)
+    #[arg(long, value_name = "ID")]
+    resume: Option<String>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+// Flags for the commands that send prompts: chat, -p, ask and run
+#[derive(Args)]
+struct RequestArgs {
+    #[command(flatten)]
+    sampling: SamplingArgs,
+
+    /// Continue responses cut off at max_tokens up to N times (overrides config)
+    #[arg(long, value_name = "N")]
+    auto_continue: Option<u32>,
+
+    /// Output for one-shot prompts: text, json or stream-json
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    output_format: Option<OutputFormat>,
+
+    /// Answer one-shot prompts as JSON matching this JSON Schema file
+    #[arg(long, value_name = "FILE")]
+    schema: Option<PathBuf>,
+}
+
+impl RequestArgs {
+    fn is_empty(&self) -> bool {
+        self.sampling.to_params() == SamplingParams::default()
+            && self.auto_continue.is_none()
+            && self.output_format.is_none()
+            && self.schema.is_none()
+    }
+}
+
+// Sampling overrides for this run, layered over the defaults saved in config
+#[derive(Args)]
+struct SamplingArgs {
+    /// Maximum tokens to generate (defaults to the model's default_max_tokens)
+    #[arg(long)]
+    max_tokens: Option<u32>,
+    /// Sampling temperature between 0.0 and 1.0
+    #[arg(long)]
+    temperature: Option<f32>,
+    /// Nucleus sampling cutoff between 0.0 and 1.0
+    #[arg(long)]
+    top_p: Option<f32>,
+    /// Only sample from the top K tokens
+    #[arg(long)]
+    top_k: Option<u32>,
+    /// Stop generating at this sequence (repeat for several)
+    #[arg(long = "stop")]
+    stop_sequences: Vec<String>,
+}
+
+impl SamplingArgs {
+    fn to_params(&self) -> SamplingParams {
+        SamplingParams {
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            stop_sequences: self.stop_sequences.clone(),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Set your Claude API key
@@ -27,101 +143,375 @@ enum Commands {
     },
     /// Show available commands and usage information
     Status, // Changed from Help to Status since help is built-in
+    /// Report token usage and cost recorded in the usage ledger
+    Usage {
+        /// Only include requests since a date (YYYY-MM-DD, RFC 3339, or e.g. 7d)
+        #[arg(long)]
+        since: Option<String>,
+        /// Group the report by model, day or profile
+        #[arg(long, value_enum, default_value = "model")]
+        by: GroupBy,
+        /// Print the report as JSON
+        #[arg(long, conflicts_with = "csv")]
+        json: bool,
+        /// Print the report as CSV
+        #[arg(long)]
+        csv: bool,
+    },
+    /// Show or change spending limits
+    Budget {
+        #[command(subcommand)]
+        action: Option<BudgetAction>,
+    },
+    /// List or choose models from the model registry
+    Models {
+        #[command(subcommand)]
+        action: Option<ModelsAction>,
+    },
+    /// Answer a single prompt non-interactively, reading extra context from stdin
+    Ask {
+        /// The prompt; may be omitted when input is piped on stdin
+        prompt: Vec<String>,
+        #[command(flatten)]
+        request: RequestArgs,
+    },
+    /// Run a prompt template; lists the available templates when no name is given
+    Run {
+        /// Template name, or the path to a prompt file (as used by a shebang line)
+        name: Option<String>,
+        /// Set a template variable; may be repeated
+        #[arg(long = "var", value_name = "KEY=VALUE")]
+        vars: Vec<String>,
+        #[command(flatten)]
+        request: RequestArgs,
+    },
+    /// Search saved conversations
+    Search {
+        /// Words that must all appear in a message
+        #[arg(required = true)]
+        query: Vec<String>,
+        /// Only messages to or from models whose id contains this
+        #[arg(long)]
+        model: Option<String>,
+        /// Only messages since a date (YYYY-MM-DD, RFC 3339, or e.g. 7d)
+        #[arg(long)]
+        since: Option<String>,
+        /// Only messages up to and including a date
+        #[arg(long)]
+        until: Option<String>,
+        /// Only your messages or only answers
+        #[arg(long, value_enum)]
+        role: Option<Role>,
+        /// Show at most this many results
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Export a saved conversation as a readable transcript
+    Export {
+        /// Conversation id, or a prefix only one id starts with
+        id: String,
+        /// Output format
+        #[arg(long, value_enum, default_value = "md")]
+        format: export::Format,
+        /// Write to a file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Import conversations from elsewhere into the saved conversations
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+    /// Submit and manage Message Batches from JSONL files
+    Batch {
+        #[command(subcommand)]
+        action: BatchAction,
+    },
 }
 
-struct Config {
-    api_key: Option<String>,
-    config_path: std::path::PathBuf,
+#[derive(Subcommand)]
+enum ImportSource {
+    /// A Claude.ai data export
+    ClaudeExport {
+        /// The export's zip file, or the conversations.json in it
+        file: PathBuf,
+    },
 }
 
-impl Config {
-    fn new() -> io::Result<Self> {
-        let config_dir = dirs::config_dir().ok_or_else(|| {
-            io::Error::new(io::ErrorKind::NotFound, "Could not find config directory")
-        })?;
-        let config_path = config_dir.join("claude-cli/config.json");
+#[derive(Subcommand)]
+enum BatchAction {
+    /// Submit every line of a JSONL file as one batch
+    Submit {
+        /// Lines are either `{"custom_id", "params"}` or `{"id", "prompt"}`
+        file: PathBuf,
+        /// Wait for the batch to finish and download its results
+        #[arg(long)]
+        wait: bool,
+        /// Where to write results when using --wait
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Sampling for shorthand lines, which have no params of their own
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        /// Print results with --wait on stdout as text, json or stream-json
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        output_format: Option<OutputFormat>,
+    },
+    /// Show progress of a batch, or of every tracked batch
+    Status {
+        batch_id: Option<String>,
+        /// Keep polling until the batch ends
+        #[arg(long, requires = "batch_id")]
+        wait: bool,
+    },
+    /// Write results of an ended batch as JSONL keyed by custom_id
+    Results {
+        /// Defaults to the most recently submitted batch
+        batch_id: Option<String>,
+        /// Defaults to <batch id>.results.jsonl
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Print results on stdout as text, json or stream-json
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        output_format: Option<OutputFormat>,
+    },
+    /// Cancel a batch that is still processing
+    Cancel {
+        /// Defaults to the most recently submitted batch
+        batch_id: Option<String>,
+    },
+}
 
-        if let Ok(config_str) = fs::read_to_string(&config_path) {
-            let config: HashMap<String, String> =
-                serde_json::from_str(&config_str).unwrap_or_default();
-            Ok(Config {
-                api_key: config.get("api_key").cloned(),
-                config_path,
-            })
-        } else {
-            Ok(Config {
-                api_key: None,
-                config_path,
-            })
+#[derive(Subcommand)]
+enum ModelsAction {
+    /// List models with pricing and capabilities
+    List {
+        /// Include models the last refresh found retired
+        #[arg(long)]
+        all: bool,
+    },
+    /// Fetch the models available to your API key and cache them locally
+    Refresh,
+    /// Make a model (by id or alias) the default for new sessions
+    Use {
+        /// Model id or alias, e.g. `sonnet`
+        model: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BudgetAction {
+    /// Set one or more limits in USD
+    Set {
+        /// Limit for a single chat session
+        #[arg(long)]
+        session: Option<f64>,
+        /// Limit across all profiles per day
+        #[arg(long)]
+        daily: Option<f64>,
+        /// Daily limit for the active profile (see --profile)
+        #[arg(long)]
+        profile_limit: Option<f64>,
+        /// Fraction of a limit at which to start warning, e.g. 0.8
+        #[arg(long)]
+        warn_at: Option<f64>,
+    },
+    /// Remove all limits
+    Clear,
+}
+
+fn update_budget(config: &mut Config, profile: &str, action: &BudgetAction) -> io::Result<()> {
+    let mut limits = config.budget.clone();
+
+    match action {
+        BudgetAction::Set {
+            session,
+            daily,
+            profile_limit,
+            warn_at,
+        } => {
+            if let Some(session) = session {
+                limits.session = Some(*session);
+            }
+            if let Some(daily) = daily {
+                limits.daily = Some(*daily);
+            }
+            if let Some(limit) = profile_limit {
+                limits.profiles.insert(profile.to_string(), *limit);
+            }
+            if let Some(warn_at) = warn_at {
+                limits.warn_at = *warn_at;
+            }
         }
+        BudgetAction::Clear => limits = Default::default(),
+    }
+
+    config.set_budget(limits)
+}
+
+fn print_models(registry: &ModelRegistry, cache: Option<&RemoteModelCache>, all: bool) {
+    let current = registry.get_current_model();
+
+    if let Some(cache) = cache {
+        println!(
+            "Availability from {} (run `agent models refresh` to update)\n",
+            cache.fetched_at.format("%Y-%m-%d %H:%M UTC")
+        );
     }
 
-    fn save(&self) -> io::Result<()> {
-        let mut config = HashMap::new();
-        if let Some(key) = &self.api_key {
-            config.insert("api_key".to_string(), key.clone());
+    let mut hidden = 0;
+    for model in registry.list_models() {
+        if model.status == ModelStatus::Retired && !all && model.id != current.id {
+            hidden += 1;
+            continue;
         }
 
-        // Create directory if it doesn't exist
-        if let Some(parent) = self.config_path.parent() {
-            fs::create_dir_all(parent)?;
+        let marker = if model.id == current.id { "*" } else { " " };
+        let mut capabilities = Vec::new();
+        if model.supports(Capability::Vision) {
+            capabilities.push("vision");
+        }
+        if model.supports(Capability::Thinking) {
+            capabilities.push("thinking");
+        }
+        if model.supports(Capability::Tools) {
+            capabilities.push("tools");
         }
 
-        let config_str = serde_json::to_string(&config)?;
-        fs::write(&self.config_path, config_str)
+        let flag = match model.status {
+            ModelStatus::Deprecated => format!(
+                " [deprecated, retires {}]",
+                model.deprecated.as_deref().unwrap_or("soon")
+            ),
+            ModelStatus::Retired => " [retired]".to_string(),
+            _ => String::new(),
+        };
+
+        println!("{} {} ({}){}", marker, model.display_name, model.id, flag);
+        if !model.aliases.is_empty() {
+            println!("    aliases:      {}", model.aliases.join(", "));
+        }
+        println!(
+            "    context:      {} tokens, up to {} output",
+            model.context_window, model.max_output_tokens
+        );
+        if model.pricing.input == 0.0 && model.pricing.output == 0.0 {
+            println!("    pricing:      unknown (add it to pricing.json)");
+        } else {
+            println!(
+                "    pricing:      ${:.2} in / ${:.2} out per 1M tokens",
+                model.pricing.input, model.pricing.output
+            );
+        }
+        if !capabilities.is_empty() {
+            println!("    capabilities: {}", capabilities.join(", "));
+        }
     }
 
-    fn set_key(&mut self, key: String) -> io::Result<()> {
-        self.api_key = Some(key);
-        self.save()
+    if hidden > 0 {
+        println!(
+            "\n{} retired model(s) hidden, use --all to show them",
+            hidden
+        );
     }
 }
 
-async fn send_message(api_key: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    // visit here to verify which model was called https://console.anthropic.com/settings/logs
-    // sonnet is claude-3-5-sonnet-20241022
-    let request = ClaudeStreamApiRequest {
-        model: "claude-3-5-haiku-20241022".to_string(),
-        max_tokens: 1024,
-        messages: vec![ClaudeMessage {
-            role: "user".to_string(),
-            content: content.to_string(),
-        }],
-        stream: true,
-    };
+// Fetch the listing, check local ids the listing does not name directly, and cache it
+async fn refresh_models(
+    client: &ApiClient,
+    registry: &ModelRegistry,
+    cache_path: &std::path::Path,
+) -> Result<RemoteModelCache, Box<dyn std::error::Error>> {
+    let models = client.list_models().await?;
 
-    let mut response = client
-        .post("https://api.anthropic.com/v1/messages")
-        .header("x-api-key", api_key)
-        .header("anthropic-version", "2023-06-01")
-        .header("content-type", "application/json")
-        .json(&request)
-        .send()
-        .await?;
-
-    print!("🤖 "); // Claude emoji prompt
-    io::stdout().flush()?;
-
-    let mut stdout = io::stdout();
-
-    while let Some(chunk) = response.chunk().await? {
-        let chunk_str = String::from_utf8_lossy(&chunk);
-        for line in chunk_str.lines() {
-            if let Some(event) = parse_sse_line(line) {
-                match event {
-                    StreamEvent::ContentBlockDelta { delta, .. } => {
-                        stdout.write_all(delta.text.as_bytes())?;
-                        stdout.flush()?;
-                    }
-                    StreamEvent::MessageStop => {
-                        println!(); // New line after message is complete
-                        return Ok(());
-                    }
-                    _ => {} // Ignore other events
-                }
+    let mut resolved = HashMap::new();
+    let mut missing = Vec::new();
+    for local in registry.list_models() {
+        if models.iter().any(|m| m.id == local.id) {
+            continue;
+        }
+        match client.get_model(&local.id).await? {
+            Some(info) => {
+                resolved.insert(local.id.clone(), info.id);
             }
+            None => missing.push(local.id.clone()),
+        }
+    }
+
+    let cache = RemoteModelCache {
+        fetched_at: Utc::now(),
+        models,
+        resolved,
+        missing,
+    };
+    cache.save(cache_path)?;
+    Ok(cache)
+}
+
+fn print_templates(dirs: &[PathBuf]) {
+    let templates = templates::list(dirs);
+    if templates.is_empty() {
+        println!("No prompt templates found. Add Markdown files to:");
+        for dir in dirs {
+            println!("  {}", dir.display());
         }
+        return;
+    }
+
+    let width = templates.iter().map(|t| t.name.len()).max().unwrap_or(0);
+    for template in &templates {
+        let vars = template
+            .meta
+            .vars
+            .iter()
+            .map(|v| format!(" --var {}=...", v))
+            .collect::<String>();
+        println!(
+            "{:<width$}  {}{}",
+            template.name,
+            template.meta.description.as_deref().unwrap_or(""),
+            vars,
+            width = width
+        );
+    }
+}
+
+// Fill in a template, reading stdin into `{{stdin}}` or appending it like `ask`
+fn render_template(
+    template: &templates::PromptTemplate,
+    vars: &[String],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let vars = templates::parse_vars(vars)?;
+    template.check_vars(&vars)?;
+    if template.uses_stdin() {
+        let stdin = oneshot::read_piped_stdin()?;
+        return Ok(template.render(&vars, stdin.as_deref())?);
+    }
+
+    let rendered = template.render(&vars, None)?;
+    Ok(oneshot::build_prompt(Some(&rendered))?.unwrap_or_default())
+}
+
+fn print_usage_report(
+    config: &Config,
+    since: Option<&str>,
+    by: GroupBy,
+    json: bool,
+    csv: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let since = since.map(usage::parse_since).transpose()?;
+    let ledger = UsageLedger::new(config.get_usage_ledger_path());
+    let records = ledger.read(since)?;
+    let summaries = usage::summarize(&records, by);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+    } else if csv {
+        print!("{}", usage::render_csv(&summaries));
+    } else if summaries.is_empty() {
+        println!("No usage recorded yet.");
+    } else {
+        print!("{}", usage::render_table(&summaries, by));
     }
 
     Ok(())
@@ -130,7 +520,37 @@ async fn send_message(api_key: &str, content: &str) -> Result<(), Box<dyn std::e
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
-    let mut config = Config::new()?;
+    // Request flags go before ask and run too, but mean nothing to other subcommands
+    let request = match &cli.command {
+        Some(Commands::Ask { request, .. } | Commands::Run { request, .. }) => Some(request),
+        Some(_) if !cli.request.is_empty() => Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "sampling flags, --auto-continue and --output-format only apply to chat, -p, \
+                 ask and run, and --schema to -p, ask and run (batch submit and batch results \
+                 take their own after the subcommand)",
+            )
+            .exit(),
+        None if cli.print.is_none() && cli.request.schema.is_some() => Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--schema only applies to one-shot prompts: -p, ask and run",
+            )
+            .exit(),
+        _ => None,
+    };
+    let mut config = match Config::new() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(oneshot::EXIT_FAILED);
+        }
+    };
+    // A --profile flag applies to this run only and is never written back to config
+    let profile = cli
+        .profile
+        .clone()
+        .unwrap_or_else(|| config.profile.clone());
 
     match &cli.command {
         Some(Commands::SetKey { key }) => {
@@ -140,50 +560,335 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         Some(Commands::Status) => {
             println!("Available commands:");
-            println!("  setkey <key>    Set your Claude API key");
+            println!("  set-key <key>   Set your Claude API key");
             println!("  status          Show this status message");
+            println!("  usage           Report token usage and cost");
+            println!("  budget          Show or change spending limits");
+            println!("  models          List or choose models");
+            println!("  batch           Submit and manage message batches");
+            println!("  search <words>  Search saved conversations");
+            println!("  export <id>     Export a conversation as Markdown, HTML or JSON");
+            println!("  import claude-export <file>  Import a Claude.ai data export");
+            println!("  ask <prompt>    Answer one prompt and exit (same as -p)");
             println!("\nIn chat mode:");
             println!("  /quit           Exit the program");
             println!("  /help           Show help message");
+            println!("  /budget         Show remaining budget");
+            println!("  /set <key> <v>  Change a sampling parameter");
+            println!("  /params         Show sampling parameters");
             return Ok(());
         }
-        None => {}
+        Some(Commands::Usage {
+            since,
+            by,
+            json,
+            csv,
+        }) => {
+            return print_usage_report(&config, since.as_deref(), *by, *json, *csv);
+        }
+        Some(Commands::Search {
+            query,
+            model,
+            since,
+            until,
+            role,
+            limit,
+        }) => {
+            let mut search = SearchQuery::new(&query.join(" "));
+            search.model = model.clone();
+            search.since = since.as_deref().map(usage::parse_since).transpose()?;
+            search.until = until.as_deref().map(search::parse_until).transpose()?;
+            search.role = *role;
+            search.limit = *limit;
+            let store = ConversationStore::new(config.get_conversations_dir());
+            search::run(&store, &search, !tui::plain_output(), &mut io::stdout())?;
+            return Ok(());
+        }
+        Some(Commands::Import {
+            source: ImportSource::ClaudeExport { file },
+        }) => {
+            let store = ConversationStore::new(config.get_conversations_dir());
+            if let Err(e) = import::claude_export(file, &store, &mut io::stdout()) {
+                eprintln!("error: {}", e);
+                std::process::exit(oneshot::EXIT_FAILED);
+            }
+            return Ok(());
+        }
+        Some(Commands::Budget { action }) => {
+            if let Some(action) = action {
+                update_budget(&mut config, &profile, action)?;
+            }
+            let ledger = UsageLedger::new(config.get_usage_ledger_path());
+            budget::print_budget(
+                &BudgetTracker::new(config.budget.clone()),
+                &ledger,
+                &profile,
+                &mut io::stdout(),
+            )?;
+            return Ok(());
+        }
+        _ => {}
     }
 
-    if config.api_key.is_none() {
-        println!(
-            "No API key found. Please set your API key using: claude-cli setkey <your-api-key>"
-        );
-        return Ok(());
+    let pricing = PricingTable::load(&config.get_pricing_path())?;
+    let mut registry = ModelRegistry::load(&pricing, &config.get_model_registry_files())?;
+    let mut models_cache = RemoteModelCache::load(&config.get_models_cache_path())?;
+    if let Some(cache) = &models_cache {
+        registry.merge_remote(cache, &pricing);
     }
+    registry.load_from_config(&config.config_path)?;
 
-    println!("Claude CLI started. Type /quit to exit, /help for commands.");
+    if let Some(Commands::Models { action }) = &cli.command {
+        match action {
+            Some(ModelsAction::Use { model }) => {
+                let id = registry.select_model(model)?.id.clone();
+                config.set_model(id)?;
+                println!(
+                    "Default model set to {}.",
+                    registry.get_current_model().display_name
+                );
+            }
+            Some(ModelsAction::Refresh) => {
+                let Some(api_key) = config.api_key.clone() else {
+                    println!("No API key found. Please set your API key using: agent set-key <your-api-key>");
+                    return Ok(());
+                };
+                let cache = refresh_models(
+                    &ApiClient::new(api_key),
+                    &registry,
+                    &config.get_models_cache_path(),
+                )
+                .await?;
+                println!(
+                    "Fetched {} models available to this key.",
+                    cache.models.len()
+                );
+                registry.merge_remote(&cache, &pricing);
+                models_cache = Some(cache);
+                print_models(&registry, models_cache.as_ref(), false);
+            }
+            Some(ModelsAction::List { all }) => {
+                print_models(&registry, models_cache.as_ref(), *all)
+            }
+            None => print_models(&registry, models_cache.as_ref(), false),
+        }
+        return Ok(());
+    }
 
-    loop {
-        print!("👤 "); // Human emoji prompt
-        io::stdout().flush()?;
+    if let Some(Commands::Export { id, format, output }) = &cli.command {
+        let store = ConversationStore::new(config.get_conversations_dir());
+        let conversation = match store.load(id) {
+            Ok(conversation) => conversation,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(oneshot::EXIT_USAGE);
+            }
+        };
+        let contents = export::export(&conversation, &registry, *format);
+        match output {
+            Some(path) => {
+                std::fs::write(path, &contents)?;
+                println!("Exported {} to {}.", conversation.id, path.display());
+            }
+            None => print!("{}", contents),
+        }
+        return Ok(());
+    }
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let input = input.trim();
+    let mut cli_params = cli.request.sampling.to_params();
+    if let Some(request) = request {
+        cli_params.merge(&request.sampling.to_params());
+    }
+    let mut params = config.sampling.clone();
+    params.merge(&cli_params);
+    let max_continuations = request
+        .and_then(|request| request.auto_continue)
+        .or(cli.request.auto_continue)
+        .unwrap_or(config.max_continuations);
+    let output_format = request
+        .and_then(|request| request.output_format)
+        .or(cli.request.output_format);
+    let schema = request
+        .and_then(|request| request.schema.as_deref())
+        .or(cli.request.schema.as_deref());
 
-        match input {
-            "/quit" => break,
-            "/help" => {
-                println!("Available commands:");
-                println!("  /quit           Exit the program");
-                println!("  /help           Show this help message");
-                continue;
+    let template = match &cli.command {
+        Some(Commands::Run { name: None, .. }) => {
+            print_templates(&config.get_prompt_dirs());
+            return Ok(());
+        }
+        Some(Commands::Run {
+            name: Some(name), ..
+        }) => match templates::find(name, &config.get_prompt_dirs()) {
+            Ok(template) => Some(template),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(oneshot::EXIT_USAGE);
             }
-            "" => continue,
-            _ => match send_message(config.api_key.as_ref().unwrap(), input).await {
-                Ok(()) => {}
-                Err(e) => {
-                    println!("Error: {}", e);
+        },
+        _ => None,
+    };
+
+    let one_shot = match &cli.command {
+        Some(Commands::Ask { prompt, .. }) => Some(prompt.join(" ")),
+        Some(Commands::Run { .. }) => Some(String::new()),
+        _ => cli.print.clone(),
+    };
+    if let Some(prompt) = one_shot {
+        let Some(api_key) = config.api_key.clone() else {
+            eprintln!("error: no API key found, set one with: agent set-key <your-api-key>");
+            std::process::exit(oneshot::EXIT_USAGE);
+        };
+
+        // A template's front matter sits between the config and the command line
+        let mut model = registry.get_current_model();
+        let mut system = None;
+        let prompt = match (&template, &cli.command) {
+            (Some(template), Some(Commands::Run { vars, .. })) => {
+                if let Some(name) = &template.meta.model {
+                    let Some(found) = registry.get(name) else {
+                        eprintln!("error: {} asks for unknown model '{}'", template.name, name);
+                        std::process::exit(oneshot::EXIT_USAGE);
+                    };
+                    model = found;
                 }
-            },
+                system = template.meta.system.clone();
+                params = config.sampling.clone();
+                params.merge(&SamplingParams {
+                    temperature: template.meta.temperature,
+                    max_tokens: template.meta.max_tokens,
+                    ..Default::default()
+                });
+                params.merge(&cli_params);
+
+                match render_template(template, vars) {
+                    Ok(prompt) => Some(prompt).filter(|p| !p.is_empty()),
+                    Err(e) => {
+                        eprintln!("error: {}", e);
+                        std::process::exit(oneshot::EXIT_USAGE);
+                    }
+                }
+            }
+            _ => oneshot::build_prompt(Some(&prompt))?,
+        };
+        let Some(prompt) = prompt else {
+            eprintln!("error: no prompt given and nothing piped on stdin");
+            std::process::exit(oneshot::EXIT_USAGE);
+        };
+
+        let schema = match schema.map(OutputSchema::load).transpose() {
+            Ok(schema) => schema,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(oneshot::EXIT_USAGE);
+            }
+        };
+
+        let ctx = oneshot::OneShotContext {
+            client: ApiClient::new(api_key),
+            config: &config,
+            model,
+            system,
+            params: &params,
+            profile: &profile,
+            max_continuations,
+            format: output_format.unwrap_or(OutputFormat::Text),
+            schema: schema.as_ref(),
+        };
+        std::process::exit(oneshot::run(&ctx, &prompt).await);
+    }
+
+    if config.api_key.is_none() {
+        println!("No API key found. Please set your API key using: agent set-key <your-api-key>");
+        return Ok(());
+    }
+
+    if let Some(Commands::Batch { action }) = &cli.command {
+        let mut batch_params = params.clone();
+        let format = match action {
+            BatchAction::Submit {
+                sampling,
+                output_format,
+                ..
+            } => {
+                batch_params.merge(&sampling.to_params());
+                *output_format
+            }
+            BatchAction::Results { output_format, .. } => *output_format,
+            _ => None,
+        };
+        let ctx = batch::BatchContext {
+            client: ApiClient::new(config.api_key.clone().unwrap()),
+            config: &config,
+            registry: &registry,
+            params: &batch_params,
+            profile: &profile,
+            format,
+        };
+        return match action {
+            BatchAction::Submit {
+                file, wait, output, ..
+            } => batch::submit(&ctx, file, *wait, output.as_deref()).await,
+            BatchAction::Status { batch_id, wait } => {
+                batch::status(&ctx, batch_id.as_deref(), *wait).await
+            }
+            BatchAction::Results {
+                batch_id, output, ..
+            } => batch::results(&ctx, batch_id.as_deref(), output.as_deref()).await,
+            BatchAction::Cancel { batch_id } => batch::cancel(&ctx, batch_id.as_deref()).await,
+        };
+    }
+
+    params.validate(registry.get_current_model())?;
+
+    let store = ConversationStore::new(config.get_conversations_dir());
+    let conversation = match &cli.resume {
+        Some(id) => match store.load(id) {
+            Ok(conversation) => conversation,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(oneshot::EXIT_USAGE);
+            }
+        },
+        None => Conversation::new(),
+    };
+
+    let mut ui = TerminalUi::new()?;
+    ui.set_model(registry.get_current_model());
+    if let Some(name) = &config.theme {
+        match theme::load(name, &config.get_themes_dir()) {
+            Ok(style) => ui.style = style,
+            Err(e) => eprintln!("Warning: {}", e),
         }
     }
 
-    Ok(())
+    // The full-screen chat needs a terminal to draw on
+    let full_screen = (cli.tui || config.tui_enabled) && io::stdin().is_terminal() && !ui.plain;
+
+    if !full_screen {
+        ui.init_conversation()?;
+    }
+
+    let commands = CommandRegistry::load(&config.get_command_dirs());
+    let session = Session {
+        client: ApiClient::new(config.api_key.clone().unwrap()),
+        ledger: UsageLedger::new(config.get_usage_ledger_path()),
+        budget: BudgetTracker::new(config.budget.clone()),
+        config,
+        registry,
+        params,
+        profile,
+        max_continuations,
+        ui,
+        truncated: None,
+        restore_model: None,
+        conversation,
+        store,
+        full_screen,
+    };
+    if full_screen {
+        chat::run(session, commands).await
+    } else {
+        repl::run(session, commands).await
+    }
 }
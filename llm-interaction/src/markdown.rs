@@ -0,0 +1,737 @@
+use pulldown_cmark::{Alignment, CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// Theme for fenced code blocks
+const CODE_THEME: &str = "base16-ocean.dark";
+
+// Columns between tab stops in code blocks, as `tui::wrap` expands tabs
+const TAB_WIDTH: usize = 4;
+
+// Loading syntaxes takes a while, so it's done once
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn code_theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults().themes;
+        themes.remove(CODE_THEME).unwrap_or_default()
+    })
+}
+
+fn heading_style(level: HeadingLevel) -> Style {
+    let style = Style::new().add_modifier(Modifier::BOLD);
+    match level {
+        HeadingLevel::H1 => style.fg(Color::Magenta).add_modifier(Modifier::UNDERLINED),
+        HeadingLevel::H2 => style.fg(Color::Cyan),
+        _ => style,
+    }
+}
+
+fn dim() -> Style {
+    Style::new().fg(Color::DarkGray)
+}
+
+// Render Markdown as lines at most `width` columns wide. Text that is still
+// streaming in renders as far as it goes: an unclosed code fence is shown
+// as code, a half-written table as plain text until its header is complete.
+pub fn render(text: &str, width: usize) -> Vec<Line<'static>> {
+    let width = width.max(8);
+    let mut renderer = Renderer {
+        width,
+        lines: Vec::new(),
+        spans: Vec::new(),
+        styles: vec![Style::new()],
+        containers: Vec::new(),
+        marker: None,
+        link: None,
+        code: None,
+        code_blocks: 0,
+        table: None,
+    };
+
+    for event in Parser::new_ext(text, options()) {
+        renderer.event(event);
+    }
+    renderer.flush();
+
+    while renderer.lines.last().is_some_and(|l| l.width() == 0) {
+        renderer.lines.pop();
+    }
+    renderer.lines
+}
+
+enum Container {
+    Quote,
+    // Next number for ordered lists
+    List(Option<u64>),
+    // A list item's text is indented past its marker
+    Item(usize),
+}
+
+pub struct CodeBlock {
+    pub lang: String,
+    pub text: String,
+}
+
+// The code blocks in `text`, in the order `render` numbers them from 1
+pub fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current: Option<CodeBlock> = None;
+    for event in Parser::new_ext(text, options()) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                current = Some(CodeBlock {
+                    lang: code_lang(&kind),
+                    text: String::new(),
+                })
+            }
+            Event::Text(text) => {
+                if let Some(block) = current.as_mut() {
+                    block.text.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(current.take()),
+            _ => {}
+        }
+    }
+    blocks
+}
+
+pub fn options() -> Options {
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH
+}
+
+fn code_lang(kind: &CodeBlockKind) -> String {
+    match kind {
+        CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or("").to_string(),
+        CodeBlockKind::Indented => String::new(),
+    }
+}
+
+struct Table {
+    alignments: Vec<Alignment>,
+    rows: Vec<Vec<Vec<Span<'static>>>>,
+    row: Vec<Vec<Span<'static>>>,
+}
+
+struct Renderer {
+    width: usize,
+    lines: Vec<Line<'static>>,
+    // Inline text of the block being built
+    spans: Vec<Span<'static>>,
+    styles: Vec<Style>,
+    containers: Vec<Container>,
+    // List marker for the first line of the current item
+    marker: Option<String>,
+    // Destination of the link being read
+    link: Option<String>,
+    code: Option<CodeBlock>,
+    // Code blocks rendered so far, for their numbers
+    code_blocks: usize,
+    table: Option<Table>,
+}
+
+impl Renderer {
+    fn style(&self) -> Style {
+        *self.styles.last().unwrap()
+    }
+
+    fn push_style(&mut self, style: Style) {
+        self.styles.push(self.style().patch(style));
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some(code) = self.code.as_mut() {
+            code.text.push_str(text);
+        } else {
+            self.spans
+                .push(Span::styled(text.to_string(), self.style()));
+        }
+    }
+
+    // Blank line between blocks, but not inside tight lists
+    fn gap(&mut self) {
+        let in_item = self
+            .containers
+            .iter()
+            .any(|c| matches!(c, Container::Item(_)));
+        if !in_item && self.lines.last().is_some_and(|l| l.width() > 0) {
+            let prefix = self.prefix(false);
+            self.lines.push(Line::from(prefix));
+        }
+    }
+
+    // What goes before each line: quote bars and list indentation. The
+    // first line of a list item gets its marker instead.
+    fn prefix(&mut self, first: bool) -> Vec<Span<'static>> {
+        let mut prefix = Vec::new();
+        let last_item = self
+            .containers
+            .iter()
+            .rposition(|c| matches!(c, Container::Item(_)));
+        for (i, container) in self.containers.iter().enumerate() {
+            match container {
+                Container::Quote => prefix.push(Span::styled("│ ", dim())),
+                Container::Item(indent) => {
+                    let marker = if first && Some(i) == last_item {
+                        self.marker.take()
+                    } else {
+                        None
+                    };
+                    match marker {
+                        Some(marker) => {
+                            prefix.push(Span::styled(marker, Style::new().fg(Color::Cyan)))
+                        }
+                        None => prefix.push(Span::raw(" ".repeat(*indent))),
+                    }
+                }
+                Container::List(_) => {}
+            }
+        }
+        prefix
+    }
+
+    fn prefix_width(&self) -> usize {
+        self.containers
+            .iter()
+            .map(|c| match c {
+                Container::Quote => 2,
+                Container::Item(indent) => *indent,
+                Container::List(_) => 0,
+            })
+            .sum()
+    }
+
+    // Wrap the pending inline text into lines
+    fn flush(&mut self) {
+        if self.spans.is_empty() {
+            return;
+        }
+        let spans = std::mem::take(&mut self.spans);
+        let width = self.width.saturating_sub(self.prefix_width()).max(1);
+        for (i, row) in wrap_spans(&spans, width).into_iter().enumerate() {
+            let mut line = self.prefix(i == 0);
+            line.extend(row);
+            self.lines.push(Line::from(line));
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => self.text(&text),
+            Event::Code(code) => self.spans.push(Span::styled(
+                code.to_string(),
+                self.style().fg(Color::Yellow),
+            )),
+            Event::SoftBreak => self.text(" "),
+            Event::HardBreak => self.spans.push(Span::raw("\n")),
+            Event::Rule => {
+                self.flush();
+                self.gap();
+                let width = self.width.saturating_sub(self.prefix_width());
+                let mut line = self.prefix(true);
+                line.push(Span::styled("─".repeat(width), dim()));
+                self.lines.push(Line::from(line));
+            }
+            Event::Html(html) | Event::InlineHtml(html) => self.text(&html),
+            Event::TaskListMarker(done) => self.text(if done { "[x] " } else { "[ ] " }),
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph => {
+                self.flush();
+                self.gap();
+            }
+            Tag::Heading { level, .. } => {
+                self.flush();
+                self.gap();
+                self.push_style(heading_style(level));
+            }
+            Tag::BlockQuote(_) => {
+                self.flush();
+                self.gap();
+                self.containers.push(Container::Quote);
+                self.push_style(Style::new().add_modifier(Modifier::ITALIC));
+            }
+            Tag::CodeBlock(kind) => {
+                self.flush();
+                self.gap();
+                self.code = Some(CodeBlock {
+                    lang: code_lang(&kind),
+                    text: String::new(),
+                });
+            }
+            Tag::List(start) => {
+                self.flush();
+                if !self
+                    .containers
+                    .iter()
+                    .any(|c| matches!(c, Container::Item(_)))
+                {
+                    self.gap();
+                }
+                self.containers.push(Container::List(start));
+            }
+            Tag::Item => {
+                self.flush();
+                let marker = match self.containers.last_mut() {
+                    Some(Container::List(Some(n))) => {
+                        *n += 1;
+                        format!("{}. ", *n - 1)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.containers.push(Container::Item(marker.width()));
+                self.marker = Some(marker);
+            }
+            Tag::Emphasis => self.push_style(Style::new().add_modifier(Modifier::ITALIC)),
+            Tag::Strong => self.push_style(Style::new().add_modifier(Modifier::BOLD)),
+            Tag::Strikethrough => self.push_style(Style::new().add_modifier(Modifier::CROSSED_OUT)),
+            Tag::Link { dest_url, .. } => {
+                self.link = Some(dest_url.to_string());
+                self.push_style(
+                    Style::new()
+                        .fg(Color::Blue)
+                        .add_modifier(Modifier::UNDERLINED),
+                );
+            }
+            Tag::Image { .. } => {
+                self.text("[image: ");
+            }
+            Tag::Table(alignments) => {
+                self.flush();
+                self.gap();
+                self.table = Some(Table {
+                    alignments,
+                    rows: Vec::new(),
+                    row: Vec::new(),
+                });
+            }
+            Tag::TableHead => self.push_style(Style::new().add_modifier(Modifier::BOLD)),
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph => self.flush(),
+            TagEnd::Heading(_) => {
+                self.flush();
+                self.styles.pop();
+            }
+            TagEnd::BlockQuote(_) => {
+                self.flush();
+                self.containers.pop();
+                self.styles.pop();
+            }
+            TagEnd::CodeBlock => {
+                if let Some(code) = self.code.take() {
+                    self.code_block(&code);
+                }
+            }
+            TagEnd::List(_) => {
+                self.flush();
+                self.containers.pop();
+            }
+            TagEnd::Item => {
+                self.flush();
+                // An empty item still shows its marker
+                if self.marker.is_some() {
+                    let line = self.prefix(true);
+                    self.lines.push(Line::from(line));
+                }
+                self.containers.pop();
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => {
+                self.styles.pop();
+            }
+            TagEnd::Link => {
+                self.styles.pop();
+                if let Some(url) = self.link.take() {
+                    let shown: String = self.spans.iter().map(|s| s.content.as_ref()).collect();
+                    if !shown.ends_with(&url) {
+                        self.spans.push(Span::styled(format!(" ({})", url), dim()));
+                    }
+                }
+            }
+            TagEnd::Image => self.text("]"),
+            TagEnd::TableCell => {
+                let cell = std::mem::take(&mut self.spans);
+                if let Some(table) = self.table.as_mut() {
+                    table.row.push(cell);
+                }
+            }
+            TagEnd::TableHead | TagEnd::TableRow => {
+                if matches!(tag, TagEnd::TableHead) {
+                    self.styles.pop();
+                }
+                if let Some(table) = self.table.as_mut() {
+                    let row = std::mem::take(&mut table.row);
+                    table.rows.push(row);
+                }
+            }
+            TagEnd::Table => {
+                if let Some(table) = self.table.take() {
+                    self.table_block(table);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Highlighted code with its whitespace kept; long lines are broken at
+    // the edge rather than at spaces. The header carries the number /copy
+    // and /save refer to.
+    fn code_block(&mut self, code: &CodeBlock) {
+        self.code_blocks += 1;
+        let width = self.width.saturating_sub(self.prefix_width()).max(1);
+        let mut header = self.prefix(true);
+        let label = if code.lang.is_empty() {
+            "code"
+        } else {
+            &code.lang
+        };
+        header.push(Span::styled(
+            format!("─── [{}] {} ", self.code_blocks, label),
+            dim(),
+        ));
+        self.lines.push(Line::from(header));
+
+        let syntaxes = syntaxes();
+        let syntax = (!code.lang.is_empty())
+            .then(|| syntaxes.find_syntax_by_token(&code.lang))
+            .flatten();
+        let mut highlighter = syntax.map(|s| HighlightLines::new(s, code_theme()));
+
+        for line in LinesWithEndings::from(&code.text) {
+            let line = expand_tabs(line);
+            let spans: Vec<Span<'static>> = match highlighter
+                .as_mut()
+                .and_then(|h| h.highlight_line(&line, syntaxes).ok())
+            {
+                Some(ranges) => ranges
+                    .into_iter()
+                    .map(|(style, text)| {
+                        let fg = style.foreground;
+                        let mut s = Style::new().fg(Color::Rgb(fg.r, fg.g, fg.b));
+                        if style.font_style.contains(FontStyle::BOLD) {
+                            s = s.add_modifier(Modifier::BOLD);
+                        }
+                        Span::styled(text.trim_end_matches('\n').to_string(), s)
+                    })
+                    .collect(),
+                None => vec![Span::styled(
+                    line.trim_end_matches('\n').to_string(),
+                    Style::new().fg(Color::Yellow),
+                )],
+            };
+            for row in break_spans(spans, width) {
+                let mut line = self.prefix(false);
+                line.extend(row);
+                self.lines.push(Line::from(line));
+            }
+        }
+    }
+
+    fn table_block(&mut self, table: Table) {
+        let columns = table.rows.iter().map(Vec::len).max().unwrap_or(0);
+        if columns == 0 {
+            return;
+        }
+        let cell_width = |cell: &Vec<Span>| cell.iter().map(|s| s.width()).sum::<usize>();
+        let mut widths: Vec<usize> = (0..columns)
+            .map(|c| {
+                table
+                    .rows
+                    .iter()
+                    .filter_map(|row| row.get(c))
+                    .map(cell_width)
+                    .max()
+                    .unwrap_or(0)
+                    .max(1)
+            })
+            .collect();
+
+        // Narrow the widest columns until the table fits; cells then wrap
+        let available = self
+            .width
+            .saturating_sub(self.prefix_width() + 3 * (columns - 1));
+        while widths.iter().sum::<usize>() > available {
+            let widest = (0..columns).max_by_key(|&c| widths[c]).unwrap();
+            if widths[widest] <= 3 {
+                break;
+            }
+            widths[widest] -= 1;
+        }
+
+        for (r, row) in table.rows.iter().enumerate() {
+            let cells: Vec<Vec<Vec<Span<'static>>>> = (0..columns)
+                .map(|c| match row.get(c) {
+                    Some(cell) => wrap_spans(cell, widths[c]),
+                    None => vec![Vec::new()],
+                })
+                .collect();
+            let height = cells.iter().map(Vec::len).max().unwrap_or(1);
+
+            for i in 0..height {
+                let mut line = self.prefix(false);
+                for (c, cell) in cells.iter().enumerate() {
+                    if c > 0 {
+                        line.push(Span::styled(" │ ", dim()));
+                    }
+                    let content = cell.get(i).cloned().unwrap_or_default();
+                    let padding = widths[c].saturating_sub(content.iter().map(|s| s.width()).sum());
+                    let (left, right) = match table.alignments.get(c) {
+                        Some(Alignment::Right) => (padding, 0),
+                        Some(Alignment::Center) => (padding / 2, padding - padding / 2),
+                        _ => (0, padding),
+                    };
+                    line.push(Span::raw(" ".repeat(left)));
+                    line.extend(content);
+                    line.push(Span::raw(" ".repeat(right)));
+                }
+                self.lines.push(Line::from(line));
+            }
+
+            // Rule under the header
+            if r == 0 {
+                let mut line = self.prefix(false);
+                let rule = widths
+                    .iter()
+                    .map(|w| "─".repeat(*w))
+                    .collect::<Vec<_>>()
+                    .join("─┼─");
+                line.push(Span::styled(rule, dim()));
+                self.lines.push(Line::from(line));
+            }
+        }
+    }
+}
+
+// Tabs as spaces up to the next tab stop, since the terminal's own would
+// ignore the prefix and the wrapping
+fn expand_tabs(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = TAB_WIDTH - column % TAB_WIDTH;
+            out.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
+        } else {
+            out.push(c);
+            column += c.width().unwrap_or(0);
+        }
+    }
+    out
+}
+
+// Word-wrap styled text to `width` columns. Runs of whitespace become one
+// space, "\n" spans force a break and words too long for a line are split.
+fn wrap_spans(spans: &[Span<'static>], width: usize) -> Vec<Vec<Span<'static>>> {
+    let mut lines = Vec::new();
+    let mut line: Vec<Span<'static>> = Vec::new();
+    let mut line_width = 0;
+    let mut space = false;
+
+    for span in spans {
+        if span.content == "\n" {
+            lines.push(std::mem::take(&mut line));
+            line_width = 0;
+            space = false;
+            continue;
+        }
+
+        let mut rest = span.content.as_ref();
+        while !rest.is_empty() {
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            if word_end == 0 {
+                let spaces = rest.len() - rest.trim_start().len();
+                rest = &rest[spaces..];
+                space = line_width > 0;
+                continue;
+            }
+            let mut word = &rest[..word_end];
+            rest = &rest[word_end..];
+
+            let gap = usize::from(space);
+            if line_width > 0 && line_width + gap + word.width() > width {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+            } else if space {
+                line.push(Span::styled(" ", span.style));
+                line_width += 1;
+            }
+            space = false;
+
+            // Split words wider than a whole line, between graphemes so wide
+            // and combined characters stay whole
+            while line_width + word.width() > width {
+                let mut end = 0;
+                let mut w = line_width;
+                for (i, grapheme) in word.grapheme_indices(true) {
+                    let gw = grapheme.width();
+                    if w + gw > width {
+                        break;
+                    }
+                    w += gw;
+                    end = i + grapheme.len();
+                }
+                if end == 0 {
+                    if line.is_empty() {
+                        // Not even one grapheme fits; take it anyway
+                        end = word.graphemes(true).next().map_or(word.len(), str::len);
+                    } else {
+                        lines.push(std::mem::take(&mut line));
+                        line_width = 0;
+                        continue;
+                    }
+                }
+                line.push(Span::styled(word[..end].to_string(), span.style));
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+                word = &word[end..];
+            }
+            if !word.is_empty() {
+                line.push(Span::styled(word.to_string(), span.style));
+                line_width += word.width();
+            }
+        }
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+// Break styled text into rows of `width` columns exactly where they fill,
+// keeping every space and never splitting a grapheme
+fn break_spans(spans: Vec<Span<'static>>, width: usize) -> Vec<Vec<Span<'static>>> {
+    let mut rows = vec![Vec::new()];
+    let mut row_width = 0;
+
+    for span in spans {
+        let mut piece = String::new();
+        for grapheme in span.content.graphemes(true) {
+            let gw = grapheme.width();
+            if row_width + gw > width && row_width > 0 {
+                if !piece.is_empty() {
+                    rows.last_mut()
+                        .unwrap()
+                        .push(Span::styled(std::mem::take(&mut piece), span.style));
+                }
+                rows.push(Vec::new());
+                row_width = 0;
+            }
+            piece.push_str(grapheme);
+            row_width += gw;
+        }
+        if !piece.is_empty() {
+            rows.last_mut()
+                .unwrap()
+                .push(Span::styled(piece, span.style));
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use proptest::prelude::*;
+    use ratatui::text::Line;
+    use unicode_segmentation::UnicodeSegmentation;
+
+    // Words mixing ASCII, CJK, emoji (including ZWJ sequences and
+    // modifiers) and combining marks
+    fn word() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-z]{1,12}",
+            "[一-龥]{1,6}",
+            "[ぁ-ゖ]{1,6}",
+            Just("😀".to_string()),
+            Just("👩‍👩‍👧".to_string()),
+            Just("👍🏽".to_string()),
+            Just("e\u{301}".to_string()),
+            Just("한국어".to_string()),
+        ]
+    }
+
+    // Runs of words without spaces, some too long for a line
+    fn paragraph() -> impl Strategy<Value = String> {
+        let run = prop::collection::vec(word(), 1..6).prop_map(|words| words.concat());
+        prop::collection::vec(run, 1..30).prop_map(|runs| runs.join(" "))
+    }
+
+    // The inside of a code block, with its spacing and tabs
+    fn code() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            word(),
+            Just(" ".to_string()),
+            Just("    ".to_string()),
+            Just("\t".to_string()),
+            Just("\n".to_string()),
+        ];
+        prop::collection::vec(piece, 0..40).prop_map(|pieces| pieces.concat())
+    }
+
+    fn text(line: &Line) -> String {
+        line.spans
+            .iter()
+            .map(|span| span.content.as_ref())
+            .collect()
+    }
+
+    fn visible_graphemes(text: &str) -> Vec<String> {
+        text.graphemes(true)
+            .filter(|g| !g.trim().is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    // Taken line by line, so a grapheme split across two lines shows
+    fn rendered_graphemes(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .flat_map(|line| visible_graphemes(&text(line)))
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn lines_fit_the_width(p in paragraph(), code in code(), width in 16usize..60) {
+            let markdown = format!("# {p}\n\n{p}\n\n> {p}\n\n- {p}\n\n```\n{code}\n```\n");
+            for line in render(&markdown, width) {
+                prop_assert!(line.width() <= width, "{:?} is wider than {}", text(&line), width);
+            }
+        }
+
+        #[test]
+        fn paragraphs_keep_every_grapheme(p in paragraph(), width in 8usize..40) {
+            prop_assert_eq!(rendered_graphemes(&render(&p, width)), visible_graphemes(&p));
+        }
+
+        #[test]
+        fn code_keeps_every_grapheme(code in code(), width in 8usize..40) {
+            let lines = render(&format!("```\n{}\n```", code), width);
+            // After the header with the block's number
+            prop_assert_eq!(rendered_graphemes(&lines[1..]), visible_graphemes(&code));
+        }
+    }
+}
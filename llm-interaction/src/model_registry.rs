@@ -1,24 +1,220 @@
+use crate::modules::{ClaudeUsage, ModelInfo};
+use crate::pricing::{ModelPricing, PricingTable};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+const BUILTIN_MODELS: &str = include_str!("../models.json");
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    #[serde(default)]
+    pub vision: bool,
+    #[serde(default)]
+    pub thinking: bool,
+    #[serde(default)]
+    pub tools: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Vision,
+    Thinking,
+    Tools,
+}
+
+// Availability as last reported by the Models API (see `agent models refresh`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelStatus {
+    // No refresh has been run, or the model is not known to the API listing
+    #[default]
+    Unknown,
+    Available,
+    // Still served, but the registry marks it with a retirement date
+    Deprecated,
+    // Known locally but no longer served to this key
+    Retired,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LlmModel {
     pub id: String,
     pub display_name: String,
-    pub input_cost: f64,  // Cost per 1M input tokens in USD
-    pub output_cost: f64, // Cost per 1M output tokens in USD
+    pub aliases: Vec<String>,
+    pub provider: String,
+    pub pricing: ModelPricing,
+    pub context_window: u32,
+    pub max_output_tokens: u32,
     pub default_max_tokens: u32,
+    pub capabilities: Capabilities,
     pub description: String,
+    pub deprecated: Option<String>,
+    #[serde(default)]
+    pub status: ModelStatus,
 }
 
 impl LlmModel {
-    pub fn calculate_cost(&self, input_tokens: u32, output_tokens: u32) -> f64 {
-        let input_cost = (input_tokens as f64 / 1_000_000.0) * self.input_cost;
-        let output_cost = (output_tokens as f64 / 1_000_000.0) * self.output_cost;
-        input_cost + output_cost
+    pub fn calculate_cost(&self, usage: &ClaudeUsage) -> f64 {
+        self.pricing.cost(usage)
+    }
+
+    pub fn calculate_batch_cost(&self, usage: &ClaudeUsage) -> f64 {
+        self.calculate_cost(usage) * (1.0 - self.pricing.batch_discount.unwrap_or(0.0))
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Vision => self.capabilities.vision,
+            Capability::Thinking => self.capabilities.thinking,
+            Capability::Tools => self.capabilities.tools,
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.id == name || self.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+    }
+}
+
+// Layout of models.json / models.toml. Every field except `id` is optional so a
+// user file can patch a single field of a built-in model or add a new one.
+#[derive(Debug, Default, Deserialize)]
+struct RegistryFile {
+    default: Option<String>,
+    #[serde(default)]
+    models: Vec<ModelEntry>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ModelEntry {
+    id: String,
+    display_name: Option<String>,
+    aliases: Option<Vec<String>>,
+    provider: Option<String>,
+    pricing: Option<ModelPricing>,
+    context_window: Option<u32>,
+    max_output_tokens: Option<u32>,
+    default_max_tokens: Option<u32>,
+    capabilities: Option<Capabilities>,
+    description: Option<String>,
+    // Retirement date announced for the model, e.g. "2025-10-22"
+    deprecated: Option<String>,
+}
+
+impl ModelEntry {
+    fn merge(&mut self, other: ModelEntry) {
+        if other.display_name.is_some() {
+            self.display_name = other.display_name;
+        }
+        if other.aliases.is_some() {
+            self.aliases = other.aliases;
+        }
+        if other.provider.is_some() {
+            self.provider = other.provider;
+        }
+        if other.pricing.is_some() {
+            self.pricing = other.pricing;
+        }
+        if other.context_window.is_some() {
+            self.context_window = other.context_window;
+        }
+        if other.max_output_tokens.is_some() {
+            self.max_output_tokens = other.max_output_tokens;
+        }
+        if other.default_max_tokens.is_some() {
+            self.default_max_tokens = other.default_max_tokens;
+        }
+        if other.capabilities.is_some() {
+            self.capabilities = other.capabilities;
+        }
+        if other.description.is_some() {
+            self.description = other.description;
+        }
+        if other.deprecated.is_some() {
+            self.deprecated = other.deprecated;
+        }
+    }
+
+    // Prices written inline in a registry file win over the pricing table.
+    // Either way the table's batch discount applies unless they set their own.
+    fn into_model(self, pricing: &PricingTable) -> LlmModel {
+        let mut model_pricing = self
+            .pricing
+            .or_else(|| pricing.get(&self.id).cloned())
+            .unwrap_or_default();
+        model_pricing.batch_discount = Some(pricing.batch_discount_for(&model_pricing));
+        let max_output_tokens = self.max_output_tokens.unwrap_or(4096);
+
+        LlmModel {
+            display_name: self.display_name.unwrap_or_else(|| self.id.clone()),
+            aliases: self.aliases.unwrap_or_default(),
+            provider: self.provider.unwrap_or_else(|| "anthropic".to_string()),
+            pricing: model_pricing,
+            context_window: self.context_window.unwrap_or(200_000),
+            max_output_tokens,
+            default_max_tokens: self
+                .default_max_tokens
+                .unwrap_or(max_output_tokens.min(4096)),
+            capabilities: self.capabilities.unwrap_or_default(),
+            description: self.description.unwrap_or_default(),
+            deprecated: self.deprecated,
+            status: ModelStatus::Unknown,
+            id: self.id,
+        }
+    }
+}
+
+fn parse_registry_file(path: &Path) -> io::Result<RegistryFile> {
+    let contents = fs::read_to_string(path)?;
+    let parsed = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    };
+
+    parsed.map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid model registry {}: {}", path.display(), e),
+        )
+    })
+}
+
+// Local copy of the Models API listing written by `agent models refresh`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteModelCache {
+    pub fetched_at: DateTime<Utc>,
+    pub models: Vec<ModelInfo>,
+    // Local ids the API resolved to another id, e.g. `-latest` aliases
+    #[serde(default)]
+    pub resolved: HashMap<String, String>,
+    // Local ids the API did not recognise
+    #[serde(default)]
+    pub missing: Vec<String>,
+}
+
+impl RemoteModelCache {
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).map(Some).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid models cache {}: {}", path.display(), e),
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
     }
 }
 
@@ -29,51 +225,127 @@ pub struct ModelRegistry {
 
 impl ModelRegistry {
     pub fn new() -> Self {
-        let mut models = HashMap::new();
-
-        // Define available models
-        let haiku = LlmModel {
-            id: "claude-3-5-haiku-latest".to_string(),
-            display_name: "Claude 3.5 Haiku".to_string(),
-            input_cost: 1_000.0,  // $1.00 per 1M input tokens
-            output_cost: 5_000.0, // $5.00 per 1M output tokens
-            default_max_tokens: 2048,
-            description: "Fast and efficient model for everyday tasks.".to_string(),
-        };
+        Self::with_pricing(&PricingTable::builtin())
+    }
 
-        let sonnet = LlmModel {
-            id: "claude-3-7-sonnet-latest".to_string(),
-            display_name: "Claude 3.7 Sonnet".to_string(),
-            input_cost: 5_000.0,   // $5.00 per 1M input tokens
-            output_cost: 20_000.0, // $20.00 per 1M output tokens
-            default_max_tokens: 4096,
-            description: "Powerful model with advanced reasoning capabilities.".to_string(),
-        };
+    pub fn with_pricing(pricing: &PricingTable) -> Self {
+        Self::load(pricing, &[]).expect("built-in models.json is valid")
+    }
+
+    // Built-in models merged, in order, with any of `user_files` that exist
+    pub fn load(pricing: &PricingTable, user_files: &[PathBuf]) -> io::Result<Self> {
+        let builtin: RegistryFile =
+            serde_json::from_str(BUILTIN_MODELS).expect("built-in models.json is valid");
+
+        let mut default_model = builtin.default;
+        let mut entries: Vec<ModelEntry> = builtin.models;
+
+        for path in user_files.iter().filter(|p| p.exists()) {
+            let file = parse_registry_file(path)?;
+            if file.default.is_some() {
+                default_model = file.default;
+            }
+            for entry in file.models {
+                match entries.iter_mut().find(|e| e.id == entry.id) {
+                    Some(existing) => existing.merge(entry),
+                    None => entries.push(entry),
+                }
+            }
+        }
 
-        models.insert(haiku.id.clone(), haiku);
-        models.insert(sonnet.id.clone(), sonnet);
+        let models: HashMap<String, LlmModel> = entries
+            .into_iter()
+            .map(|entry| (entry.id.clone(), entry.into_model(pricing)))
+            .collect();
 
-        ModelRegistry {
+        let mut registry = ModelRegistry {
             models,
-            current_model_id: "claude-3-5-haiku-latest".to_string(), // Default to Haiku
+            current_model_id: String::new(),
+        };
+        registry.current_model_id = default_model
+            .and_then(|name| registry.get(&name).map(|m| m.id.clone()))
+            .or_else(|| registry.list_models().first().map(|m| m.id.clone()))
+            .unwrap_or_default();
+
+        Ok(registry)
+    }
+
+    // Fold the API listing into the registry. Local entries keep their names,
+    // pricing and capabilities; models only the API knows are added with
+    // table pricing, if any.
+    pub fn merge_remote(&mut self, cache: &RemoteModelCache, pricing: &PricingTable) {
+        let available: HashMap<&str, &ModelInfo> =
+            cache.models.iter().map(|m| (m.id.as_str(), m)).collect();
+
+        for model in self.models.values_mut() {
+            let resolved = cache.resolved.get(&model.id).unwrap_or(&model.id);
+            model.status = if available.contains_key(resolved.as_str()) {
+                if model.deprecated.is_some() {
+                    ModelStatus::Deprecated
+                } else {
+                    ModelStatus::Available
+                }
+            } else if cache.missing.contains(&model.id) {
+                ModelStatus::Retired
+            } else {
+                ModelStatus::Unknown
+            };
+        }
+
+        let covered: Vec<String> = cache.resolved.values().cloned().collect();
+        for info in &cache.models {
+            if self.models.contains_key(&info.id) || covered.contains(&info.id) {
+                continue;
+            }
+
+            let mut model = ModelEntry {
+                id: info.id.clone(),
+                display_name: Some(info.display_name.clone()),
+                // Every model served by the Messages API takes images and tools;
+                // thinking support is left for the registry file to declare
+                capabilities: Some(Capabilities {
+                    vision: true,
+                    thinking: false,
+                    tools: true,
+                }),
+                ..Default::default()
+            }
+            .into_model(pricing);
+            model.status = ModelStatus::Available;
+            self.models.insert(model.id.clone(), model);
         }
     }
 
+    // Look a model up by id or alias
+    pub fn get(&self, name: &str) -> Option<&LlmModel> {
+        self.models
+            .get(name)
+            .or_else(|| self.models.values().find(|m| m.matches(name)))
+    }
+
+    pub fn display_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.get(name)
+            .map(|m| m.display_name.as_str())
+            .unwrap_or(name)
+    }
+
     pub fn get_current_model(&self) -> &LlmModel {
         self.models.get(&self.current_model_id).unwrap()
     }
 
     pub fn list_models(&self) -> Vec<&LlmModel> {
-        self.models.values().collect()
+        let mut models: Vec<&LlmModel> = self.models.values().collect();
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        models
     }
 
     pub fn select_model(&mut self, model_id: &str) -> Result<&LlmModel, String> {
-        if let Some(model) = self.models.get(model_id) {
-            self.current_model_id = model_id.to_string();
-            Ok(model)
-        } else {
-            Err(format!("Model '{}' not found", model_id))
-        }
+        let id = match self.get(model_id) {
+            Some(model) => model.id.clone(),
+            None => return Err(format!("Model '{}' not found", model_id)),
+        };
+        self.current_model_id = id;
+        Ok(self.get_current_model())
     }
 
     pub fn save_to_config(&self, config_path: &Path) -> io::Result<()> {
@@ -99,8 +371,8 @@ impl ModelRegistry {
                 serde_json::from_str(&config_str).unwrap_or_default();
 
             if let Some(model_id) = config.get("current_model").and_then(|v| v.as_str()) {
-                if self.models.contains_key(model_id) {
-                    self.current_model_id = model_id.to_string();
+                if let Some(model) = self.get(model_id) {
+                    self.current_model_id = model.id.clone();
                 }
             }
         }
@@ -108,3 +380,36 @@ impl ModelRegistry {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(json: &str) -> ModelEntry {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn inline_pricing_gets_the_table_batch_discount() {
+        let table = PricingTable::builtin();
+        let model = entry(r#"{"id": "custom", "pricing": {"input": 2.0, "output": 10.0}}"#)
+            .into_model(&table);
+        assert_eq!(model.pricing.batch_discount, table.batch_discount);
+
+        let usage = ClaudeUsage {
+            input_tokens: Some(1_000_000),
+            output_tokens: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(model.calculate_batch_cost(&usage), 2.0 * (1.0 - 0.5));
+    }
+
+    #[test]
+    fn inline_pricing_keeps_its_own_batch_discount() {
+        let model = entry(
+            r#"{"id": "custom", "pricing": {"input": 2.0, "output": 10.0, "batch_discount": 0.0}}"#,
+        )
+        .into_model(&PricingTable::builtin());
+        assert_eq!(model.pricing.batch_discount, Some(0.0));
+    }
+}
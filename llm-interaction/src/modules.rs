@@ -2,10 +2,62 @@
 #![allow(unused_variables)]
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug, Clone)]
 pub struct ClaudeMessage {
     pub role: String,
-    pub content: String,
+    pub content: MessageContent,
+}
+
+impl ClaudeMessage {
+    pub fn text(role: &str, text: &str) -> Self {
+        ClaudeMessage {
+            role: role.to_string(),
+            content: MessageContent::Text(text.to_string()),
+        }
+    }
+}
+
+// Plain text, or content blocks when a turn carries tool calls or results
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Blocks(Vec<RequestBlock>),
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub input_schema: serde_json::Value,
+}
+
+// `{"type": "tool", "name": ...}` makes the model answer through that tool
+#[derive(Serialize, Debug, Clone)]
+pub struct ToolChoice {
+    #[serde(rename = "type")]
+    pub choice_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 #[derive(Serialize)]
 pub struct ClaudeApiRequest {
@@ -16,15 +68,55 @@ pub struct ClaudeApiRequest {
 
 #[derive(Deserialize, Debug)]
 pub struct ClaudeContentItem {
-    text: String,
+    #[serde(default)]
+    pub text: String,
     #[serde(rename = "type")]
-    content_type: String,
+    pub content_type: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ClaudeUsage {
     pub input_tokens: Option<u32>,
     pub output_tokens: Option<u32>,
+    pub cache_creation_input_tokens: Option<u32>,
+    pub cache_read_input_tokens: Option<u32>,
+}
+
+impl ClaudeUsage {
+    // Streaming responses report input and cache tokens in `message_start` and
+    // output tokens in `message_delta`, so fold each event's counts in as they arrive
+    pub fn merge(&mut self, other: &ClaudeUsage) {
+        if other.input_tokens.is_some() {
+            self.input_tokens = other.input_tokens;
+        }
+        if other.output_tokens.is_some() {
+            self.output_tokens = other.output_tokens;
+        }
+        if other.cache_creation_input_tokens.is_some() {
+            self.cache_creation_input_tokens = other.cache_creation_input_tokens;
+        }
+        if other.cache_read_input_tokens.is_some() {
+            self.cache_read_input_tokens = other.cache_read_input_tokens;
+        }
+    }
+
+    // Sum the counts of separate requests, e.g. a reply and its continuations
+    pub fn add(&mut self, other: &ClaudeUsage) {
+        fn sum(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+            match (a, b) {
+                (None, None) => None,
+                (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+            }
+        }
+        self.input_tokens = sum(self.input_tokens, other.input_tokens);
+        self.output_tokens = sum(self.output_tokens, other.output_tokens);
+        self.cache_creation_input_tokens = sum(
+            self.cache_creation_input_tokens,
+            other.cache_creation_input_tokens,
+        );
+        self.cache_read_input_tokens =
+            sum(self.cache_read_input_tokens, other.cache_read_input_tokens);
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -40,7 +132,7 @@ pub struct ClaudeApiResponse {
     pub usage: ClaudeUsage,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClaudeErrorDetails {
     #[serde(rename = "type")]
     pub error_type: String,
@@ -54,6 +146,90 @@ pub struct ClaudeApiError {
     pub error: ClaudeErrorDetails,
 }
 
+impl ClaudeApiResponse {
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter(|c| c.content_type == "text")
+            .map(|c| c.text.as_str())
+            .collect()
+    }
+}
+
+// Message Batches API
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchRequest {
+    pub custom_id: String,
+    pub params: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub struct CreateBatchRequest {
+    pub requests: Vec<BatchRequest>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BatchRequestCounts {
+    pub processing: u32,
+    pub succeeded: u32,
+    pub errored: u32,
+    pub canceled: u32,
+    pub expired: u32,
+}
+
+impl BatchRequestCounts {
+    pub fn total(&self) -> u32 {
+        self.processing + self.succeeded + self.errored + self.canceled + self.expired
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageBatch {
+    pub id: String,
+    // in_progress, canceling or ended
+    pub processing_status: String,
+    pub request_counts: BatchRequestCounts,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub ended_at: Option<String>,
+    pub cancel_initiated_at: Option<String>,
+    pub results_url: Option<String>,
+}
+
+// One line of a batch results file
+#[derive(Deserialize, Debug)]
+pub struct BatchResultLine {
+    pub custom_id: String,
+    pub result: BatchResult,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BatchResult {
+    Succeeded { message: ClaudeApiResponse },
+    Errored { error: ClaudeApiError },
+    Canceled,
+    Expired,
+}
+
+// Entries from GET /v1/models
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelInfo {
+    pub id: String,
+    pub display_name: String,
+    pub created_at: String,
+    #[serde(rename = "type")]
+    pub model_type: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModelListResponse {
+    pub data: Vec<ModelInfo>,
+    pub has_more: bool,
+    pub first_id: Option<String>,
+    pub last_id: Option<String>,
+}
+
 // Add these to modules.rs
 
 #[derive(Deserialize, Debug)]
@@ -79,6 +255,8 @@ pub enum StreamEvent {
     MessageStop,
     #[serde(rename = "ping")]
     Ping,
+    #[serde(rename = "error")]
+    Error { error: ClaudeErrorDetails },
 }
 
 #[derive(Deserialize, Debug)]
@@ -94,18 +272,27 @@ pub struct MessageStart {
     pub usage: ClaudeUsage,
 }
 
+// A `text` block carries text; a `tool_use` block carries id, name and input
 #[derive(Deserialize, Debug)]
 pub struct ContentBlock {
     #[serde(rename = "type")]
     pub block_type: String,
+    #[serde(default)]
     pub text: String,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub input: Option<serde_json::Value>,
 }
 
+// `text_delta` fills `text`; `input_json_delta` streams a tool call's input
+// as fragments of JSON in `partial_json`
 #[derive(Deserialize, Debug)]
 pub struct Delta {
     #[serde(rename = "type")]
     pub delta_type: String,
+    #[serde(default)]
     pub text: String,
+    pub partial_json: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -121,12 +308,25 @@ pub struct ClaudeStreamApiRequest {
     pub max_tokens: u32,
     pub messages: Vec<ClaudeMessage>,
     pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
 }
 
 // Add thfunction to parse SSE events
 pub fn parse_sse_line(line: &str) -> Option<StreamEvent> {
-    if line.starts_with("data: ") {
-        let json = &line["data: ".len()..];
+    if let Some(json) = line.strip_prefix("data: ") {
         match serde_json::from_str(json) {
             Ok(event) => Some(event),
             Err(e) => {
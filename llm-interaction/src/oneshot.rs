@@ -0,0 +1,396 @@
+use crate::api::{self, ApiClient, MessageResult};
+use crate::budget::{self, BudgetCheck, BudgetTracker};
+use crate::config::Config;
+use crate::model_registry::LlmModel;
+use crate::modules::{ClaudeMessage, ClaudeUsage, MessageContent, RequestBlock};
+use crate::output::{self, FinalMessage, OutputEvent, OutputFormat};
+use crate::sampling::SamplingParams;
+use crate::structured::{self, OutputSchema};
+use crate::usage::UsageLedger;
+use std::io::{self, IsTerminal, Read, Write};
+use std::time::Duration;
+
+// Exit codes for `agent -p` / `agent ask`
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_BUDGET: i32 = 3;
+pub const EXIT_TRUNCATED: i32 = 4;
+pub const EXIT_INVALID: i32 = 5;
+
+pub struct OneShotContext<'a> {
+    pub client: ApiClient,
+    pub config: &'a Config,
+    pub model: &'a LlmModel,
+    pub system: Option<String>,
+    pub params: &'a SamplingParams,
+    pub profile: &'a str,
+    pub max_continuations: u32,
+    pub format: OutputFormat,
+    pub schema: Option<&'a OutputSchema>,
+}
+
+// Everything piped on stdin, or None when it is a terminal or empty
+pub fn read_piped_stdin() -> io::Result<Option<String>> {
+    let mut stdin = io::stdin();
+    if stdin.is_terminal() {
+        return Ok(None);
+    }
+    let mut input = String::new();
+    stdin.read_to_string(&mut input)?;
+    Ok(Some(input).filter(|s| !s.trim().is_empty()))
+}
+
+// Combine the prompt argument with anything piped on stdin. Piped text is
+// appended after the prompt, or is the whole prompt when none was given.
+pub fn build_prompt(prompt: Option<&str>) -> io::Result<Option<String>> {
+    let piped = read_piped_stdin()?;
+
+    let prompt = prompt.map(str::trim).filter(|p| !p.is_empty());
+    Ok(match (prompt, piped) {
+        (Some(prompt), Some(piped)) => Some(format!("{}\n\n{}", prompt, piped.trim_end())),
+        (Some(prompt), None) => Some(prompt.to_string()),
+        (None, Some(piped)) => Some(piped.trim_end().to_string()),
+        (None, None) => None,
+    })
+}
+
+// Nobody is there to confirm an override, so a request over budget is refused
+fn within_budget(check: BudgetCheck) -> bool {
+    match check {
+        BudgetCheck::Within => true,
+        BudgetCheck::Warning(allowances) => {
+            for allowance in &allowances {
+                eprintln!("warning: approaching budget limit for {}", allowance);
+            }
+            true
+        }
+        BudgetCheck::Exceeded(allowances) => {
+            for allowance in &allowances {
+                eprintln!("error: budget limit reached for {}", allowance);
+            }
+            false
+        }
+    }
+}
+
+// Report a failure on stderr, or as an error event when stdout is JSON
+fn fail(format: OutputFormat, message: &str) {
+    match format {
+        OutputFormat::Text => eprintln!("error: {}", message),
+        OutputFormat::Json | OutputFormat::StreamJson => {
+            let _ = output::emit(&OutputEvent::Error {
+                message: message.to_string(),
+            });
+        }
+    }
+}
+
+// Stream events to stdout as the format asks: raw text or one JSON line each
+fn print_event(format: OutputFormat, text: &mut TextOutput, event: &OutputEvent) -> io::Result<()> {
+    match (format, event) {
+        (OutputFormat::Text, OutputEvent::TextDelta { text: delta, .. }) => text.write(delta),
+        (OutputFormat::StreamJson, event) => output::emit(event),
+        _ => Ok(()),
+    }
+}
+
+// Answer text as it streams in. Trailing whitespace is held back until more
+// text follows: a cut-off answer is prefilled without it and the
+// continuation usually starts with that whitespace again.
+#[derive(Default)]
+struct TextOutput {
+    held: String,
+    // Set while waiting for the first text of a continuation
+    continuing: bool,
+}
+
+impl TextOutput {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        if self.continuing && !text.is_empty() {
+            self.continuing = false;
+            if text.starts_with(char::is_whitespace) {
+                self.held.clear();
+            }
+        }
+
+        let mut stdout = io::stdout();
+        let kept = text.trim_end();
+        if !kept.is_empty() {
+            stdout.write_all(self.held.as_bytes())?;
+            stdout.write_all(kept.as_bytes())?;
+            self.held.clear();
+        }
+        self.held.push_str(&text[kept.len()..]);
+        stdout.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(self.held.as_bytes())?;
+        self.held.clear();
+        writeln!(stdout)
+    }
+}
+
+// Spend and usage across every request made for one prompt
+struct RunState {
+    ledger: UsageLedger,
+    tracker: BudgetTracker,
+    usage: ClaudeUsage,
+    cost: f64,
+    latency: Duration,
+}
+
+impl RunState {
+    fn new(config: &Config) -> Self {
+        RunState {
+            ledger: UsageLedger::new(config.get_usage_ledger_path()),
+            tracker: BudgetTracker::new(config.budget.clone()),
+            usage: ClaudeUsage::default(),
+            cost: 0.0,
+            latency: Duration::ZERO,
+        }
+    }
+
+    // Ok when the next request may be sent, otherwise the exit code to stop with
+    fn check_budget(&self, ctx: &OneShotContext<'_>, input: &str) -> Result<(), i32> {
+        let model = ctx.model;
+        let projected = budget::projected_cost(model, input, ctx.params.max_tokens_for(model));
+        match self.tracker.check(projected, &self.ledger, ctx.profile) {
+            Ok(check) => {
+                if within_budget(check) {
+                    return Ok(());
+                }
+                if ctx.format != OutputFormat::Text {
+                    fail(ctx.format, "budget limit reached");
+                }
+                Err(EXIT_BUDGET)
+            }
+            Err(e) => {
+                fail(ctx.format, &format!("could not read usage ledger: {}", e));
+                Err(EXIT_FAILED)
+            }
+        }
+    }
+
+    fn record(&mut self, ctx: &OneShotContext<'_>, result: &MessageResult) {
+        let model = ctx.model;
+        self.latency += result.latency;
+        if let Some(usage) = &result.usage {
+            let cost = model.calculate_cost(usage);
+            self.usage.add(usage);
+            self.cost += cost;
+            self.tracker.add_session_spend(cost);
+            if ctx.config.records_usage() {
+                if let Err(e) = self
+                    .ledger
+                    .record(ctx.profile, model, usage, result.latency)
+                {
+                    eprintln!("warning: could not write usage ledger: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Send one prompt, print only the answer on stdout and return the exit code
+pub async fn run(ctx: &OneShotContext<'_>, prompt: &str) -> i32 {
+    let model = ctx.model;
+    if let Err(e) = ctx.params.validate(model) {
+        fail(ctx.format, &e);
+        return EXIT_USAGE;
+    }
+    if let Some(schema) = ctx.schema {
+        return run_structured(ctx, prompt, schema).await;
+    }
+
+    let mut state = RunState::new(ctx.config);
+    let mut text = String::new();
+    let mut continuations = 0;
+    let mut tool_calls = Vec::new();
+
+    let format = ctx.format;
+    let mut output = TextOutput::default();
+
+    loop {
+        if let Err(code) = state.check_budget(ctx, &format!("{}{}", prompt, text)) {
+            return code;
+        }
+
+        let prefill = (!text.is_empty()).then_some(text.as_str());
+        let mut request = api::build_request(&[], prompt, prefill, model, ctx.params);
+        request.system = ctx.system.clone();
+        output.continuing = prefill.is_some();
+        let mut on_event = |event: &OutputEvent| print_event(format, &mut output, event);
+        let result: MessageResult = match ctx
+            .client
+            .send_request(&request, prefill, &mut on_event)
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                if format == OutputFormat::Text && !text.is_empty() {
+                    println!();
+                }
+                fail(format, &e.to_string());
+                return EXIT_FAILED;
+            }
+        };
+
+        state.record(ctx, &result);
+        tool_calls.extend(result.tool_calls.iter().cloned());
+
+        if result.hit_max_tokens() && continuations < ctx.max_continuations {
+            continuations += 1;
+            text = result.text;
+            continue;
+        }
+
+        let truncated = result.hit_max_tokens();
+        let message = FinalMessage {
+            model: model.id.clone(),
+            text: result.text,
+            output: None,
+            tool_calls,
+            stop_reason: result.stop_reason,
+            usage: state.usage,
+            cost: state.cost,
+            latency_ms: state.latency.as_millis() as u64,
+            continuations,
+        };
+
+        let printed = match format {
+            OutputFormat::Text => output.finish(),
+            _ => print_final(format, message),
+        };
+        let _ = printed.and_then(|_| io::stdout().flush());
+
+        if truncated {
+            eprintln!("warning: response cut off at max_tokens");
+            return EXIT_TRUNCATED;
+        }
+        return EXIT_OK;
+    }
+}
+
+fn print_final(format: OutputFormat, message: FinalMessage) -> io::Result<()> {
+    match format {
+        OutputFormat::Text => match &message.output {
+            Some(output) => writeln!(io::stdout(), "{}", serde_json::to_string_pretty(output)?),
+            None => writeln!(io::stdout(), "{}", message.text),
+        },
+        OutputFormat::Json => writeln!(io::stdout(), "{}", serde_json::to_string_pretty(&message)?),
+        OutputFormat::StreamJson => output::emit(&OutputEvent::Result(message)),
+    }
+}
+
+// Force the answer through the schema's tool, feeding validation errors back
+// to the model until it produces a valid answer or runs out of attempts
+async fn run_structured(ctx: &OneShotContext<'_>, prompt: &str, schema: &OutputSchema) -> i32 {
+    let model = ctx.model;
+    let mut state = RunState::new(ctx.config);
+    let mut messages = vec![ClaudeMessage::text("user", prompt)];
+
+    let format = ctx.format;
+    let mut on_event = |event: &OutputEvent| match event {
+        // The tool input is printed once it validates, not as it streams
+        OutputEvent::TextDelta { .. } if format == OutputFormat::Text => Ok(()),
+        event => print_event(format, &mut TextOutput::default(), event),
+    };
+
+    for attempt in 1..=structured::MAX_ATTEMPTS {
+        let sent = serde_json::to_string(&messages).unwrap_or_default();
+        if let Err(code) = state.check_budget(ctx, &sent) {
+            return code;
+        }
+
+        let mut request = api::build_conversation_request(messages.clone(), model, ctx.params);
+        request.system = ctx.system.clone();
+        request.tools = vec![schema.tool()];
+        request.tool_choice = Some(schema.tool_choice());
+
+        let result = match ctx.client.send_request(&request, None, &mut on_event).await {
+            Ok(result) => result,
+            Err(e) => {
+                fail(format, &e.to_string());
+                return EXIT_FAILED;
+            }
+        };
+        state.record(ctx, &result);
+
+        if result.hit_max_tokens() {
+            fail(
+                format,
+                "structured answer cut off at max_tokens; raise --max-tokens",
+            );
+            return EXIT_TRUNCATED;
+        }
+        let Some(call) = result
+            .tool_calls
+            .iter()
+            .find(|c| c.name == structured::TOOL_NAME)
+        else {
+            fail(format, "the model answered without calling the schema tool");
+            return EXIT_FAILED;
+        };
+
+        let error = match schema.validate(&call.input) {
+            Ok(value) => {
+                let message = FinalMessage {
+                    model: model.id.clone(),
+                    text: result.text.clone(),
+                    output: Some(value),
+                    tool_calls: Vec::new(),
+                    stop_reason: result.stop_reason.clone(),
+                    usage: state.usage,
+                    cost: state.cost,
+                    latency_ms: state.latency.as_millis() as u64,
+                    continuations: 0,
+                };
+                let _ = print_final(format, message).and_then(|_| io::stdout().flush());
+                return EXIT_OK;
+            }
+            Err(error) => error,
+        };
+
+        if attempt == structured::MAX_ATTEMPTS {
+            fail(
+                format,
+                &format!(
+                    "answer did not match the schema after {} attempts:\n{}",
+                    attempt, error
+                ),
+            );
+            return EXIT_INVALID;
+        }
+        eprintln!(
+            "warning: answer did not match the schema, retrying ({}/{})",
+            attempt + 1,
+            structured::MAX_ATTEMPTS
+        );
+
+        messages.push(ClaudeMessage {
+            role: "assistant".to_string(),
+            content: MessageContent::Blocks(vec![RequestBlock::ToolUse {
+                id: call.id.clone(),
+                name: call.name.clone(),
+                input: call.input.clone(),
+            }]),
+        });
+        messages.push(ClaudeMessage {
+            role: "user".to_string(),
+            content: MessageContent::Blocks(vec![RequestBlock::ToolResult {
+                tool_use_id: call.id.clone(),
+                content: format!(
+                    "The input does not match the schema:\n{}\nCall {} again with corrected input.",
+                    error,
+                    structured::TOOL_NAME
+                ),
+                is_error: true,
+            }]),
+        });
+    }
+
+    EXIT_FAILED
+}
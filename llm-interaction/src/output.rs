@@ -0,0 +1,184 @@
+use crate::modules::{ClaudeUsage, StreamEvent};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// How one-shot and batch commands write their results to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    // The answer as plain text
+    Text,
+    // One JSON object with the final message, usage and cost
+    Json,
+    // One JSON event per line as the answer streams
+    StreamJson,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: serde_json::Value,
+}
+
+// The final message as printed by `--output-format json`
+#[derive(Debug, Clone, Serialize)]
+pub struct FinalMessage {
+    pub model: String,
+    pub text: String,
+    // The validated answer when a --schema was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    pub stop_reason: Option<String>,
+    pub usage: ClaudeUsage,
+    pub cost: f64,
+    pub latency_ms: u64,
+    // Requests sent to continue an answer cut off at max_tokens
+    pub continuations: u32,
+}
+
+// Events written by `--output-format stream-json`, one per line. They are
+// normalized from the API's `StreamEvent`s so scripts only see what matters.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputEvent {
+    MessageStart {
+        id: String,
+        model: String,
+    },
+    TextDelta {
+        index: usize,
+        text: String,
+    },
+    ToolUseStart {
+        index: usize,
+        id: String,
+        name: String,
+    },
+    ToolInputDelta {
+        index: usize,
+        partial_json: String,
+    },
+    // A tool call once its input has finished streaming
+    ToolUse {
+        index: usize,
+        #[serde(flatten)]
+        call: ToolCall,
+    },
+    // Token counts so far for the current request
+    Usage(ClaudeUsage),
+    Stop {
+        stop_reason: Option<String>,
+        stop_sequence: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+    // Always the last event of a successful run
+    Result(FinalMessage),
+}
+
+// Turns raw stream events into `OutputEvent`s, assembling tool inputs and
+// keeping a running usage total along the way
+#[derive(Default)]
+pub struct StreamNormalizer {
+    tool_blocks: HashMap<usize, (String, String, String)>,
+    usage: ClaudeUsage,
+}
+
+impl StreamNormalizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn normalize(&mut self, event: StreamEvent) -> Vec<OutputEvent> {
+        match event {
+            StreamEvent::MessageStart { message } => {
+                self.usage.merge(&message.usage);
+                vec![
+                    OutputEvent::MessageStart {
+                        id: message.id,
+                        model: message.model,
+                    },
+                    OutputEvent::Usage(self.usage.clone()),
+                ]
+            }
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            } => {
+                if content_block.block_type == "tool_use" {
+                    let id = content_block.id.unwrap_or_default();
+                    let name = content_block.name.unwrap_or_default();
+                    self.tool_blocks
+                        .insert(index, (id.clone(), name.clone(), String::new()));
+                    vec![OutputEvent::ToolUseStart { index, id, name }]
+                } else if !content_block.text.is_empty() {
+                    vec![OutputEvent::TextDelta {
+                        index,
+                        text: content_block.text,
+                    }]
+                } else {
+                    Vec::new()
+                }
+            }
+            StreamEvent::ContentBlockDelta { index, delta } => {
+                if let Some(partial_json) = delta.partial_json {
+                    if let Some((_, _, input)) = self.tool_blocks.get_mut(&index) {
+                        input.push_str(&partial_json);
+                    }
+                    vec![OutputEvent::ToolInputDelta {
+                        index,
+                        partial_json,
+                    }]
+                } else if delta.delta_type == "text_delta" {
+                    vec![OutputEvent::TextDelta {
+                        index,
+                        text: delta.text,
+                    }]
+                } else {
+                    Vec::new()
+                }
+            }
+            StreamEvent::ContentBlockStop { index } => match self.tool_blocks.remove(&index) {
+                Some((id, name, input)) => {
+                    // A tool called without arguments streams no input at all
+                    let input = if input.trim().is_empty() {
+                        serde_json::json!({})
+                    } else {
+                        serde_json::from_str(&input).unwrap_or(serde_json::Value::String(input))
+                    };
+                    vec![OutputEvent::ToolUse {
+                        index,
+                        call: ToolCall { id, name, input },
+                    }]
+                }
+                None => Vec::new(),
+            },
+            StreamEvent::MessageDelta { delta, usage } => {
+                self.usage.merge(&usage);
+                vec![
+                    OutputEvent::Usage(self.usage.clone()),
+                    OutputEvent::Stop {
+                        stop_reason: delta.stop_reason,
+                        stop_sequence: delta.stop_sequence,
+                    },
+                ]
+            }
+            StreamEvent::Error { error } => vec![OutputEvent::Error {
+                message: format!("{}: {}", error.error_type, error.message),
+            }],
+            StreamEvent::MessageStop | StreamEvent::Ping => Vec::new(),
+        }
+    }
+}
+
+// Write one event as a line of JSON and flush so readers see it immediately
+pub fn emit(event: &OutputEvent) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, event)?;
+    stdout.write_all(b"\n")?;
+    stdout.flush()
+}
@@ -0,0 +1,146 @@
+use crate::modules::ClaudeUsage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Pricing file format understood by this build. Bump when the layout changes.
+pub const PRICING_FORMAT_VERSION: u32 = 1;
+
+const BUILTIN_PRICING: &str = include_str!("../pricing.json");
+
+// All prices are USD per 1M tokens
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelPricing {
+    pub input: f64,
+    pub output: f64,
+    #[serde(default)]
+    pub cache_write: f64,
+    #[serde(default)]
+    pub cache_read: f64,
+    // Fraction taken off for Message Batches requests; falls back to the table default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_discount: Option<f64>,
+}
+
+impl ModelPricing {
+    pub fn cost(&self, usage: &ClaudeUsage) -> f64 {
+        let per_million =
+            |tokens: Option<u32>, price: f64| (tokens.unwrap_or(0) as f64 / 1_000_000.0) * price;
+
+        per_million(usage.input_tokens, self.input)
+            + per_million(usage.output_tokens, self.output)
+            + per_million(usage.cache_creation_input_tokens, self.cache_write)
+            + per_million(usage.cache_read_input_tokens, self.cache_read)
+    }
+}
+
+// Override files may leave the version out
+fn current_format_version() -> u32 {
+    PRICING_FORMAT_VERSION
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PricingTable {
+    #[serde(default = "current_format_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub updated: Option<String>,
+    // None in an override file keeps the built-in default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_discount: Option<f64>,
+    #[serde(default)]
+    pub models: HashMap<String, ModelPricing>,
+}
+
+impl PricingTable {
+    pub fn builtin() -> Self {
+        serde_json::from_str(BUILTIN_PRICING).expect("built-in pricing.json is valid")
+    }
+
+    // Built-in prices, overridden model by model from the user's pricing file if present
+    pub fn load(override_path: &Path) -> io::Result<Self> {
+        let mut table = Self::builtin();
+
+        let override_str = match fs::read_to_string(override_path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(table),
+            Err(e) => return Err(e),
+        };
+
+        let overrides: PricingTable = serde_json::from_str(&override_str).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid pricing file {}: {}", override_path.display(), e),
+            )
+        })?;
+
+        if overrides.version > PRICING_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Pricing file {} uses format version {}, this build understands up to {}",
+                    override_path.display(),
+                    overrides.version,
+                    PRICING_FORMAT_VERSION
+                ),
+            ));
+        }
+
+        table.merge(overrides);
+        Ok(table)
+    }
+
+    pub fn merge(&mut self, other: PricingTable) {
+        if other.updated.is_some() {
+            self.updated = other.updated;
+        }
+        if other.batch_discount.is_some() {
+            self.batch_discount = other.batch_discount;
+        }
+        self.models.extend(other.models);
+    }
+
+    pub fn get(&self, model_id: &str) -> Option<&ModelPricing> {
+        self.models.get(model_id)
+    }
+
+    pub fn batch_discount_for(&self, pricing: &ModelPricing) -> f64 {
+        pricing
+            .batch_discount
+            .or(self.batch_discount)
+            .unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(json: &str) -> PricingTable {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn overrides_may_leave_out_the_version() {
+        let table = overrides(r#"{"models": {}}"#);
+        assert_eq!(table.version, PRICING_FORMAT_VERSION);
+        assert_eq!(table.batch_discount, None);
+    }
+
+    #[test]
+    fn an_override_can_turn_the_batch_discount_off() {
+        let mut table = PricingTable::builtin();
+        table.merge(overrides(r#"{"batch_discount": 0.0}"#));
+        assert_eq!(table.batch_discount_for(&ModelPricing::default()), 0.0);
+    }
+
+    #[test]
+    fn leaving_out_the_batch_discount_keeps_the_default() {
+        let mut table = PricingTable::builtin();
+        let default = table.batch_discount;
+        table.merge(overrides(r#"{"models": {}}"#));
+        assert_eq!(table.batch_discount, default);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::api::{build_request, trim_prefill, ApiClient, MessageResult};
+use crate::budget::{self, BudgetCheck, BudgetTracker};
+use crate::commands::{CommandRegistry, Outcome};
+use crate::completion::Completer;
+use crate::config::Config;
+use crate::conversation::{Conversation, ConversationStore, TurnUsage};
+use crate::editor::{LineEditor, ReadLine};
+use crate::model_registry::ModelRegistry;
+use crate::sampling::SamplingParams;
+use crate::theme;
+use crate::tui::{self, MessageStream, TerminalUi};
+use crate::usage::UsageLedger;
+use crossterm::style::Color;
+use dialoguer::Confirm;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+
+// Everything the interactive session and its slash commands work with
+pub struct Session {
+    pub client: ApiClient,
+    pub config: Config,
+    pub registry: ModelRegistry,
+    pub params: SamplingParams,
+    pub budget: BudgetTracker,
+    pub ledger: UsageLedger,
+    pub profile: String,
+    pub max_continuations: u32,
+    pub ui: TerminalUi,
+    // Prompt and partial answer of the last response cut off at max_tokens
+    pub truncated: Option<(String, String)>,
+    // Model to go back to once the prompt being sent is answered, after
+    // `/retry <model>`
+    pub restore_model: Option<String>,
+    pub conversation: Conversation,
+    pub store: ConversationStore,
+    // Whether the full-screen chat is drawing the screen
+    pub full_screen: bool,
+}
+
+impl Session {
+    // How sending `prompt`, continuing from `prefill`, stands against the
+    // budget. The conversation so far is sent along and billed as input too.
+    pub fn check_budget(&self, prompt: &str, prefill: Option<&str>) -> io::Result<BudgetCheck> {
+        let model = self.registry.get_current_model();
+        let history = self.conversation.history(prompt, prefill.is_some());
+        let projected = budget::projected_cost(
+            model,
+            &format!(
+                "{}{}{}",
+                serde_json::to_string(&history)?,
+                prompt,
+                prefill.unwrap_or_default()
+            ),
+            self.params.max_tokens_for(model),
+        );
+        self.budget.check(projected, &self.ledger, &self.profile)
+    }
+
+    // Count a response against the session budget and write it to the ledger
+    pub fn record(&mut self, result: &MessageResult) -> io::Result<()> {
+        let Some(usage) = &result.usage else {
+            return Ok(());
+        };
+        let model = self.registry.get_current_model();
+        self.budget.add_session_spend(model.calculate_cost(usage));
+        if self.config.records_usage() {
+            self.ledger
+                .record(&self.profile, model, usage, result.latency)?;
+        }
+        Ok(())
+    }
+
+    // Add an answer to the conversation and save it
+    pub fn save_exchange(
+        &mut self,
+        prompt: &str,
+        result: &MessageResult,
+        continued: bool,
+    ) -> io::Result<()> {
+        let model = self.registry.get_current_model();
+        let usage = result.usage.as_ref().map(|usage| TurnUsage {
+            input_tokens: usage.input_tokens.unwrap_or(0),
+            output_tokens: usage.output_tokens.unwrap_or(0),
+            cost: model.calculate_cost(usage),
+        });
+        self.conversation
+            .add_exchange(prompt, &result.text, &model.id, usage, continued);
+        self.store.save(&self.conversation)
+    }
+
+    // Send a prompt, continuing from `partial` when it isn't empty, and keep
+    // going on max_tokens up to the continuation limit. `frontend` shows it.
+    pub async fn send(
+        &mut self,
+        frontend: &mut impl Frontend,
+        prompt: String,
+        partial: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let sent = self.send_and_continue(frontend, prompt, partial).await;
+        if let Some(id) = self.restore_model.take() {
+            if let Ok(model) = self.registry.select_model(&id) {
+                self.ui.set_model(model);
+            }
+        }
+        sent
+    }
+
+    async fn send_and_continue(
+        &mut self,
+        frontend: &mut impl Frontend,
+        prompt: String,
+        partial: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut prefill = (!partial.is_empty()).then_some(partial);
+        let mut continuations = 0;
+
+        loop {
+            let check = self.check_budget(&prompt, prefill.as_deref())?;
+            if !frontend.confirm(self, check).await? {
+                return Ok(());
+            }
+
+            let Some(result) = frontend.stream(self, &prompt, prefill.as_deref()).await? else {
+                return Ok(());
+            };
+
+            if let Err(e) = self.record(&result) {
+                frontend.notice(
+                    self,
+                    &format!("Warning: could not write usage ledger: {}", e),
+                )?;
+            }
+            if let Err(e) = self.save_exchange(&prompt, &result, prefill.is_some()) {
+                frontend.notice(
+                    self,
+                    &format!("Warning: could not save conversation: {}", e),
+                )?;
+            }
+
+            if !result.hit_max_tokens() {
+                return Ok(());
+            }
+
+            if continuations < self.max_continuations {
+                continuations += 1;
+                frontend.notice(
+                    self,
+                    &format!(
+                        "↪ Response reached max_tokens, continuing ({}/{})",
+                        continuations, self.max_continuations
+                    ),
+                )?;
+                prefill = Some(result.text);
+                continue;
+            }
+
+            frontend.notice(
+                self,
+                "✂ Response cut off at max_tokens. Type /continue to keep going.",
+            )?;
+            self.truncated = Some((prompt, result.text));
+            return Ok(());
+        }
+    }
+}
+
+// How a front end shows what `Session::send` does
+pub trait Frontend {
+    // Show budget warnings and, past a limit, ask whether to send anyway
+    async fn confirm(&mut self, session: &Session, check: BudgetCheck) -> io::Result<bool>;
+
+    // Stream a reply. With a prefill it goes on from the reply that text
+    // ends, already on screen. None when it failed or was cancelled.
+    async fn stream(
+        &mut self,
+        session: &mut Session,
+        prompt: &str,
+        prefill: Option<&str>,
+    ) -> io::Result<Option<MessageResult>>;
+
+    // Status messages and warnings, shown below the reply they are about
+    fn notice(&mut self, session: &Session, text: &str) -> io::Result<()>;
+}
+
+// The line-based session: replies are drawn in the terminal as they stream
+// in, and a continuation goes on in the box of the reply it continues
+#[derive(Default)]
+struct Console {
+    stream: Option<MessageStream>,
+}
+
+impl Frontend for Console {
+    async fn confirm(&mut self, _session: &Session, check: BudgetCheck) -> io::Result<bool> {
+        // Warnings and questions go below the reply, so a continuation
+        // can't be drawn into its box any more
+        if !matches!(check, BudgetCheck::Within) {
+            self.stream = None;
+        }
+        budget::confirm(check)
+    }
+
+    async fn stream(
+        &mut self,
+        session: &mut Session,
+        prompt: &str,
+        prefill: Option<&str>,
+    ) -> io::Result<Option<MessageResult>> {
+        let stream = self
+            .stream
+            .get_or_insert_with(|| MessageStream::new(prefill.map(trim_prefill)));
+        let model = session.registry.get_current_model();
+        let history = session.conversation.history(prompt, prefill.is_some());
+        let request = build_request(&history, prompt, prefill, model, &session.params);
+        match session
+            .client
+            .send_message_tui(&request, prefill, model, &mut session.ui, stream)
+            .await
+        {
+            Ok(result) => Ok(Some(result)),
+            Err(e) => {
+                self.stream = None;
+                println!("Error: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn notice(&mut self, session: &Session, text: &str) -> io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => session.ui.stream_notice(stream, text),
+            None => session.ui.draw_notice(text),
+        }
+    }
+}
+
+pub async fn run(
+    mut session: Session,
+    commands: CommandRegistry,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Piped input (e.g. scripted sessions) is read line by line without
+    // editing, and so is input for pipes and dumb terminals. NO_COLOR only
+    // takes the colors away.
+    let mut editor = (io::stdin().is_terminal() && tui::cursor_terminal()).then(|| {
+        let mut editor = LineEditor::new(Some(session.config.get_history_path()));
+        editor.set_completer(Completer::new(
+            &commands,
+            &session.registry,
+            theme::names(&session.config.get_themes_dir()),
+        ));
+        editor
+    });
+
+    if !session.conversation.is_empty() {
+        session.ui.draw_notice(&format!(
+            "Resumed conversation {}: {} ({} messages)",
+            session.conversation.id,
+            session.conversation.title,
+            session.conversation.active().len()
+        ))?;
+        if let Some(answer) = session.conversation.last_answer() {
+            session.ui.draw_model_message(answer, None, None)?;
+        }
+    }
+
+    loop {
+        let input = match editor.as_mut() {
+            Some(editor) => {
+                let color = if tui::no_color() {
+                    Color::Reset
+                } else {
+                    session.ui.style.prompt_fg
+                };
+                match editor.read_line(&session.ui.prompt_text(), color)? {
+                    ReadLine::Line(line) => line,
+                    ReadLine::Interrupted => continue,
+                    ReadLine::Eof => break,
+                }
+            }
+            None => {
+                session.ui.draw_prompt()?;
+                let mut line = String::new();
+                if io::stdin().read_line(&mut line)? == 0 {
+                    break;
+                }
+                line
+            }
+        };
+        let input = input.trim();
+
+        let position = session.conversation.current;
+        let outcome = if input.starts_with('/') {
+            commands.dispatch(&mut session, input, &mut io::stdout())?
+        } else if input.is_empty() {
+            Outcome::Handled
+        } else {
+            Outcome::Send {
+                prompt: attach_files(input),
+                partial: String::new(),
+            }
+        };
+
+        match outcome {
+            // After /undo or /branch, show where the conversation is now
+            Outcome::Handled if session.conversation.current != position => {
+                if let Some(answer) = session.conversation.last_answer() {
+                    session.ui.draw_model_message(answer, None, None)?;
+                }
+            }
+            Outcome::Handled => {}
+            Outcome::Quit => break,
+            Outcome::Send { prompt, partial } => {
+                // /retry and /edit send a prompt that wasn't typed here
+                if session.conversation.current != position && prompt != input {
+                    session.ui.draw_user_message(&prompt)?;
+                }
+                session
+                    .send(&mut Console::default(), prompt, partial)
+                    .await?
+            }
+            Outcome::Write { path, contents } => {
+                let overwrite = !path.exists()
+                    || (io::stdin().is_terminal()
+                        && Confirm::new()
+                            .with_prompt(format!("{} exists. Overwrite it?", path.display()))
+                            .default(false)
+                            .interact()
+                            .map_err(io::Error::other)?);
+                if overwrite {
+                    println!("{}", write_file(&path, &contents));
+                } else {
+                    println!("Not saved.");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Save text for /save and /save-response; returns the message to show
+pub fn write_file(path: &Path, contents: &str) -> String {
+    match fs::write(path, contents) {
+        Ok(()) => format!("Saved {} ({} bytes).", path.display(), contents.len()),
+        Err(e) => format!("Error: could not write {}: {}", path.display(), e),
+    }
+}
+
+// Append the contents of each file mentioned as `@path`. Words that don't
+// name a readable file are left as they are.
+pub fn attach_files(input: &str) -> String {
+    let mut prompt = input.to_string();
+    let mut attached: Vec<&str> = Vec::new();
+    for path in input.split_whitespace().filter_map(|w| w.strip_prefix('@')) {
+        if attached.contains(&path) {
+            continue;
+        }
+        if let Ok(contents) = fs::read_to_string(path) {
+            prompt.push_str(&file_block(path, &contents));
+            attached.push(path);
+        }
+    }
+    prompt
+}
+
+// How an attached file follows the prompt
+pub fn file_block(path: &str, contents: &str) -> String {
+    format!(
+        "\n\n<file path=\"{}\">\n{}\n</file>",
+        path,
+        contents.trim_end()
+    )
+}
+
+// The prompt as typed and the paths of the files `attach_files` added to it
+pub fn split_attachments(prompt: &str) -> (&str, Vec<&str>) {
+    const START: &str = "\n\n<file path=\"";
+    let Some(end) = prompt.find(START) else {
+        return (prompt, Vec::new());
+    };
+    let paths = prompt[end..]
+        .split(START)
+        .filter_map(|block| block.split_once("\">\n").map(|(path, _)| path))
+        .collect();
+    (&prompt[..end], paths)
+}
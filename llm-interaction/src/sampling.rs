@@ -0,0 +1,265 @@
+use crate::model_registry::LlmModel;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Keys accepted by `/set` and shown by `/params`
+pub const PARAM_KEYS: [&str; 5] = ["max_tokens", "temperature", "top_p", "top_k", "stop"];
+
+// Per-request sampling parameters. Unset values are left out of the request
+// so the API (or the model's default_max_tokens) decides.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+}
+
+impl SamplingParams {
+    pub fn is_empty(&self) -> bool {
+        *self == SamplingParams::default()
+    }
+
+    pub fn max_tokens_for(&self, model: &LlmModel) -> u32 {
+        self.max_tokens.unwrap_or(model.default_max_tokens)
+    }
+
+    // Values set in `other` replace ours
+    pub fn merge(&mut self, other: &SamplingParams) {
+        if other.max_tokens.is_some() {
+            self.max_tokens = other.max_tokens;
+        }
+        if other.temperature.is_some() {
+            self.temperature = other.temperature;
+        }
+        if other.top_p.is_some() {
+            self.top_p = other.top_p;
+        }
+        if other.top_k.is_some() {
+            self.top_k = other.top_k;
+        }
+        if !other.stop_sequences.is_empty() {
+            self.stop_sequences = other.stop_sequences.clone();
+        }
+    }
+
+    // Apply `/set key value`. An empty value or `default` clears the key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let clear = value.is_empty() || value == "default";
+
+        match key {
+            "max_tokens" => {
+                self.max_tokens = if clear {
+                    None
+                } else {
+                    Some(parse(key, value)?)
+                };
+            }
+            "temperature" => {
+                self.temperature = if clear {
+                    None
+                } else {
+                    Some(parse(key, value)?)
+                };
+            }
+            "top_p" => {
+                self.top_p = if clear {
+                    None
+                } else {
+                    Some(parse(key, value)?)
+                };
+            }
+            "top_k" => {
+                self.top_k = if clear {
+                    None
+                } else {
+                    Some(parse(key, value)?)
+                };
+            }
+            "stop" | "stop_sequences" => {
+                self.stop_sequences = if clear {
+                    Vec::new()
+                } else {
+                    value.split(',').map(|s| unescape(s.trim())).collect()
+                };
+            }
+            other => {
+                return Err(format!(
+                    "Unknown parameter '{}'. Known parameters: {}",
+                    other,
+                    PARAM_KEYS.join(", ")
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self, model: &LlmModel) -> Result<(), String> {
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens == 0 || max_tokens > model.max_output_tokens {
+                return Err(format!(
+                    "max_tokens must be between 1 and {} for {}",
+                    model.max_output_tokens, model.display_name
+                ));
+            }
+        }
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=1.0).contains(&temperature) {
+                return Err("temperature must be between 0.0 and 1.0".to_string());
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err("top_p must be between 0.0 and 1.0".to_string());
+            }
+        }
+        if self.top_k == Some(0) {
+            return Err("top_k must be at least 1".to_string());
+        }
+        if self.stop_sequences.iter().any(|s| s.trim().is_empty()) {
+            return Err("stop sequences must contain non-whitespace characters".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for SamplingParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn show<T: fmt::Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "default".to_string())
+        }
+
+        writeln!(f, "  max_tokens      {}", show(&self.max_tokens))?;
+        writeln!(f, "  temperature     {}", show(&self.temperature))?;
+        writeln!(f, "  top_p           {}", show(&self.top_p))?;
+        writeln!(f, "  top_k           {}", show(&self.top_k))?;
+        if self.stop_sequences.is_empty() {
+            writeln!(f, "  stop            none")
+        } else {
+            writeln!(f, "  stop            {:?}", self.stop_sequences)
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{}' for {}", value, key))
+}
+
+// Lets `/set stop \n\nHuman:` express newlines on a single input line
+fn unescape(value: &str) -> String {
+    value.replace("\\n", "\n").replace("\\t", "\t")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_registry::ModelRegistry;
+
+    fn params(sets: &[(&str, &str)]) -> SamplingParams {
+        let mut params = SamplingParams::default();
+        for (key, value) in sets {
+            params.set(key, value).unwrap();
+        }
+        params
+    }
+
+    #[test]
+    fn set_parses_and_clears_values() {
+        let mut params = params(&[
+            ("max_tokens", "1000"),
+            ("temperature", " 0.5 "),
+            ("stop", "END, \\n\\nHuman:"),
+        ]);
+        assert_eq!(params.max_tokens, Some(1000));
+        assert_eq!(params.temperature, Some(0.5));
+        assert_eq!(params.stop_sequences, ["END", "\n\nHuman:"]);
+
+        params.set("temperature", "default").unwrap();
+        params.set("stop", "").unwrap();
+        assert_eq!(params.temperature, None);
+        assert!(params.stop_sequences.is_empty());
+
+        assert!(params.set("top_k", "-1").is_err());
+        assert!(params.set("temperature", "warm").is_err());
+        assert!(params.set("top_q", "1").unwrap_err().contains("top_p"));
+        assert_eq!(params, self::params(&[("max_tokens", "1000")]));
+    }
+
+    #[test]
+    fn validate_checks_ranges_against_the_model() {
+        let registry = ModelRegistry::new();
+        let model = registry.get_current_model();
+        assert!(SamplingParams::default().validate(model).is_ok());
+
+        let limit = model.max_output_tokens.to_string();
+        let over = (model.max_output_tokens + 1).to_string();
+        for (key, value) in [
+            ("max_tokens", limit.as_str()),
+            ("temperature", "0"),
+            ("temperature", "1"),
+            ("top_p", "1.0"),
+            ("top_k", "1"),
+        ] {
+            assert!(
+                params(&[(key, value)]).validate(model).is_ok(),
+                "{} {}",
+                key,
+                value
+            );
+        }
+        for (key, value) in [
+            ("max_tokens", "0"),
+            ("max_tokens", over.as_str()),
+            ("temperature", "1.1"),
+            ("temperature", "-0.1"),
+            ("top_p", "2"),
+            ("top_k", "0"),
+            ("stop", "a, "),
+        ] {
+            assert!(
+                params(&[(key, value)]).validate(model).is_err(),
+                "{} {}",
+                key,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn merged_values_win_and_unset_ones_are_kept() {
+        // Saved defaults, then flags given for one run
+        let mut params = params(&[("temperature", "0.2"), ("top_k", "40"), ("stop", "END")]);
+        params.merge(&self::params(&[
+            ("temperature", "0.9"),
+            ("max_tokens", "50"),
+        ]));
+
+        assert_eq!(params.temperature, Some(0.9));
+        assert_eq!(params.max_tokens, Some(50));
+        assert_eq!(params.top_k, Some(40));
+        assert_eq!(params.stop_sequences, ["END"]);
+
+        let registry = ModelRegistry::new();
+        let model = registry.get_current_model();
+        assert_eq!(params.max_tokens_for(model), 50);
+        assert_eq!(
+            SamplingParams::default().max_tokens_for(model),
+            model.default_max_tokens
+        );
+    }
+}
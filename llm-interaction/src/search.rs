@@ -0,0 +1,491 @@
+use crate::conversation::{Conversation, ConversationStore, Role};
+use crate::usage;
+use chrono::{DateTime, NaiveDate, Utc};
+use crossterm::style::Stylize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+const DEFAULT_LIMIT: usize = 10;
+// Characters of context shown before the first match
+const SNIPPET_BEFORE: usize = 60;
+const SNIPPET_CHARS: usize = 160;
+
+// Postings of one conversation, kept in `index/<id>.json` next to the
+// conversations. Saving a conversation rewrites only its own segment, and
+// only indexes the turns that changed since it was last saved; a search
+// reads every segment.
+#[derive(Serialize, Deserialize, Default)]
+struct Segment {
+    // Fingerprints of the indexed turns
+    turns: Vec<u64>,
+    // (turn, occurrences) of each term
+    terms: HashMap<String, Vec<(u32, u32)>>,
+}
+
+impl Segment {
+    fn load(path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        // Written aside first so an interrupted save or another session
+        // saving at the same time can't leave half a segment
+        let partial = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&partial, serde_json::to_string(self)?)?;
+        fs::rename(&partial, path)
+    }
+
+    // Index the turns that are new or differ from what was indexed before
+    fn update(&mut self, conversation: &Conversation) {
+        let fingerprints: Vec<u64> = conversation
+            .turns
+            .iter()
+            .map(|turn| fingerprint(&turn.text))
+            .collect();
+        let unchanged = self
+            .turns
+            .iter()
+            .zip(&fingerprints)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if unchanged < self.turns.len() {
+            for postings in self.terms.values_mut() {
+                postings.retain(|&(turn, _)| (turn as usize) < unchanged);
+            }
+            self.terms.retain(|_, postings| !postings.is_empty());
+        }
+
+        for (i, turn) in conversation.turns.iter().enumerate().skip(unchanged) {
+            let mut counts: HashMap<String, u32> = HashMap::new();
+            for (_, word) in words(&turn.text) {
+                *counts.entry(word.to_lowercase()).or_default() += 1;
+            }
+            for (term, count) in counts {
+                self.terms.entry(term).or_default().push((i as u32, count));
+            }
+        }
+        self.turns = fingerprints;
+    }
+}
+
+// Inverted index over every saved turn, one segment per conversation
+#[derive(Default)]
+pub struct SearchIndex {
+    segments: HashMap<String, Segment>,
+}
+
+pub struct Hit {
+    pub conversation: String,
+    pub turn: usize,
+    pub score: f64,
+}
+
+impl SearchIndex {
+    // Every saved segment. Conversations without one, e.g. saved before
+    // there was an index, are indexed now.
+    pub fn open(store: &ConversationStore) -> io::Result<Self> {
+        let dir = store.index_dir();
+        let mut index = SearchIndex::default();
+        for id in store.ids()? {
+            let path = dir.join(format!("{}.json", id));
+            if let Some(segment) = Segment::load(&path) {
+                index.segments.insert(id, segment);
+                continue;
+            }
+            match store.load(&id) {
+                Ok(conversation) => {
+                    let mut segment = Segment::default();
+                    segment.update(&conversation);
+                    fs::create_dir_all(&dir)?;
+                    segment.save(&path)?;
+                    index.segments.insert(id, segment);
+                }
+                Err(e) => eprintln!("Warning: {}", e),
+            }
+        }
+        Ok(index)
+    }
+
+    // Bring the saved segment of a conversation up to date
+    pub fn save_conversation(
+        store: &ConversationStore,
+        conversation: &Conversation,
+    ) -> io::Result<()> {
+        let dir = store.index_dir();
+        let path = dir.join(format!("{}.json", conversation.id));
+        let mut segment = Segment::load(&path).unwrap_or_default();
+        segment.update(conversation);
+        fs::create_dir_all(&dir)?;
+        segment.save(&path)
+    }
+
+    // Turns containing every term, best first: each term counts by how
+    // often it occurs and how rare it is across all turns (tf-idf)
+    pub fn search(&self, terms: &[String]) -> Vec<Hit> {
+        let total: usize = self.segments.values().map(|s| s.turns.len()).sum();
+        let mut scores: HashMap<(&str, u32), (usize, f64)> = HashMap::new();
+        for term in terms {
+            let postings: Vec<(&str, u32, u32)> = self
+                .segments
+                .iter()
+                .filter_map(|(id, segment)| Some((id, segment.terms.get(term)?)))
+                .flat_map(|(id, postings)| {
+                    postings
+                        .iter()
+                        .map(move |&(turn, count)| (id.as_str(), turn, count))
+                })
+                .collect();
+            if postings.is_empty() {
+                return Vec::new();
+            }
+            let idf = (1.0 + total as f64 / postings.len() as f64).ln();
+            for (id, turn, count) in postings {
+                let score = scores.entry((id, turn)).or_default();
+                score.0 += 1;
+                score.1 += (1.0 + (count as f64).ln()) * idf;
+            }
+        }
+
+        let mut hits: Vec<Hit> = scores
+            .into_iter()
+            .filter(|(_, (matched, _))| *matched == terms.len())
+            .map(|((id, turn), (_, score))| Hit {
+                conversation: id.to_string(),
+                turn: turn as usize,
+                score,
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits
+    }
+}
+
+// FNV-1a, which unlike the std hasher stays the same between builds
+fn fingerprint(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Runs of letters and digits with their byte offsets
+fn words(text: &str) -> Vec<(usize, &str)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                words.push((s, &text[s..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, &text[s..]));
+    }
+    words
+}
+
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    // Part of the model id
+    pub model: Option<String>,
+    pub role: Option<Role>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    pub fn new(text: &str) -> Self {
+        SearchQuery {
+            terms: words(text)
+                .into_iter()
+                .map(|(_, word)| word.to_lowercase())
+                .collect(),
+            model: None,
+            role: None,
+            since: None,
+            until: None,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+
+    // `/search` arguments: words to find plus the same options as
+    // `agent search`, e.g. `tokio select --model sonnet --since 7d`
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let mut text = Vec::new();
+        let mut query = SearchQuery::new("");
+        let mut args = args.split_whitespace();
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix("--") else {
+                text.push(arg);
+                continue;
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| format!("--{} needs a value", option))?;
+            match option {
+                "model" => query.model = Some(value.to_string()),
+                "role" => {
+                    query.role = Some(match value {
+                        "user" => Role::User,
+                        "assistant" => Role::Assistant,
+                        _ => {
+                            return Err(format!("Unknown role '{}': use user or assistant", value))
+                        }
+                    })
+                }
+                "since" => query.since = Some(usage::parse_since(value)?),
+                "until" => query.until = Some(parse_until(value)?),
+                "limit" => {
+                    query.limit = value
+                        .parse()
+                        .map_err(|_| format!("Invalid --limit value '{}'", value))?
+                }
+                _ => return Err(format!("Unknown option --{}", option)),
+            }
+        }
+        query.terms = SearchQuery::new(&text.join(" ")).terms;
+        Ok(query)
+    }
+}
+
+// Like `--since`, but a plain date includes the whole day
+pub fn parse_until(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d") {
+        return Ok(date
+            .succ_opt()
+            .unwrap_or(date)
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc());
+    }
+    usage::parse_since(value).map_err(|_| {
+        format!(
+            "Invalid --until value '{}': use YYYY-MM-DD, RFC 3339, or e.g. 7d / 12h",
+            value
+        )
+    })
+}
+
+// Print the best matches with a snippet of each; matched words are
+// highlighted when `highlight` is set
+pub fn run(
+    store: &ConversationStore,
+    query: &SearchQuery,
+    highlight: bool,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    if query.terms.is_empty() {
+        writeln!(out, "Nothing to search for.")?;
+        return Ok(());
+    }
+
+    let index = SearchIndex::open(store)?;
+    let mut conversations: HashMap<String, Option<Conversation>> = HashMap::new();
+    let mut shown = 0;
+    for hit in index.search(&query.terms) {
+        let conversation = conversations
+            .entry(hit.conversation.clone())
+            .or_insert_with(|| store.load(&hit.conversation).ok());
+        let Some(conversation) = conversation else {
+            continue;
+        };
+        let Some(turn) = conversation.turns.get(hit.turn) else {
+            continue;
+        };
+        if query.role.is_some_and(|role| role != turn.role)
+            || query
+                .model
+                .as_ref()
+                .is_some_and(|model| !turn.model.contains(model.as_str()))
+            || query.since.is_some_and(|since| turn.timestamp < since)
+            || query.until.is_some_and(|until| turn.timestamp >= until)
+        {
+            continue;
+        }
+
+        writeln!(
+            out,
+            "{}  {}  {:<9}  {}  {}",
+            conversation.id,
+            turn.timestamp.format("%Y-%m-%d %H:%M"),
+            turn.role.as_str(),
+            turn.model,
+            conversation.title
+        )?;
+        writeln!(out, "    {}", snippet(&turn.text, &query.terms, highlight))?;
+        shown += 1;
+        if shown == query.limit {
+            break;
+        }
+    }
+
+    if shown == 0 {
+        writeln!(out, "No matches.")?;
+    } else {
+        writeln!(out, "Resume a conversation with `agent --resume <id>`.")?;
+    }
+    Ok(())
+}
+
+// A window of `text` around the first match, on one line
+fn snippet(text: &str, terms: &[String], highlight: bool) -> String {
+    let words = words(text);
+    let is_match = |word: &str| terms.contains(&word.to_lowercase());
+    let first = words
+        .iter()
+        .find(|(_, word)| is_match(word))
+        .map_or(0, |(offset, _)| *offset);
+
+    let before = text[..first].chars().rev().take(SNIPPET_BEFORE).count();
+    let start = text[..first]
+        .char_indices()
+        .rev()
+        .nth(before.saturating_sub(1))
+        .map_or(first, |(i, _)| i);
+    let end = text[start..]
+        .char_indices()
+        .nth(SNIPPET_CHARS)
+        .map_or(text.len(), |(i, _)| start + i);
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let mut last = start;
+    for (offset, word) in words
+        .iter()
+        .filter(|(offset, word)| *offset >= start && offset + word.len() <= end)
+    {
+        snippet.push_str(&text[last..*offset]);
+        if highlight && is_match(word) {
+            snippet.push_str(&word.bold().yellow().to_string());
+        } else {
+            snippet.push_str(word);
+        }
+        last = offset + word.len();
+    }
+    snippet.push_str(&text[last..end]);
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation(exchanges: &[(&str, &str)]) -> Conversation {
+        let mut conversation = Conversation::new();
+        for (prompt, answer) in exchanges {
+            conversation.add_exchange(prompt, answer, "claude-test", None, false);
+        }
+        conversation
+    }
+
+    fn index(conversations: &[&Conversation]) -> SearchIndex {
+        let mut index = SearchIndex::default();
+        for conversation in conversations {
+            index
+                .segments
+                .entry(conversation.id.clone())
+                .or_default()
+                .update(conversation);
+        }
+        index
+    }
+
+    fn terms(text: &str) -> Vec<String> {
+        SearchQuery::new(text).terms
+    }
+
+    fn found(index: &SearchIndex, text: &str) -> Vec<(String, usize)> {
+        index
+            .search(&terms(text))
+            .into_iter()
+            .map(|hit| (hit.conversation, hit.turn))
+            .collect()
+    }
+
+    #[test]
+    fn matches_every_term_best_first() {
+        let a = conversation(&[("How does tokio select work?", "Select select select.")]);
+        let b = conversation(&[("Select a crate", "Serde, for one.")]);
+        let index = index(&[&a, &b]);
+
+        let hits = found(&index, "select");
+        assert_eq!(hits.len(), 3);
+        assert_eq!(hits[0], (a.id.clone(), 1));
+        assert_eq!(found(&index, "Tokio SELECT"), vec![(a.id.clone(), 0)]);
+        assert!(found(&index, "tokio serde").is_empty());
+        assert!(found(&index, "missing").is_empty());
+    }
+
+    #[test]
+    fn update_reindexes_only_what_changed() {
+        let mut a = conversation(&[("alpha", "beta"), ("gamma", "delta")]);
+        let mut index = index(&[&a]);
+
+        a.turns[1].text = "epsilon".to_string();
+        index.segments.get_mut(&a.id).unwrap().update(&a);
+        assert!(found(&index, "beta").is_empty());
+        assert_eq!(found(&index, "epsilon"), vec![(a.id.clone(), 1)]);
+        assert_eq!(found(&index, "alpha"), vec![(a.id.clone(), 0)]);
+        assert_eq!(found(&index, "delta"), vec![(a.id.clone(), 3)]);
+
+        a.turns.truncate(2);
+        index.segments.get_mut(&a.id).unwrap().update(&a);
+        assert!(found(&index, "delta").is_empty());
+        assert_eq!(found(&index, "epsilon"), vec![(a.id.clone(), 1)]);
+    }
+
+    #[test]
+    fn conversations_saved_apart_are_all_found() {
+        let dir = std::env::temp_dir().join(format!("agent-search-{}", std::process::id()));
+        let store = ConversationStore::new(dir.clone());
+        let a = conversation(&[("first session", "reply")]);
+        let b = conversation(&[("second session", "reply")]);
+        store.save(&a).unwrap();
+        store.save(&b).unwrap();
+
+        let mut hits = found(&SearchIndex::open(&store).unwrap(), "session");
+        hits.sort();
+        let mut expected = vec![(a.id.clone(), 0), (b.id.clone(), 0)];
+        expected.sort();
+        assert_eq!(hits, expected);
+
+        // Conversations saved without a segment are indexed when opened
+        fs::remove_dir_all(store.index_dir()).unwrap();
+        assert_eq!(
+            found(&SearchIndex::open(&store).unwrap(), "session").len(),
+            2
+        );
+        assert!(store.index_dir().join(format!("{}.json", a.id)).is_file());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snippet_shows_the_first_match_in_context() {
+        assert_eq!(
+            snippet("a  short\n\ntext", &terms("short"), false),
+            "a short text"
+        );
+
+        let text = format!("{} needle {}", "é ".repeat(100), "tail ".repeat(100));
+        let shown = snippet(&text, &terms("needle"), false);
+        assert!(shown.starts_with('…') && shown.ends_with('…'));
+        assert!(shown.contains("needle"));
+        assert!(shown.chars().count() <= SNIPPET_CHARS + 2);
+
+        let highlighted = snippet("find the Needle", &terms("needle"), true);
+        assert!(highlighted.contains(&"Needle".bold().yellow().to_string()));
+    }
+}
@@ -0,0 +1,260 @@
+use crate::modules::{ToolChoice, ToolDefinition};
+use jsonschema::Validator;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+
+// The single tool the model is forced to answer through
+pub const TOOL_NAME: &str = "respond";
+
+// Tries in total before giving up on an answer that doesn't validate
+pub const MAX_ATTEMPTS: u32 = 3;
+
+// A JSON Schema loaded from `--schema`. Tool inputs must be objects, so a
+// schema that allows anything else is wrapped in `{"value": ...}` and the
+// answer unwrapped again afterwards.
+
+// Keywords that only constrain objects, for schemas that leave out `type`
+const OBJECT_KEYWORDS: [&str; 6] = [
+    "properties",
+    "required",
+    "additionalProperties",
+    "patternProperties",
+    "minProperties",
+    "maxProperties",
+];
+
+// Keywords that belong at the root of the document, with the definitions
+// `$ref`s point into
+const ROOT_KEYWORDS: [&str; 4] = ["$schema", "$id", "$defs", "definitions"];
+
+pub struct OutputSchema {
+    input_schema: Value,
+    wrapped: bool,
+    validator: Validator,
+}
+
+impl OutputSchema {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read schema {}: {}", path.display(), e))?;
+        let schema: Value = serde_json::from_str(&contents)
+            .map_err(|e| format!("Schema {} is not valid JSON: {}", path.display(), e))?;
+        Self::new(schema)
+    }
+
+    pub fn new(schema: Value) -> Result<Self, String> {
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| format!("Invalid JSON Schema: {}", e))?;
+
+        let wrapped = !describes_object(&schema);
+        let input_schema = if wrapped {
+            wrap(schema)
+        } else {
+            // The tool's input is an object either way
+            let mut schema = schema;
+            schema["type"] = json!("object");
+            schema
+        };
+
+        Ok(OutputSchema {
+            input_schema,
+            wrapped,
+            validator,
+        })
+    }
+
+    pub fn tool(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: TOOL_NAME.to_string(),
+            description:
+                "Give your answer by calling this tool with input that matches its schema."
+                    .to_string(),
+            input_schema: self.input_schema.clone(),
+        }
+    }
+
+    pub fn tool_choice(&self) -> ToolChoice {
+        ToolChoice {
+            choice_type: "tool".to_string(),
+            name: Some(TOOL_NAME.to_string()),
+        }
+    }
+
+    #[cfg(test)]
+    fn input_schema(&self) -> &Value {
+        &self.input_schema
+    }
+
+    // Check a tool input against the schema and return the answer it carries.
+    // The error lists every violation so it can be handed back to the model.
+    pub fn validate(&self, input: &Value) -> Result<Value, String> {
+        let value = if self.wrapped {
+            input
+                .get("value")
+                .cloned()
+                .ok_or_else(|| "the input is missing the required \"value\" field".to_string())?
+        } else {
+            input.clone()
+        };
+
+        let errors: Vec<String> = self
+            .validator
+            .iter_errors(&value)
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                if path.is_empty() {
+                    e.to_string()
+                } else {
+                    format!("at {}: {}", path, e)
+                }
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+// Whether the schema only ever accepts objects, with `type` given as a
+// string, an array or left out
+fn describes_object(schema: &Value) -> bool {
+    match schema.get("type") {
+        Some(Value::String(kind)) => kind == "object",
+        Some(Value::Array(kinds)) => kinds.iter().all(|kind| kind == "object"),
+        Some(_) => false,
+        None => OBJECT_KEYWORDS.iter().any(|key| schema.get(key).is_some()),
+    }
+}
+
+// `{"value": schema}` as a document of its own. The definitions move up to
+// the new root, where `#/$defs/...` refs still find them; refs to anything
+// else in the schema point into `value` instead.
+fn wrap(mut schema: Value) -> Value {
+    rewrite_refs(&mut schema);
+    let mut root = json!({
+        "type": "object",
+        "required": ["value"],
+    });
+    if let Some(fields) = schema.as_object_mut() {
+        for key in ROOT_KEYWORDS {
+            if let Some(value) = fields.remove(key) {
+                root[key] = value;
+            }
+        }
+    }
+    root["properties"] = json!({ "value": schema });
+    root
+}
+
+fn rewrite_refs(schema: &mut Value) {
+    match schema {
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                match value {
+                    Value::String(target) if key == "$ref" => {
+                        if let Some(pointer) = target.strip_prefix('#') {
+                            let definition = ["/$defs/", "/definitions/"]
+                                .iter()
+                                .any(|prefix| pointer.starts_with(prefix));
+                            if !definition {
+                                *target = format!("#/properties/value{}", pointer);
+                            }
+                        }
+                    }
+                    value => rewrite_refs(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(rewrite_refs),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_schemas_are_used_as_they_are() {
+        for schema in [
+            json!({"type": "object", "properties": {"a": {"type": "string"}}}),
+            json!({"type": ["object"], "required": ["a"]}),
+            json!({"properties": {"a": {"type": "string"}}}),
+        ] {
+            let output = OutputSchema::new(schema.clone()).unwrap();
+            assert!(!output.wrapped, "{}", schema);
+            assert_eq!(output.input_schema()["type"], "object");
+            assert!(output.validate(&json!({"a": "x"})).is_ok());
+        }
+    }
+
+    #[test]
+    fn other_schemas_are_wrapped_and_unwrapped() {
+        for schema in [
+            json!({"type": "array", "items": {"type": "integer"}}),
+            json!({"type": ["array", "null"]}),
+            json!({"enum": [[1, 2], "x"]}),
+        ] {
+            let output = OutputSchema::new(schema.clone()).unwrap();
+            assert!(output.wrapped, "{}", schema);
+            assert_eq!(
+                output.validate(&json!({"value": [1, 2]})).unwrap(),
+                json!([1, 2])
+            );
+            assert!(output.validate(&json!({"other": [1, 2]})).is_err());
+        }
+
+        // Objects, but null too
+        let output = OutputSchema::new(json!({"type": ["object", "null"]})).unwrap();
+        assert!(output.wrapped);
+        assert_eq!(
+            output.validate(&json!({"value": null})).unwrap(),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn wrapping_keeps_refs_working() {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "array",
+            "items": {"$ref": "#/$defs/node"},
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "name": {"type": "string"},
+                        "children": {"$ref": "#"},
+                        "legacy": {"$ref": "#/definitions/flag"}
+                    },
+                    "required": ["name"]
+                }
+            },
+            "definitions": {"flag": {"type": "boolean"}}
+        });
+        let output = OutputSchema::new(schema).unwrap();
+        let input = output.input_schema();
+        assert!(input["$defs"]["node"].is_object());
+        assert_eq!(
+            input["$schema"],
+            "https://json-schema.org/draft/2020-12/schema"
+        );
+        assert_eq!(
+            input["$defs"]["node"]["properties"]["children"]["$ref"],
+            "#/properties/value"
+        );
+
+        // The wrapped schema, as the model sees it, accepts what the original does
+        let validator = jsonschema::validator_for(input).unwrap();
+        let good = json!([{"name": "a", "legacy": true, "children": [{"name": "b"}]}]);
+        let bad = json!([{"name": "a", "children": [{"legacy": 1}]}]);
+        assert!(validator.is_valid(&json!({"value": good})));
+        assert!(!validator.is_valid(&json!({"value": bad})));
+        assert!(output.validate(&json!({"value": good})).is_ok());
+        assert!(output.validate(&json!({"value": bad})).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Front matter at the top of a prompt file, between `---` lines
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TemplateMeta {
+    pub description: Option<String>,
+    // Model id or alias; the current model when unset
+    pub model: Option<String>,
+    pub system: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    // Variables that must be given with --var
+    pub vars: Vec<String>,
+    // Values for variables that may be left out
+    pub defaults: HashMap<String, String>,
+    // Argument hint shown by /help for slash commands
+    pub args: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub path: PathBuf,
+    pub meta: TemplateMeta,
+    pub body: String,
+}
+
+impl PromptTemplate {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let (meta, body) = parse_front_matter(&contents)
+            .map_err(|e| format!("Invalid front matter in {}: {}", path.display(), e))?;
+
+        Ok(PromptTemplate {
+            name: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            path: path.to_path_buf(),
+            meta,
+            body,
+        })
+    }
+
+    pub fn uses_stdin(&self) -> bool {
+        placeholders(&self.body).any(|p| p == "stdin")
+    }
+
+    fn value_of(&self, vars: &HashMap<String, String>, name: &str) -> Option<String> {
+        vars.get(name)
+            .or_else(|| self.meta.defaults.get(name))
+            .cloned()
+    }
+
+    // Fails listing every variable that is required or used but has no value
+    pub fn check_vars(&self, vars: &HashMap<String, String>) -> Result<(), String> {
+        let mut missing: Vec<&str> = self
+            .meta
+            .vars
+            .iter()
+            .map(String::as_str)
+            .chain(placeholders(&self.body).filter(|p| *p != "stdin" && !p.starts_with("file:")))
+            .filter(|name| self.value_of(vars, name).is_none())
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        missing.sort();
+        missing.dedup();
+        Err(format!(
+            "{} needs {}",
+            self.name,
+            missing
+                .iter()
+                .map(|name| format!("--var {}=...", name))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ))
+    }
+
+    // Fill in `{{var}}`, `{{stdin}}` and `{{file:path}}`. Relative file paths
+    // are resolved from the working directory.
+    pub fn render(
+        &self,
+        vars: &HashMap<String, String>,
+        stdin: Option<&str>,
+    ) -> Result<String, String> {
+        self.check_vars(vars)?;
+
+        let mut out = String::with_capacity(self.body.len());
+        let mut rest = self.body.as_str();
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            out.push_str(&rest[..start]);
+            let key = rest[start + 2..start + 2 + len].trim();
+
+            if key == "stdin" {
+                let Some(stdin) = stdin else {
+                    return Err(format!("{} expects input on stdin", self.name));
+                };
+                out.push_str(stdin.trim_end());
+            } else if let Some(path) = key.strip_prefix("file:") {
+                let path = path.trim();
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read {}: {}", path, e))?;
+                out.push_str(contents.trim_end());
+            } else {
+                // Checked above, every other placeholder has a value
+                out.push_str(&self.value_of(vars, key).unwrap_or_default());
+            }
+
+            rest = &rest[start + 2 + len + 2..];
+        }
+        out.push_str(rest);
+
+        Ok(out.trim().to_string())
+    }
+}
+
+// Split a prompt file into front matter and body. A `#!` line at the top is
+// skipped so prompt files can be run directly as scripts.
+pub fn parse_front_matter<T: for<'de> Deserialize<'de> + Default>(
+    contents: &str,
+) -> Result<(T, String), String> {
+    let mut contents = contents;
+    if contents.starts_with("#!") {
+        contents = contents
+            .split_once('\n')
+            .map(|(_, rest)| rest)
+            .unwrap_or("");
+    }
+
+    let Some(after_open) = contents
+        .strip_prefix("---\n")
+        .or_else(|| contents.strip_prefix("---\r\n"))
+    else {
+        return Ok((T::default(), contents.to_string()));
+    };
+
+    let (yaml, body) = if let Some(body) = after_open.strip_prefix("---") {
+        ("", body)
+    } else {
+        match after_open.find("\n---") {
+            Some(end) => (&after_open[..end], &after_open[end + 4..]),
+            None => return Err("front matter is never closed with ---".to_string()),
+        }
+    };
+    let body = body.split_once('\n').map(|(_, rest)| rest).unwrap_or("");
+
+    let meta = if yaml.trim().is_empty() {
+        T::default()
+    } else {
+        serde_yaml::from_str(yaml).map_err(|e| e.to_string())?
+    };
+    Ok((meta, body.to_string()))
+}
+
+// Names inside `{{ }}`, trimmed
+fn placeholders(body: &str) -> impl Iterator<Item = &str> {
+    body.split("{{")
+        .skip(1)
+        .filter_map(|part| part.split_once("}}").map(|(key, _)| key.trim()))
+}
+
+// Parse `--var key=value` arguments
+pub fn parse_vars(vars: &[String]) -> Result<HashMap<String, String>, String> {
+    vars.iter()
+        .map(|var| {
+            var.split_once('=')
+                .map(|(k, v)| (k.trim().to_string(), v.to_string()))
+                .ok_or_else(|| format!("Invalid --var '{}': expected key=value", var))
+        })
+        .collect()
+}
+
+// Variables for a slash command: `{{args}}` is everything after the command,
+// `{{1}}`, `{{2}}`... each word
+pub fn arg_vars(args: &str) -> HashMap<String, String> {
+    let mut vars: HashMap<String, String> = args
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, arg)| ((i + 1).to_string(), arg.to_string()))
+        .collect();
+    vars.insert("args".to_string(), args.to_string());
+    vars
+}
+
+// A path to a prompt file, or the name of one in the prompt directories
+pub fn find(name: &str, dirs: &[PathBuf]) -> Result<PromptTemplate, String> {
+    let path = Path::new(name);
+    if path.is_file() {
+        return PromptTemplate::load(path);
+    }
+
+    let file_name = if name.ends_with(".md") {
+        name.to_string()
+    } else {
+        format!("{}.md", name)
+    };
+    dirs.iter()
+        .map(|dir| dir.join(&file_name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| format!("No prompt named '{}'. See `agent run` for the list.", name))
+        .and_then(|path| PromptTemplate::load(&path))
+}
+
+// Every template in the prompt directories; earlier directories win on name
+pub fn list(dirs: &[PathBuf]) -> Vec<PromptTemplate> {
+    let mut templates: Vec<PromptTemplate> = Vec::new();
+
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "md"))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            match PromptTemplate::load(&path) {
+                Ok(template) => {
+                    if !templates.iter().any(|t| t.name == template.name) {
+                        templates.push(template);
+                    }
+                }
+                Err(e) => eprintln!("Skipping {}", e),
+            }
+        }
+    }
+
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    templates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(contents: &str) -> PromptTemplate {
+        let (meta, body) = parse_front_matter(contents).unwrap();
+        PromptTemplate {
+            name: "test".to_string(),
+            path: PathBuf::from("test.md"),
+            meta,
+            body,
+        }
+    }
+
+    #[test]
+    fn front_matter_is_split_from_the_body() {
+        let template = template(
+            "#!/usr/bin/env agent run\n---\ndescription: Review\nmodel: haiku\nvars: [lang]\ndefaults:\n  tone: short\n---\nReview this {{lang}}.\n",
+        );
+        assert_eq!(template.meta.description.as_deref(), Some("Review"));
+        assert_eq!(template.meta.model.as_deref(), Some("haiku"));
+        assert_eq!(template.meta.vars, ["lang"]);
+        assert_eq!(template.meta.defaults["tone"], "short");
+        assert_eq!(template.body, "Review this {{lang}}.\n");
+    }
+
+    #[test]
+    fn files_without_front_matter_are_all_body() {
+        let (meta, body) = parse_front_matter::<TemplateMeta>("Just a prompt\n---\n").unwrap();
+        assert!(meta.description.is_none());
+        assert_eq!(body, "Just a prompt\n---\n");
+
+        let (_, body) = parse_front_matter::<TemplateMeta>("---\r\n---\r\nBody").unwrap();
+        assert_eq!(body, "Body");
+    }
+
+    #[test]
+    fn bad_front_matter_is_an_error() {
+        assert!(parse_front_matter::<TemplateMeta>("---\nmodel: haiku\nBody").is_err());
+        assert!(parse_front_matter::<TemplateMeta>("---\nvars: 3\n---\nBody").is_err());
+    }
+
+    #[test]
+    fn command_arguments_fill_args_and_numbered_words() {
+        let template = template("Translate {{ 2 }} to {{1}}: {{args}}");
+        let vars = arg_vars("french  hello world");
+        assert_eq!(
+            template.render(&vars, None).unwrap(),
+            "Translate hello to french: french  hello world"
+        );
+
+        // Words past the last argument have no value
+        assert_eq!(
+            template.check_vars(&arg_vars("french")).unwrap_err(),
+            "test needs --var 2=..."
+        );
+        assert_eq!(arg_vars("")["args"], "");
+    }
+
+    #[test]
+    fn defaults_and_stdin_fill_placeholders() {
+        let template =
+            template("---\ndefaults:\n  tone: short\n---\n{{tone}} {{who}}\n\n{{stdin}}\n");
+        let mut vars = parse_vars(&["who=everyone".to_string()]).unwrap();
+        assert!(template.uses_stdin());
+        assert_eq!(
+            template.render(&vars, Some("input\n\n")).unwrap(),
+            "short everyone\n\ninput"
+        );
+        assert!(template.render(&vars, None).is_err());
+
+        vars.insert("tone".to_string(), "long".to_string());
+        assert!(template
+            .render(&vars, Some(""))
+            .unwrap()
+            .starts_with("long"));
+        assert!(parse_vars(&["novalue".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::tui::{BorderStyle, UiStyle};
+use crossterm::style::Color;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+// The dark theme is `UiStyle::default()`
+pub const DEFAULT_THEME: &str = "dark";
+
+const BUILTIN_THEMES: [(&str, &str); 3] = [
+    ("dark", ""),
+    (
+        "light",
+        r##"
+[colors]
+user_fg = "black"
+user_bg = "#e8e8e8"
+model_fg = "black"
+model_bg = "#dcebfa"
+prompt = "dark_blue"
+status = "dark_grey"
+"##,
+    ),
+    (
+        "high-contrast",
+        r##"
+border = "bold"
+
+[colors]
+user_fg = "white"
+user_bg = "black"
+model_fg = "yellow"
+model_bg = "black"
+prompt = "white"
+status = "white"
+"##,
+    ),
+];
+
+// A theme file. Anything left out is taken from the dark theme.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ThemeFile {
+    // single, double, rounded or bold
+    border: Option<String>,
+    emoji: Option<bool>,
+    // `{model}` is replaced with the model's display name
+    prompt: Option<String>,
+    colors: ThemeColors,
+}
+
+// Color names (`cyan`, `dark_grey`, ...), `#rrggbb` or an ANSI number
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ThemeColors {
+    user_fg: Option<String>,
+    user_bg: Option<String>,
+    model_fg: Option<String>,
+    model_bg: Option<String>,
+    prompt: Option<String>,
+    status: Option<String>,
+}
+
+// Built-in themes followed by the `.toml` files in `dir`
+pub fn names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = BUILTIN_THEMES
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect();
+
+    let mut files: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+        .filter(|name| !names.contains(name))
+        .collect();
+    files.sort();
+    names.extend(files);
+    names
+}
+
+// `dir/<name>.toml`, or the built-in theme of that name
+pub fn load(name: &str, dir: &Path) -> Result<UiStyle, String> {
+    let path = dir.join(format!("{}.toml", name));
+    if path.is_file() {
+        let contents = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        return parse(&contents).map_err(|e| format!("Invalid theme {}: {}", path.display(), e));
+    }
+
+    match BUILTIN_THEMES.iter().find(|(builtin, _)| *builtin == name) {
+        Some((_, contents)) => parse(contents),
+        None => Err(format!(
+            "Unknown theme '{}' (available: {})",
+            name,
+            names(dir).join(", ")
+        )),
+    }
+}
+
+fn parse(contents: &str) -> Result<UiStyle, String> {
+    let file: ThemeFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+    let mut style = UiStyle::default();
+
+    if let Some(border) = &file.border {
+        style.border_style = match border.as_str() {
+            "single" => BorderStyle::Single,
+            "double" => BorderStyle::Double,
+            "rounded" => BorderStyle::Rounded,
+            "bold" => BorderStyle::Bold,
+            other => {
+                return Err(format!(
+                    "unknown border '{}' (expected single, double, rounded or bold)",
+                    other
+                ))
+            }
+        };
+    }
+    if let Some(emoji) = file.emoji {
+        style.emoji = emoji;
+    }
+    if let Some(prompt) = file.prompt {
+        style.prompt_format = prompt;
+    }
+
+    let colors = file.colors;
+    for (value, slot) in [
+        (colors.user_fg, &mut style.user_box_fg),
+        (colors.user_bg, &mut style.user_box_bg),
+        (colors.model_fg, &mut style.model_box_fg),
+        (colors.model_bg, &mut style.model_box_bg),
+        (colors.prompt, &mut style.prompt_fg),
+        (colors.status, &mut style.status_fg),
+    ] {
+        if let Some(value) = value {
+            *slot = parse_color(&value)?;
+        }
+    }
+
+    Ok(style)
+}
+
+fn parse_color(value: &str) -> Result<Color, String> {
+    if let Some(hex) = value.strip_prefix('#') {
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+        };
+        if let (6, Some(r), Some(g), Some(b)) = (hex.len(), channel(0), channel(2), channel(4)) {
+            return Ok(Color::Rgb { r, g, b });
+        }
+    } else if let Ok(ansi) = value.parse::<u8>() {
+        return Ok(Color::AnsiValue(ansi));
+    } else if let Ok(color) = Color::try_from(value.replace("gray", "grey").as_str()) {
+        return Ok(color);
+    }
+    Err(format!(
+        "unknown color '{}' (use a name like dark_grey, #rrggbb or 0-255)",
+        value
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_by_name_hex_and_number() {
+        assert_eq!(parse_color("dark_grey"), Ok(Color::DarkGrey));
+        assert_eq!(parse_color("dark_gray"), Ok(Color::DarkGrey));
+        assert_eq!(
+            parse_color("#E8e8ff"),
+            Ok(Color::Rgb {
+                r: 0xe8,
+                g: 0xe8,
+                b: 0xff
+            })
+        );
+        assert_eq!(parse_color("208"), Ok(Color::AnsiValue(208)));
+        for bad in ["#e8e8e", "#e8e8e8e8", "#gggggg", "#éé", "256", "purple", ""] {
+            assert!(parse_color(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn missing_settings_come_from_the_dark_theme() {
+        let style = parse("emoji = false\n\n[colors]\nprompt = \"red\"\n").unwrap();
+        let dark = UiStyle::default();
+        assert!(!style.emoji);
+        assert_eq!(style.prompt_fg, Color::Red);
+        assert_eq!(style.user_box_bg, dark.user_box_bg);
+        assert_eq!(style.prompt_format, dark.prompt_format);
+    }
+
+    #[test]
+    fn builtin_themes_parse() {
+        let dir = std::env::temp_dir().join(format!("agent-themes-{}", std::process::id()));
+        for name in names(&dir) {
+            load(&name, &dir).unwrap();
+        }
+        let style = load("high-contrast", &dir).unwrap();
+        assert!(matches!(style.border_style, BorderStyle::Bold));
+        assert!(load("missing", &dir)
+            .err()
+            .unwrap()
+            .contains("high-contrast"));
+    }
+
+    #[test]
+    fn unknown_settings_are_errors() {
+        assert!(parse("border = \"dotted\"")
+            .err()
+            .unwrap()
+            .contains("dotted"));
+        assert!(parse("[colors]\nuser_fg = \"nope\"").is_err());
+        assert!(parse("[colors]\nbackground = \"red\"").is_err());
+        assert!(parse("emoji = \"yes\"").is_err());
+    }
+
+    #[test]
+    fn theme_files_override_builtins_of_the_same_name() {
+        let dir = std::env::temp_dir().join(format!("agent-theme-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("light.toml"), "border = \"double\"").unwrap();
+        fs::write(dir.join("mine.toml"), "prompt = \"{model} > \"").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+
+        assert_eq!(names(&dir), ["dark", "light", "high-contrast", "mine"]);
+        let light = load("light", &dir).unwrap();
+        assert!(matches!(light.border_style, BorderStyle::Double));
+        assert_eq!(light.user_box_bg, UiStyle::default().user_box_bg);
+        assert_eq!(load("mine", &dir).unwrap().prompt_format, "{model} > ");
+
+        fs::write(dir.join("broken.toml"), "border = ").unwrap();
+        assert!(load("broken", &dir)
+            .err()
+            .unwrap()
+            .starts_with("Invalid theme"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,10 +1,17 @@
+use crate::markdown;
+use crate::model_registry::LlmModel;
 use crossterm::{
     cursor, execute, queue,
-    style::{self, Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor, Stylize},
+    style::{
+        Attribute, Color, Print, ResetColor, SetAttribute, SetBackgroundColor, SetForegroundColor,
+    },
     terminal::{size, Clear, ClearType},
-    Result,
 };
-use std::io::{stdout, Write};
+use ratatui::style::Modifier;
+use ratatui::text::Line;
+use std::io::{stdout, IsTerminal, Result, Write};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 // Style configuration for our UI
 pub struct UiStyle {
@@ -15,6 +22,10 @@ pub struct UiStyle {
     pub prompt_fg: Color,
     pub status_fg: Color,
     pub border_style: BorderStyle,
+    // Show 🤖 in front of the model name
+    pub emoji: bool,
+    // Input prompt; `{model}` is the model's display name
+    pub prompt_format: String,
 }
 
 pub enum BorderStyle {
@@ -49,6 +60,47 @@ impl Default for UiStyle {
             prompt_fg: Color::Cyan,
             status_fg: Color::DarkGrey,
             border_style: BorderStyle::Rounded,
+            emoji: true,
+            prompt_format: "You [{model}]: ".to_string(),
+        }
+    }
+}
+
+// Rows of a streaming model message already on screen
+pub struct MessageStream {
+    // Whether the message starts with the model name
+    header: bool,
+    drawn: Vec<String>,
+    // Leading rows of `drawn` that have scrolled out of view
+    frozen: usize,
+    // Terminal width the rows were drawn at
+    width: u16,
+    // Bytes of the message printed so far in plain output. Trailing
+    // whitespace waits until more text follows it.
+    printed: usize,
+    // Set until a continuation prints its first text
+    seam: bool,
+    // Bytes of the message shown before this stream started
+    shown_before: usize,
+    // Notices about the message, drawn under its box
+    notes: Vec<String>,
+}
+
+impl MessageStream {
+    // A continuation of a message shown earlier starts from the text it
+    // continues. The box picks up at the last line of that text, and plain
+    // output has already printed it.
+    pub fn new(continues: Option<&str>) -> Self {
+        let shown_before = continues.map_or(0, str::len);
+        MessageStream {
+            header: continues.is_none(),
+            drawn: Vec::new(),
+            frozen: 0,
+            width: 0,
+            printed: shown_before,
+            seam: continues.is_some(),
+            shown_before,
+            notes: Vec::new(),
         }
     }
 }
@@ -58,22 +110,33 @@ pub struct TerminalUi {
     pub term_width: u16,
     pub term_height: u16,
     pub current_model: String,
+    pub current_model_name: String,
+    // No colors, boxes or cursor movement, see `plain_output`
+    pub plain: bool,
 }
 
 impl TerminalUi {
     pub fn new() -> Result<Self> {
-        let (term_width, term_height) = size()?;
+        let plain = plain_output();
+        let (term_width, term_height) = if plain {
+            size().unwrap_or((80, 24))
+        } else {
+            size()?
+        };
 
         Ok(TerminalUi {
             style: UiStyle::default(),
             term_width,
             term_height,
             current_model: "claude-3-5-haiku-latest".to_string(),
+            current_model_name: "Claude 3.5 Haiku".to_string(),
+            plain,
         })
     }
 
-    pub fn set_model(&mut self, model: String) {
-        self.current_model = model;
+    pub fn set_model(&mut self, model: &LlmModel) {
+        self.current_model = model.id.clone();
+        self.current_model_name = model.display_name.clone();
     }
 
     pub fn update_terminal_size(&mut self) -> Result<()> {
@@ -83,23 +146,36 @@ impl TerminalUi {
         Ok(())
     }
 
+    pub fn prompt_text(&self) -> String {
+        self.style
+            .prompt_format
+            .replace("{model}", &self.current_model_name)
+    }
+
+    // The model's name as shown above its answers
+    fn model_label(&self) -> String {
+        if self.style.emoji {
+            format!("🤖 {}:", self.current_model_name)
+        } else {
+            format!("{}:", self.current_model_name)
+        }
+    }
+
     pub fn draw_prompt(&self) -> Result<()> {
         let mut stdout = stdout();
 
         // Get available width
         let available_width = self.term_width as usize;
 
-        // Clear the screen from cursor down
-        execute!(stdout, Clear(ClearType::FromCursorDown))?;
-
         // Draw the model info and prompt
-        let model_display = match self.current_model.as_str() {
-            "claude-3-5-haiku-latest" => "Claude 3.5 Haiku",
-            "claude-3-7-sonnet-latest" => "Claude 3.7 Sonnet",
-            other => other,
-        };
+        let prompt_text = self.prompt_text();
+        if self.plain {
+            print!("{}", prompt_text);
+            return stdout.flush();
+        }
 
-        let prompt_text = format!("You [{}]: ", model_display);
+        // Clear the screen from cursor down
+        execute!(stdout, Clear(ClearType::FromCursorDown))?;
 
         queue!(
             stdout,
@@ -115,8 +191,13 @@ impl TerminalUi {
     pub fn draw_user_message(&self, message: &str) -> Result<()> {
         let mut stdout = stdout();
 
+        if self.plain {
+            println!("{}\n", message);
+            return stdout.flush();
+        }
+
         // Get available width accounting for padding
-        let available_width = self.term_width as usize - 4; // 2 chars padding on each side
+        let available_width = (self.term_width as usize).saturating_sub(4); // 2 chars padding on each side
 
         // Word wrap the message
         let wrapped_lines = self.wrap_text(message, available_width);
@@ -133,7 +214,7 @@ impl TerminalUi {
             Print(format!(
                 "{}{}{}",
                 tl,
-                h.to_string().repeat(available_width),
+                h.to_string().repeat(available_width + 2),
                 tr
             )),
             Print("\n")
@@ -141,7 +222,7 @@ impl TerminalUi {
 
         // Message lines
         for line in &wrapped_lines {
-            let padding = " ".repeat(available_width - line.len());
+            let padding = " ".repeat(available_width.saturating_sub(line.width()));
             queue!(
                 stdout,
                 Print(format!("{} {}{} {}", v, line, padding, v)),
@@ -155,7 +236,7 @@ impl TerminalUi {
             Print(format!(
                 "{}{}{}",
                 bl,
-                h.to_string().repeat(available_width),
+                h.to_string().repeat(available_width + 2),
                 br
             )),
             ResetColor,
@@ -174,143 +255,284 @@ impl TerminalUi {
     ) -> Result<()> {
         let mut stdout = stdout();
 
-        // Get available width accounting for padding
-        let available_width = self.term_width as usize - 4; // 2 chars padding on each side
+        let stats = tokens_used.zip(cost);
+        if self.plain {
+            return self.print_plain_message(message, stats);
+        }
+        let lines = markdown::render(message, self.box_width());
+        for row in self.render_model_message(&lines, true, &[], stats)? {
+            queue!(stdout, Print(row), Print("\n"))?;
+        }
 
-        // Word wrap the message
-        let wrapped_lines = self.wrap_text(message, available_width);
-        let box_height = wrapped_lines.len() + 2; // +2 for top and bottom borders
+        stdout.flush()?;
+        Ok(())
+    }
+
+    // Width of the text in a message box
+    fn box_width(&self) -> usize {
+        (self.term_width as usize).saturating_sub(4) // 2 chars padding on each side
+    }
+
+    // The rows of a model message as they are printed: the name header, the
+    // box with the Markdown-rendered answer, notices about it and the token
+    // stats. Each row carries its own colors so it can be redrawn on its own.
+    fn render_model_message(
+        &self,
+        lines: &[Line],
+        header: bool,
+        notes: &[String],
+        stats: Option<((u32, u32), f64)>,
+    ) -> Result<Vec<String>> {
+        let mut rows = Vec::new();
+
+        let available_width = self.box_width();
 
         // Box characters
         let (tl, tr, bl, br, h, v) = self.style.border_style.get_chars();
+        let border = h.to_string().repeat(available_width + 2);
 
-        // Draw the model emoji and name
-        let model_display = match self.current_model.as_str() {
-            "claude-3-5-haiku-latest" => "Claude 3.5 Haiku",
-            "claude-3-7-sonnet-latest" => "Claude 3.7 Sonnet",
-            other => other,
-        };
-
-        queue!(
-            stdout,
-            SetForegroundColor(self.style.prompt_fg),
-            Print(format!("🤖 {}:\n", model_display)),
-            ResetColor
-        )?;
+        if header {
+            let mut row = Vec::new();
+            queue!(
+                row,
+                SetForegroundColor(self.style.prompt_fg),
+                Print(self.model_label()),
+                ResetColor
+            )?;
+            rows.push(row);
+        }
 
         // Top border
+        let mut row = Vec::new();
         queue!(
-            stdout,
+            row,
             SetForegroundColor(self.style.model_box_fg),
             SetBackgroundColor(self.style.model_box_bg),
-            Print(format!(
-                "{}{}{}",
-                tl,
-                h.to_string().repeat(available_width),
-                tr
-            )),
-            Print("\n")
+            Print(format!("{}{}{}", tl, border, tr)),
+            ResetColor
         )?;
+        rows.push(row);
 
         // Message lines
-        for line in &wrapped_lines {
-            let padding = " ".repeat(available_width - line.len());
+        for line in lines {
+            let padding = " ".repeat(available_width.saturating_sub(line.width()));
+            let mut row = Vec::new();
             queue!(
-                stdout,
-                Print(format!("{} {}{} {}", v, line, padding, v)),
-                Print("\n")
+                row,
+                SetForegroundColor(self.style.model_box_fg),
+                SetBackgroundColor(self.style.model_box_bg),
+                Print(format!("{} ", v))
             )?;
+            self.print_styled(&mut row, line, self.style.model_box_fg)?;
+            queue!(row, Print(format!("{} {}", padding, v)), ResetColor)?;
+            rows.push(row);
         }
 
         // Bottom border
+        let mut row = Vec::new();
         queue!(
-            stdout,
-            Print(format!(
-                "{}{}{}",
-                bl,
-                h.to_string().repeat(available_width),
-                br
-            )),
-            ResetColor,
-            Print("\n")
+            row,
+            SetForegroundColor(self.style.model_box_fg),
+            SetBackgroundColor(self.style.model_box_bg),
+            Print(format!("{}{}{}", bl, border, br)),
+            ResetColor
         )?;
+        rows.push(row);
+        rows.extend(notes.iter().map(|note| note.as_bytes().to_vec()));
 
         // Display token usage and cost if provided
-        if let (Some((input_tokens, output_tokens)), Some(cost)) = (tokens_used, cost) {
+        if let Some(((input_tokens, output_tokens), cost)) = stats {
+            let mut row = Vec::new();
             queue!(
-                stdout,
+                row,
                 SetForegroundColor(self.style.status_fg),
                 Print(format!(
-                    "Tokens: {} in, {} out | Cost: ${:.6}\n\n",
+                    "Tokens: {} in, {} out | Cost: ${:.6}",
                     input_tokens, output_tokens, cost
                 )),
                 ResetColor
             )?;
+            rows.push(row);
+            rows.push(Vec::new());
         }
 
-        stdout.flush()?;
+        Ok(rows
+            .into_iter()
+            .map(|row| String::from_utf8_lossy(&row).into_owned())
+            .collect())
+    }
+
+    // Print a rendered line's spans, going back to `fg` (and the box
+    // background) after each one
+    fn print_styled(&self, out: &mut impl Write, line: &Line, fg: Color) -> Result<()> {
+        for span in &line.spans {
+            if let Some(color) = span.style.fg {
+                queue!(out, SetForegroundColor(color.into()))?;
+            }
+            let modifiers = span.style.add_modifier;
+            for (modifier, attribute) in [
+                (Modifier::BOLD, Attribute::Bold),
+                (Modifier::DIM, Attribute::Dim),
+                (Modifier::ITALIC, Attribute::Italic),
+                (Modifier::UNDERLINED, Attribute::Underlined),
+                (Modifier::CROSSED_OUT, Attribute::CrossedOut),
+            ] {
+                if modifiers.contains(modifier) {
+                    queue!(out, SetAttribute(attribute))?;
+                }
+            }
+            queue!(
+                out,
+                Print(&span.content),
+                SetAttribute(Attribute::Reset),
+                SetForegroundColor(fg),
+                SetBackgroundColor(self.style.model_box_bg)
+            )?;
+        }
         Ok(())
     }
 
     // Word wrapping utility
     pub fn wrap_text(&self, text: &str, width: usize) -> Vec<String> {
-        let mut result = Vec::new();
-        let mut current_line = String::new();
-
-        for word in text.split_whitespace() {
-            if current_line.len() + word.len() + 1 > width {
-                // Line would be too long with this word, start a new line
-                if !current_line.is_empty() {
-                    result.push(current_line);
-                    current_line = String::new();
+        wrap(text, width)
+    }
+
+    // Redraw a model message that is still streaming in. Only the rows that
+    // changed since the last call are printed again: the cursor moves up to
+    // the first of them and everything below it is replaced. Rows that have
+    // scrolled off the top can't be reached and stay as they were. When the
+    // width changes the terminal has reflowed the old rows, so the ones still
+    // on screen are cleared and the message drawn again at the new width.
+    // Whatever was printed above the message is left alone.
+    pub fn stream_model_message(
+        &mut self,
+        stream: &mut MessageStream,
+        message: &str,
+        stats: Option<((u32, u32), f64)>,
+    ) -> Result<()> {
+        let mut stdout = stdout();
+
+        // Plain output only ever appends
+        if self.plain {
+            if stream.header {
+                println!("{}", self.model_label());
+                stream.header = false;
+            }
+            let end = message.trim_end().len();
+            if let Some(mut new) = message.get(stream.printed..end) {
+                // A continuation goes on below the notice, so the whitespace
+                // it starts with has nothing to separate
+                if stream.seam {
+                    new = new.trim_start();
+                    stream.seam = new.is_empty();
                 }
+                print!("{}", new);
+                stream.printed = end;
+            }
+            return stdout.flush();
+        }
 
-                // Handle words longer than width
-                if word.len() > width {
-                    // Split long words
-                    let mut remaining = word;
-                    while !remaining.is_empty() {
-                        let (chunk, rest) = if remaining.len() <= width {
-                            (remaining, "")
-                        } else {
-                            remaining.split_at(width)
-                        };
-
-                        result.push(chunk.to_string());
-                        remaining = rest;
+        self.update_terminal_size()?;
+        if self.term_width != stream.width && !stream.drawn.is_empty() {
+            // Box rows span the old width and may now take several rows each;
+            // the name header is short enough to still fit on one
+            let per_row = (stream.width as usize).div_ceil(self.term_width.max(1) as usize);
+            let reflowed: usize = (stream.frozen..stream.drawn.len())
+                .map(|i| {
+                    if i == 0 && stream.header {
+                        1
+                    } else {
+                        per_row.max(1)
                     }
-                } else {
-                    current_line = word.to_string();
-                }
-            } else {
-                // Word fits on current line
-                if !current_line.is_empty() {
-                    current_line.push(' ');
-                }
-                current_line.push_str(word);
+                })
+                .sum();
+            let up = reflowed.min((self.term_height as usize).saturating_sub(1));
+            if up > 0 {
+                queue!(stdout, cursor::MoveUp(up as u16))?;
             }
+            stream.drawn.clear();
+            stream.frozen = 0;
+        }
+        stream.width = self.term_width;
+
+        // A continuation of a message shown earlier starts at the last line
+        // of what was already there
+        let mut lines = markdown::render(message, self.box_width());
+        if let Some(before) = message.get(..stream.shown_before).filter(|b| !b.is_empty()) {
+            let skip = markdown::render(before, self.box_width()).len() - 1;
+            lines.drain(..skip.min(lines.len()));
+        }
+        let rows = self.render_model_message(&lines, stream.header, &stream.notes, stats)?;
+        let unchanged = rows
+            .iter()
+            .zip(&stream.drawn)
+            .take_while(|(new, old)| new == old)
+            .count();
+        let from = unchanged.max(stream.frozen).min(rows.len());
+
+        // The cursor sits on the row below the last one drawn
+        let up = stream.drawn.len().saturating_sub(from);
+        if up > 0 {
+            queue!(stdout, cursor::MoveUp(up as u16))?;
         }
+        queue!(
+            stdout,
+            cursor::MoveToColumn(0),
+            Clear(ClearType::FromCursorDown)
+        )?;
+        for row in &rows[from..] {
+            queue!(stdout, Print(row), Print("\n"))?;
+        }
+        stdout.flush()?;
+
+        // Only the last screenful above the cursor can be redrawn
+        let visible = (self.term_height as usize).saturating_sub(1);
+        stream.frozen = stream.frozen.max(rows.len().saturating_sub(visible));
+        stream.drawn = rows;
+        Ok(())
+    }
 
-        if !current_line.is_empty() {
-            result.push(current_line);
+    // Last update of a streamed message, adding the token stats
+    pub fn end_model_message(
+        &mut self,
+        stream: &mut MessageStream,
+        message: &str,
+        stats: Option<((u32, u32), f64)>,
+    ) -> Result<()> {
+        if !self.plain {
+            return self.stream_model_message(stream, message, stats);
         }
+        self.stream_model_message(stream, message, None)?;
+        println!();
+        self.print_plain_stats(stats)
+    }
 
-        result
+    fn print_plain_message(&self, message: &str, stats: Option<((u32, u32), f64)>) -> Result<()> {
+        println!("{}", self.model_label());
+        println!("{}", message);
+        self.print_plain_stats(stats)
     }
 
-    // Special handling for streaming responses
-    pub fn stream_model_message<F>(&self, callback: F) -> Result<()>
-    where
-        F: FnMut(&str) -> Result<()>,
-    {
-        // This is a placeholder for a more complex implementation
-        // that would handle streaming responses
-        Ok(())
+    fn print_plain_stats(&self, stats: Option<((u32, u32), f64)>) -> Result<()> {
+        if let Some(((input_tokens, output_tokens), cost)) = stats {
+            println!(
+                "Tokens: {} in, {} out | Cost: ${:.6}",
+                input_tokens, output_tokens, cost
+            );
+        }
+        println!();
+        stdout().flush()
     }
 
     // Initialize UI for a new conversation
     pub fn init_conversation(&self) -> Result<()> {
         let mut stdout = stdout();
+
+        if self.plain {
+            println!("Claude CLI - Current Model: {}", self.current_model_name);
+            println!("Type your message or commands (/help, /models, /quit)\n");
+            return stdout.flush();
+        }
         execute!(stdout, Clear(ClearType::All), cursor::MoveTo(0, 0))?;
 
         // Draw a header
@@ -319,7 +541,7 @@ impl TerminalUi {
             SetForegroundColor(Color::White),
             Print(format!(
                 "Claude CLI - Current Model: {}\n",
-                self.current_model
+                self.current_model_name
             )),
             Print("Type your message or commands (/help, /models, /quit)\n\n"),
             ResetColor
@@ -329,14 +551,73 @@ impl TerminalUi {
         Ok(())
     }
 
+    // Dim one-line status message, e.g. when a response was cut off
+    pub fn draw_notice(&self, notice: &str) -> Result<()> {
+        let mut stdout = stdout();
+
+        if self.plain {
+            println!("{}", notice);
+            return stdout.flush();
+        }
+
+        queue!(
+            stdout,
+            SetForegroundColor(self.style.status_fg),
+            Print(format!("{}\n", notice)),
+            ResetColor
+        )?;
+
+        stdout.flush()?;
+        Ok(())
+    }
+
+    // A notice about a message that may still be streaming, e.g. that it is
+    // being continued. It goes under the message's box, which can still be
+    // redrawn above it.
+    pub fn stream_notice(&self, stream: &mut MessageStream, notice: &str) -> Result<()> {
+        if self.plain {
+            // What follows starts on the line after the notice
+            stream.seam = true;
+            return self.draw_notice(notice);
+        }
+
+        let mut row = Vec::new();
+        queue!(
+            row,
+            SetForegroundColor(self.style.status_fg),
+            Print(notice),
+            ResetColor
+        )?;
+        let row = String::from_utf8_lossy(&row).into_owned();
+        let mut stdout = stdout();
+        queue!(stdout, cursor::MoveToColumn(0), Print(&row), Print("\n"))?;
+        stdout.flush()?;
+
+        stream.notes.push(row.clone());
+        stream.drawn.push(row);
+        let visible = (self.term_height as usize).saturating_sub(1);
+        stream.frozen = stream
+            .frozen
+            .max(stream.drawn.len().saturating_sub(visible));
+        Ok(())
+    }
+
     // Draw a thinking spinner
     pub fn draw_thinking_spinner(&self) -> Result<()> {
         let mut stdout = stdout();
 
+        if self.plain {
+            return Ok(());
+        }
+        let thinking = if self.style.emoji {
+            "🤖 Thinking..."
+        } else {
+            "Thinking..."
+        };
         queue!(
             stdout,
             SetForegroundColor(self.style.prompt_fg),
-            Print("🤖 Thinking..."),
+            Print(thinking),
             ResetColor
         )?;
 
@@ -348,6 +629,10 @@ impl TerminalUi {
     pub fn clear_thinking_spinner(&self) -> Result<()> {
         let mut stdout = stdout();
 
+        if self.plain {
+            return Ok(());
+        }
+
         execute!(
             stdout,
             cursor::SavePosition,
@@ -359,3 +644,177 @@ impl TerminalUi {
         Ok(())
     }
 }
+
+// Output for pipes, `TERM=dumb` terminals and anyone who set NO_COLOR
+// (https://no-color.org) is plain text
+pub fn plain_output() -> bool {
+    no_color() || !cursor_terminal()
+}
+
+pub fn no_color() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+// Whether stdout is a terminal the cursor can be moved around in
+pub fn cursor_terminal() -> bool {
+    stdout().is_terminal() && std::env::var("TERM").map_or(true, |term| term != "dumb")
+}
+
+// Wrap text to `width` terminal columns. Every line of the input starts a
+// new row and keeps its indentation and inner spacing; lines that are too
+// long break at spaces, and words longer than a row break between
+// graphemes, so wide (CJK, emoji) and combined characters are never split.
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    text.split('\n')
+        .flat_map(|line| wrap_line(&line.trim_end_matches('\r').replace('\t', "    "), width))
+        .collect()
+}
+
+fn wrap_line(line: &str, width: usize) -> Vec<String> {
+    if line.width() <= width {
+        return vec![line.to_string()];
+    }
+
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    let mut row_width = 0;
+
+    for token in tokens(line) {
+        let token_width = token.width();
+        let is_space = token.starts_with(char::is_whitespace);
+
+        if row_width + token_width <= width {
+            row.push_str(token);
+            row_width += token_width;
+            continue;
+        }
+        if is_space {
+            // Break here; the spaces go with the break
+            rows.push(row.trim_end().to_string());
+            row = String::new();
+            row_width = 0;
+            continue;
+        }
+
+        // Start the word on a fresh row unless the row holds only indentation
+        if !row.trim().is_empty() {
+            rows.push(row.trim_end().to_string());
+            row = String::new();
+            row_width = 0;
+        }
+        for grapheme in token.graphemes(true) {
+            let grapheme_width = grapheme.width();
+            if row_width + grapheme_width > width && row_width > 0 {
+                rows.push(std::mem::take(&mut row));
+                row_width = 0;
+            }
+            row.push_str(grapheme);
+            row_width += grapheme_width;
+        }
+    }
+
+    if !row.is_empty() || rows.is_empty() {
+        rows.push(row);
+    }
+    rows
+}
+
+// Alternating runs of whitespace and non-whitespace
+fn tokens(line: &str) -> impl Iterator<Item = &str> {
+    let mut rest = line;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let space = first.is_whitespace();
+        let end = rest
+            .find(|c: char| c.is_whitespace() != space)
+            .unwrap_or(rest.len());
+        let (token, tail) = rest.split_at(end);
+        rest = tail;
+        Some(token)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wrap;
+    use proptest::prelude::*;
+    use unicode_segmentation::UnicodeSegmentation;
+    use unicode_width::UnicodeWidthStr;
+
+    // Pieces mixing ASCII, indentation, CJK, emoji (including ZWJ
+    // sequences and modifiers) and combining marks
+    fn piece() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-z]{1,12}",
+            Just(" ".to_string()),
+            Just("    ".to_string()),
+            Just("\n".to_string()),
+            Just("\t".to_string()),
+            "[一-龥]{1,6}",
+            "[ぁ-ゖ]{1,6}",
+            Just("😀".to_string()),
+            Just("👩‍👩‍👧".to_string()),
+            Just("👍🏽".to_string()),
+            Just("e\u{301}".to_string()),
+            Just("한국어".to_string()),
+        ]
+    }
+
+    fn text() -> impl Strategy<Value = String> {
+        prop::collection::vec(piece(), 0..40).prop_map(|pieces| pieces.concat())
+    }
+
+    fn widest_grapheme(text: &str) -> usize {
+        text.graphemes(true).map(|g| g.width()).max().unwrap_or(0)
+    }
+
+    fn without_whitespace(text: &str) -> String {
+        text.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn rows_fit_the_width(text in text(), width in 1usize..40) {
+            let limit = width.max(widest_grapheme(&text));
+            for row in wrap(&text, width) {
+                prop_assert!(row.width() <= limit, "{:?} is wider than {}", row, limit);
+            }
+        }
+
+        #[test]
+        fn nothing_but_breaking_spaces_is_lost(text in text(), width in 1usize..40) {
+            let rows = wrap(&text, width);
+            prop_assert_eq!(without_whitespace(&rows.concat()), without_whitespace(&text));
+        }
+
+        #[test]
+        fn graphemes_are_never_split(text in text(), width in 1usize..40) {
+            let expected: Vec<&str> = text.graphemes(true).filter(|g| !g.trim().is_empty()).collect();
+            let rows = wrap(&text, width);
+            let joined = rows.concat();
+            let actual: Vec<&str> = joined.graphemes(true).filter(|g| !g.trim().is_empty()).collect();
+            prop_assert_eq!(actual, expected);
+        }
+
+        #[test]
+        fn newlines_start_new_rows(a in text(), b in text(), width in 1usize..40) {
+            let mut expected = wrap(&a, width);
+            expected.extend(wrap(&b, width));
+            prop_assert_eq!(wrap(&format!("{}\n{}", a, b), width), expected);
+        }
+
+        #[test]
+        fn lines_that_fit_are_unchanged(line in "[^\n\t\r]{0,30}", width in 30usize..60) {
+            prop_assume!(line.width() <= width);
+            prop_assert_eq!(wrap(&line, width), vec![line]);
+        }
+
+        #[test]
+        fn indentation_is_kept(indent in 0usize..8, line in "[a-z]{1,8}( [a-z]{1,8}){0,10}", width in 10usize..40) {
+            let indented = format!("{}{}", " ".repeat(indent), line);
+            let rows = wrap(&indented, width);
+            prop_assert!(rows[0].starts_with(&" ".repeat(indent)));
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use crate::model_registry::LlmModel;
+use crate::modules::ClaudeUsage;
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// One line of the usage ledger, written once per completed request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub timestamp: DateTime<Utc>,
+    pub profile: String,
+    pub model: String,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub cache_creation_input_tokens: u32,
+    pub cache_read_input_tokens: u32,
+    pub cost: f64,
+    pub latency_ms: u64,
+    // Billed through the Message Batches API at the batch discount
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub batch: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GroupBy {
+    Model,
+    Day,
+    Profile,
+}
+
+// Totals for one row of a usage report
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UsageSummary {
+    pub key: String,
+    pub requests: u32,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+    pub cost: f64,
+    pub avg_latency_ms: u64,
+}
+
+// Append-only JSONL ledger stored next to config.json
+pub struct UsageLedger {
+    path: PathBuf,
+}
+
+impl UsageLedger {
+    pub fn new(path: PathBuf) -> Self {
+        UsageLedger { path }
+    }
+
+    pub fn append(&self, record: &UsageRecord) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let line = serde_json::to_string(record)?;
+        writeln!(file, "{}", line)
+    }
+
+    // Build a record from a finished request and append it
+    pub fn record(
+        &self,
+        profile: &str,
+        model: &LlmModel,
+        usage: &ClaudeUsage,
+        latency: Duration,
+    ) -> io::Result<UsageRecord> {
+        let record = Self::build_record(profile, &model.id, usage, model.calculate_cost(usage));
+        let record = UsageRecord {
+            latency_ms: latency.as_millis() as u64,
+            ..record
+        };
+
+        self.append(&record)?;
+        Ok(record)
+    }
+
+    // Batch requests carry no latency; `cost` is at the batch discount
+    pub fn record_batch(
+        &self,
+        profile: &str,
+        model_id: &str,
+        usage: &ClaudeUsage,
+        cost: f64,
+    ) -> io::Result<UsageRecord> {
+        let record = UsageRecord {
+            batch: true,
+            ..Self::build_record(profile, model_id, usage, cost)
+        };
+
+        self.append(&record)?;
+        Ok(record)
+    }
+
+    fn build_record(profile: &str, model_id: &str, usage: &ClaudeUsage, cost: f64) -> UsageRecord {
+        UsageRecord {
+            timestamp: Utc::now(),
+            profile: profile.to_string(),
+            model: model_id.to_string(),
+            input_tokens: usage.input_tokens.unwrap_or(0),
+            output_tokens: usage.output_tokens.unwrap_or(0),
+            cache_creation_input_tokens: usage.cache_creation_input_tokens.unwrap_or(0),
+            cache_read_input_tokens: usage.cache_read_input_tokens.unwrap_or(0),
+            cost,
+            latency_ms: 0,
+            batch: false,
+        }
+    }
+
+    // Read every record at or after `since`. Lines that fail to parse are skipped
+    // so a single truncated write never makes the whole ledger unreadable.
+    pub fn read(&self, since: Option<DateTime<Utc>>) -> io::Result<Vec<UsageRecord>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Ok(record) = serde_json::from_str::<UsageRecord>(&line) {
+                if since.is_none_or(|since| record.timestamp >= since) {
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+// Accepts `YYYY-MM-DD`, an RFC 3339 timestamp, or a relative `<n>d` / `<n>h`
+pub fn parse_since(value: &str) -> Result<DateTime<Utc>, String> {
+    let value = value.trim();
+
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+
+    // Only amounts back in time: a negative one would be in the future
+    let ago = if let Some(Ok(days)) = value.strip_suffix('d').map(str::parse::<u32>) {
+        Some(ChronoDuration::days(days.into()))
+    } else if let Some(Ok(hours)) = value.strip_suffix('h').map(str::parse::<u32>) {
+        Some(ChronoDuration::hours(hours.into()))
+    } else {
+        None
+    };
+    if let Some(since) = ago.and_then(|ago| Utc::now().checked_sub_signed(ago)) {
+        return Ok(since);
+    }
+
+    Err(format!(
+        "Invalid --since value '{}': use YYYY-MM-DD, RFC 3339, or e.g. 7d / 12h",
+        value
+    ))
+}
+
+pub fn summarize(records: &[UsageRecord], group_by: GroupBy) -> Vec<UsageSummary> {
+    // Per group: totals, summed latency and how many requests reported one.
+    // Batch requests have no latency of their own and are left out of the average.
+    let mut groups: BTreeMap<String, (UsageSummary, u64, u64)> = BTreeMap::new();
+
+    for record in records {
+        let key = match group_by {
+            GroupBy::Model => record.model.clone(),
+            GroupBy::Day => record.timestamp.format("%Y-%m-%d").to_string(),
+            GroupBy::Profile => record.profile.clone(),
+        };
+
+        let (summary, total_latency, timed) = groups.entry(key.clone()).or_insert_with(|| {
+            (
+                UsageSummary {
+                    key,
+                    ..Default::default()
+                },
+                0,
+                0,
+            )
+        });
+
+        summary.requests += 1;
+        summary.input_tokens += record.input_tokens as u64;
+        summary.output_tokens += record.output_tokens as u64;
+        summary.cache_creation_input_tokens += record.cache_creation_input_tokens as u64;
+        summary.cache_read_input_tokens += record.cache_read_input_tokens as u64;
+        summary.cost += record.cost;
+        if !record.batch {
+            *total_latency += record.latency_ms;
+            *timed += 1;
+        }
+    }
+
+    groups
+        .into_values()
+        .map(|(mut summary, total_latency, timed)| {
+            summary.avg_latency_ms = total_latency / timed.max(1);
+            summary
+        })
+        .collect()
+}
+
+pub fn render_table(summaries: &[UsageSummary], group_by: GroupBy) -> String {
+    let key_header = match group_by {
+        GroupBy::Model => "model",
+        GroupBy::Day => "day",
+        GroupBy::Profile => "profile",
+    };
+    let key_width = summaries
+        .iter()
+        .map(|s| s.key.len())
+        .max()
+        .unwrap_or(0)
+        .max(key_header.len());
+
+    let mut out = format!(
+        "{:<key_width$}  {:>8}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12}  {:>10}\n",
+        key_header,
+        "requests",
+        "input",
+        "output",
+        "cache write",
+        "cache read",
+        "cost (USD)",
+        "avg ms",
+        key_width = key_width
+    );
+
+    let mut total = UsageSummary {
+        key: "total".to_string(),
+        ..Default::default()
+    };
+
+    for s in summaries {
+        out.push_str(&format_row(s, key_width));
+        total.requests += s.requests;
+        total.input_tokens += s.input_tokens;
+        total.output_tokens += s.output_tokens;
+        total.cache_creation_input_tokens += s.cache_creation_input_tokens;
+        total.cache_read_input_tokens += s.cache_read_input_tokens;
+        total.cost += s.cost;
+        total.avg_latency_ms += s.avg_latency_ms * s.requests as u64;
+    }
+    total.avg_latency_ms /= total.requests.max(1) as u64;
+
+    if summaries.len() > 1 {
+        out.push_str(&format_row(&total, key_width));
+    }
+
+    out
+}
+
+fn format_row(s: &UsageSummary, key_width: usize) -> String {
+    format!(
+        "{:<key_width$}  {:>8}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12.6}  {:>10}\n",
+        s.key,
+        s.requests,
+        s.input_tokens,
+        s.output_tokens,
+        s.cache_creation_input_tokens,
+        s.cache_read_input_tokens,
+        s.cost,
+        s.avg_latency_ms,
+        key_width = key_width
+    )
+}
+
+pub fn render_csv(summaries: &[UsageSummary]) -> String {
+    let mut out = String::from(
+        "key,requests,input_tokens,output_tokens,cache_creation_input_tokens,cache_read_input_tokens,cost,avg_latency_ms\n",
+    );
+
+    for s in summaries {
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{:.6},{}\n",
+            csv_escape(&s.key),
+            s.requests,
+            s.input_tokens,
+            s.output_tokens,
+            s.cache_creation_input_tokens,
+            s.cache_read_input_tokens,
+            s.cost,
+            s.avg_latency_ms
+        ));
+    }
+
+    out
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_since;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn relative_days_and_hours() {
+        let days = Utc::now() - parse_since("7d").unwrap();
+        assert!((days - Duration::days(7)).num_seconds().abs() < 5);
+        let hours = Utc::now() - parse_since(" 12h ").unwrap();
+        assert!((hours - Duration::hours(12)).num_seconds().abs() < 5);
+        assert!(parse_since("0d").unwrap() <= Utc::now());
+    }
+
+    #[test]
+    fn dates_and_timestamps() {
+        assert_eq!(
+            parse_since("2025-06-01").unwrap().to_rfc3339(),
+            "2025-06-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_since("2025-06-01T12:30:00+02:00")
+                .unwrap()
+                .to_rfc3339(),
+            "2025-06-01T10:30:00+00:00"
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        for value in [
+            "",
+            "d",
+            "7",
+            "7w",
+            "7é",
+            "é",
+            "７d",
+            "12日",
+            "d7",
+            "-7d",
+            "-1h",
+            "4000000000d",
+        ] {
+            assert!(parse_since(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+}